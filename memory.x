@@ -1,6 +1,7 @@
 MEMORY
 {
   BOOT2                             : ORIGIN = 0x10000000, LENGTH = 0x100
-  FLASH                             : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+  FLASH                             : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 4K
+  /* The last 4K sector is reserved for the persisted configuration, see src/storage.rs */
   RAM                               : ORIGIN = 0x20000000, LENGTH = 264K
 }
@@ -0,0 +1,381 @@
+use crate::{Event, Key, NUM_KEYS};
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+
+/// Events that can be held back while a combo is undecided. Once the queue is
+/// full the combo is decided with the keys pressed so far.
+const QUEUE_SIZE: usize = 16;
+
+/// Keys pressed together within `ComboConfig::term` that send `code`
+/// instead of their own actions.
+#[derive(Clone, Copy)]
+pub struct Combo<A> {
+    /// Mask of the keys, `1 << key as u8` for each key. Build it with `keys`.
+    pub keys: u8,
+    pub code: A,
+}
+
+/// Builds the key mask of a `Combo`.
+pub const fn keys(keys: &[Key]) -> u8 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < keys.len() {
+        mask |= 1 << keys[i] as u8;
+        i += 1;
+    }
+    mask
+}
+
+/// The key a combo is reported as, its first key.
+fn owner(keys: u8) -> Key {
+    Key::ALL.into_iter().find(|&key| keys & 1 << key as u8 != 0).unwrap_or(Key::Key1)
+}
+
+#[derive(Clone, Copy)]
+pub struct ComboConfig<A: 'static> {
+    pub combos: &'static [Combo<A>],
+    /// Time from the first to the last key press of a combo.
+    pub term: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ComboEvent<A> {
+    /// A key event that isn't part of a combo.
+    Key(Key, Event, Instant),
+    /// A combo was pressed or released. It is reported as its first key,
+    /// the events of its keys are swallowed.
+    Combo(Key, Event, A, Instant),
+}
+
+#[derive(Clone, Copy)]
+struct Active<A> {
+    combo: Combo<A>,
+    /// Keys of the combo that are still held.
+    held: u8,
+    released: bool,
+}
+
+/// Detects combos in the key events.
+///
+/// Key events pass through in order. A press of a key that is part of a
+/// combo is held back, together with everything after it, until the combo
+/// is either complete or can't be completed any more. Time only comes from
+/// the timestamps passed in, there is no clock in here. `A` is the action
+/// type combos send.
+pub struct ComboState<A: 'static> {
+    config: ComboConfig<A>,
+    events: Deque<(Key, Event, Instant), QUEUE_SIZE>,
+    /// Combos whose keys aren't all released yet.
+    active: [Option<Active<A>>; NUM_KEYS],
+}
+
+impl<A: Copy> ComboState<A> {
+    pub const fn new(config: ComboConfig<A>) -> Self {
+        ComboState {
+            config,
+            events: Deque::new(),
+            active: [None; NUM_KEYS],
+        }
+    }
+
+    /// Queues an input event, call `next` until it returns `None` afterwards.
+    /// Returns `false` if the queue is full and the event was not queued.
+    /// `next` always returns an event then, as a full queue decides the
+    /// combo, so call it and try again.
+    #[must_use]
+    pub fn push(&mut self, key: Key, event: Event, at: Instant) -> bool {
+        self.events.push_back((key, event, at)).is_ok()
+    }
+
+    /// Whether `push` would fail.
+    pub fn is_full(&self) -> bool {
+        self.events.is_full()
+    }
+
+    /// Time at which the held back keys are decided, `next` has to be called then.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.events.front() {
+            Some(&(_, Event::Pressed, at)) => Some(at + self.config.term),
+            _ => None,
+        }
+    }
+
+    /// Returns the next event to process, or `None` if there is none or a
+    /// combo is still undecided at `now`.
+    pub fn next(&mut self, now: Instant) -> Option<ComboEvent<A>> {
+        loop {
+            let &(key, event, at) = self.events.front()?;
+            let bit = 1 << key as u8;
+
+            if event == Event::Released {
+                self.events.pop_front();
+                match self.release(bit) {
+                    Some(None) => continue,
+                    Some(Some((owner, code))) => return Some(ComboEvent::Combo(owner, event, code, at)),
+                    None => return Some(ComboEvent::Key(key, event, at)),
+                }
+            }
+
+            if !self.extends(0, bit) {
+                self.events.pop_front();
+                return Some(ComboEvent::Key(key, event, at));
+            }
+
+            // Keys pressed right after the first one that still fit a combo.
+            let deadline = at + self.config.term;
+            let mut pressed = 0u8;
+            let mut count = 0;
+            let mut complete = self.events.is_full() || now >= deadline;
+            for &(key, event, at) in self.events.iter() {
+                let bit = 1 << key as u8;
+                if event == Event::Released || at >= deadline || pressed & bit != 0 || !self.extends(pressed, bit) {
+                    complete = true;
+                    break;
+                }
+                pressed |= bit;
+                count += 1;
+            }
+
+            let exact = self.config.combos.iter().find(|combo| combo.keys == pressed);
+            let longer = self.config.combos.iter().any(|combo| combo.keys != pressed && combo.keys & pressed == pressed);
+            match exact {
+                Some(&combo) if complete || !longer => {
+                    for _ in 0..count {
+                        self.events.pop_front();
+                    }
+                    let owner = owner(combo.keys);
+                    if let Some(slot) = self.active.iter_mut().find(|slot| slot.is_none()) {
+                        *slot = Some(Active {
+                            combo,
+                            held: combo.keys,
+                            released: false,
+                        });
+                    }
+                    return Some(ComboEvent::Combo(owner, Event::Pressed, combo.code, at));
+                }
+                _ if complete => {
+                    self.events.pop_front();
+                    return Some(ComboEvent::Key(key, event, at));
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// Whether a combo has all keys of `pressed` and the key `bit`.
+    fn extends(&self, pressed: u8, bit: u8) -> bool {
+        let keys = pressed | bit;
+        self.config.combos.iter().any(|combo| combo.keys & keys == keys)
+    }
+
+    /// Handles the release of a key of an active combo. Returns `None` if the
+    /// key isn't part of one, and the combo to release on its first released key.
+    fn release(&mut self, bit: u8) -> Option<Option<(Key, A)>> {
+        let slot = self.active.iter_mut().find(|slot| slot.is_some_and(|active| active.held & bit != 0))?;
+        let active = slot.as_mut()?;
+        active.held &= !bit;
+
+        let released = if active.released {
+            None
+        } else {
+            active.released = true;
+            Some((owner(active.combo.keys), active.combo.code))
+        };
+
+        if active.held == 0 {
+            *slot = None;
+        }
+        Some(released)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::{Pressed, Released};
+    use Key::{Key1, Key2, Key3};
+
+    const PAIR: ComboConfig<u8> = ComboConfig {
+        combos: &[Combo {
+            keys: keys(&[Key1, Key2]),
+            code: 1,
+        }],
+        term: Duration::from_millis(50),
+    };
+
+    /// `Key1` + `Key2` is part of both longer combos.
+    const OVERLAPPING: ComboConfig<u8> = ComboConfig {
+        combos: &[
+            Combo {
+                keys: keys(&[Key1, Key2]),
+                code: 1,
+            },
+            Combo {
+                keys: keys(&[Key1, Key2, Key3]),
+                code: 2,
+            },
+            Combo {
+                keys: keys(&[Key2, Key3]),
+                code: 3,
+            },
+        ],
+        term: Duration::from_millis(50),
+    };
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn key(key: Key, event: Event, ms: u64) -> Option<ComboEvent<u8>> {
+        Some(ComboEvent::Key(key, event, at(ms)))
+    }
+
+    fn combo(key: Key, event: Event, code: u8, ms: u64) -> Option<ComboEvent<u8>> {
+        Some(ComboEvent::Combo(key, event, code, at(ms)))
+    }
+
+    #[test]
+    fn key_mask() {
+        assert_eq!(keys(&[Key1, Key3]), 1 << Key1 as u8 | 1 << Key3 as u8);
+        assert_eq!(owner(keys(&[Key3, Key2])), Key2);
+    }
+
+    #[test]
+    fn within_term() {
+        let mut state = ComboState::new(PAIR);
+        assert!(state.push(Key1, Pressed, at(0)));
+        assert_eq!(state.next(at(0)), None);
+        assert_eq!(state.deadline(), Some(at(50)));
+        assert!(state.push(Key2, Pressed, at(30)));
+        assert_eq!(state.next(at(30)), combo(Key1, Pressed, 1, 0));
+        assert_eq!(state.next(at(30)), None);
+        assert_eq!(state.deadline(), None);
+
+        // The first release releases the combo, the other one is swallowed.
+        assert!(state.push(Key1, Released, at(100)));
+        assert_eq!(state.next(at(100)), combo(Key1, Released, 1, 100));
+        assert!(state.push(Key2, Released, at(120)));
+        assert_eq!(state.next(at(120)), None);
+
+        // Both keys are free again.
+        assert!(state.push(Key2, Pressed, at(200)));
+        assert_eq!(state.next(at(250)), key(Key2, Pressed, 200));
+    }
+
+    #[test]
+    fn outside_term() {
+        let mut state = ComboState::new(PAIR);
+        assert!(state.push(Key1, Pressed, at(0)));
+        assert_eq!(state.next(at(0)), None);
+        assert!(state.push(Key2, Pressed, at(60)));
+        assert_eq!(state.next(at(60)), key(Key1, Pressed, 0));
+        // The second key could start the combo itself.
+        assert_eq!(state.next(at(60)), None);
+        assert_eq!(state.deadline(), Some(at(110)));
+        assert_eq!(state.next(at(110)), key(Key2, Pressed, 60));
+    }
+
+    #[test]
+    fn single_key_times_out() {
+        let mut state = ComboState::new(PAIR);
+        assert!(state.push(Key1, Pressed, at(0)));
+        assert_eq!(state.next(at(49)), None);
+        assert_eq!(state.next(at(50)), key(Key1, Pressed, 0));
+        assert!(state.push(Key1, Released, at(80)));
+        assert_eq!(state.next(at(80)), key(Key1, Released, 80));
+    }
+
+    #[test]
+    fn partial_combo_released() {
+        let mut state = ComboState::new(PAIR);
+        assert!(state.push(Key1, Pressed, at(0)));
+        assert!(state.push(Key1, Released, at(20)));
+        assert_eq!(state.next(at(20)), key(Key1, Pressed, 0));
+        assert_eq!(state.next(at(20)), key(Key1, Released, 20));
+        assert_eq!(state.next(at(20)), None);
+    }
+
+    #[test]
+    fn other_keys_pass() {
+        let mut state = ComboState::new(PAIR);
+        assert!(state.push(Key3, Pressed, at(0)));
+        assert_eq!(state.next(at(0)), key(Key3, Pressed, 0));
+
+        // A key outside the combo decides the pending one.
+        assert!(state.push(Key1, Pressed, at(10)));
+        assert!(state.push(Key3, Released, at(20)));
+        assert_eq!(state.next(at(20)), key(Key1, Pressed, 10));
+        assert_eq!(state.next(at(20)), key(Key3, Released, 20));
+    }
+
+    #[test]
+    fn longest_overlapping_combo() {
+        let mut state = ComboState::new(OVERLAPPING);
+        assert!(state.push(Key1, Pressed, at(0)));
+        assert!(state.push(Key2, Pressed, at(10)));
+        // Waits for the third key.
+        assert_eq!(state.next(at(10)), None);
+        assert!(state.push(Key3, Pressed, at(20)));
+        assert_eq!(state.next(at(20)), combo(Key1, Pressed, 2, 0));
+
+        assert!(state.push(Key3, Released, at(100)));
+        assert_eq!(state.next(at(100)), combo(Key1, Released, 2, 100));
+        assert!(state.push(Key1, Released, at(110)));
+        assert!(state.push(Key2, Released, at(120)));
+        assert_eq!(state.next(at(120)), None);
+    }
+
+    #[test]
+    fn shorter_overlapping_combo() {
+        let mut state = ComboState::new(OVERLAPPING);
+        assert!(state.push(Key1, Pressed, at(0)));
+        assert!(state.push(Key2, Pressed, at(10)));
+        assert_eq!(state.next(at(49)), None);
+        assert_eq!(state.next(at(50)), combo(Key1, Pressed, 1, 0));
+
+        // Key3 is too late for the long combo and free for other uses.
+        assert!(state.push(Key3, Pressed, at(60)));
+        assert_eq!(state.next(at(110)), key(Key3, Pressed, 60));
+    }
+
+    #[test]
+    fn combo_without_first_key() {
+        let mut state = ComboState::new(OVERLAPPING);
+        assert!(state.push(Key2, Pressed, at(0)));
+        assert!(state.push(Key3, Pressed, at(10)));
+        assert_eq!(state.next(at(10)), None);
+        // Reported as its first key.
+        assert_eq!(state.next(at(50)), combo(Key2, Pressed, 3, 0));
+    }
+
+    #[test]
+    fn released_within_term() {
+        // Released before the longer combo could complete, the shorter one is sent.
+        let mut state = ComboState::new(OVERLAPPING);
+        assert!(state.push(Key1, Pressed, at(0)));
+        assert!(state.push(Key2, Pressed, at(10)));
+        assert!(state.push(Key2, Released, at(30)));
+        assert_eq!(state.next(at(30)), combo(Key1, Pressed, 1, 0));
+        assert_eq!(state.next(at(30)), combo(Key1, Released, 1, 30));
+    }
+
+    #[test]
+    fn push_to_full_queue_fails() {
+        let mut state = ComboState::new(PAIR);
+        assert!(state.push(Key1, Pressed, at(0)));
+        for i in 1..QUEUE_SIZE as u64 {
+            assert!(state.push(Key3, if i % 2 == 1 { Pressed } else { Released }, at(i)));
+        }
+        assert!(state.is_full());
+        assert!(!state.push(Key2, Pressed, at(20)));
+
+        // The full queue decides Key1, nothing queued is lost.
+        assert_eq!(state.next(at(20)), key(Key1, Pressed, 0));
+        assert!(state.push(Key2, Pressed, at(20)));
+        for i in 1..QUEUE_SIZE as u64 {
+            assert_eq!(state.next(at(20)), key(Key3, if i % 2 == 1 { Pressed } else { Released }, i));
+        }
+        assert_eq!(state.next(at(20)), None);
+        assert_eq!(state.next(at(70)), key(Key2, Pressed, 20));
+    }
+}
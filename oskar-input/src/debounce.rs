@@ -0,0 +1,165 @@
+use crate::Event;
+use embassy_time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+pub enum DebounceMode {
+    /// Reports the first edge right away and ignores further edges for the
+    /// debounce time. Lowest latency, but noise can cause a spurious event.
+    Eager,
+    /// Reports a change once the level has been stable for the debounce time.
+    Deferred,
+}
+
+#[derive(Clone, Copy)]
+pub struct Debounce {
+    pub mode: DebounceMode,
+    pub time: Duration,
+}
+
+impl Debounce {
+    pub const fn eager(ms: u64) -> Self {
+        Debounce {
+            mode: DebounceMode::Eager,
+            time: Duration::from_millis(ms),
+        }
+    }
+
+    pub const fn deferred(ms: u64) -> Self {
+        Debounce {
+            mode: DebounceMode::Deferred,
+            time: Duration::from_millis(ms),
+        }
+    }
+}
+
+/// Debounces a single key.
+///
+/// Feed it the current level on every edge, and again once `deadline` has
+/// passed. Time only comes from the timestamps passed in.
+pub struct Debouncer {
+    config: Debounce,
+    /// Last reported state.
+    stable: bool,
+    /// Last level seen.
+    raw: bool,
+    /// Eager: when `stable` last changed. Deferred: when `raw` last changed.
+    changed_at: Instant,
+}
+
+impl Debouncer {
+    pub const fn new(config: Debounce) -> Self {
+        Debouncer {
+            config,
+            stable: false,
+            raw: false,
+            changed_at: Instant::from_ticks(0),
+        }
+    }
+
+    /// Takes the current level, returns an event if the debounced state changed.
+    pub fn update(&mut self, pressed: bool, now: Instant) -> Option<Event> {
+        if matches!(self.config.mode, DebounceMode::Deferred) && pressed != self.raw {
+            self.changed_at = now;
+        }
+        self.raw = pressed;
+
+        if self.raw == self.stable || now < self.changed_at + self.config.time {
+            return None;
+        }
+
+        self.stable = self.raw;
+        if let DebounceMode::Eager = self.config.mode {
+            self.changed_at = now;
+        }
+
+        Some(if self.stable { Event::Pressed } else { Event::Released })
+    }
+
+    /// Time at which `update` has to be called again to catch a change that
+    /// is still being held back.
+    pub fn deadline(&self) -> Option<Instant> {
+        (self.raw != self.stable).then(|| self.changed_at + self.config.time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        // Away from zero, where nothing has changed yet.
+        Instant::from_millis(1000 + ms)
+    }
+
+    #[test]
+    fn eager_press_and_release() {
+        let mut debouncer = Debouncer::new(Debounce::eager(5));
+        assert_eq!(debouncer.update(true, at(0)), Some(Event::Pressed));
+        assert_eq!(debouncer.deadline(), None);
+        assert_eq!(debouncer.update(true, at(30)), None);
+        assert_eq!(debouncer.update(false, at(40)), Some(Event::Released));
+        assert_eq!(debouncer.deadline(), None);
+    }
+
+    #[test]
+    fn eager_bounce_within_window() {
+        let mut debouncer = Debouncer::new(Debounce::eager(5));
+        assert_eq!(debouncer.update(true, at(0)), Some(Event::Pressed));
+        assert_eq!(debouncer.update(false, at(1)), None);
+        assert_eq!(debouncer.update(true, at(2)), None);
+        assert_eq!(debouncer.update(false, at(3)), None);
+        assert_eq!(debouncer.update(true, at(4)), None);
+        assert_eq!(debouncer.deadline(), None);
+        // Still held after the window, nothing to report.
+        assert_eq!(debouncer.update(true, at(5)), None);
+    }
+
+    #[test]
+    fn eager_release_within_window() {
+        let mut debouncer = Debouncer::new(Debounce::eager(5));
+        assert_eq!(debouncer.update(true, at(0)), Some(Event::Pressed));
+        assert_eq!(debouncer.update(false, at(2)), None);
+        // The release is held back until the window is over.
+        assert_eq!(debouncer.deadline(), Some(at(5)));
+        assert_eq!(debouncer.update(false, at(4)), None);
+        assert_eq!(debouncer.update(false, at(5)), Some(Event::Released));
+        assert_eq!(debouncer.deadline(), None);
+    }
+
+    #[test]
+    fn deferred_stable_press_and_release() {
+        let mut debouncer = Debouncer::new(Debounce::deferred(10));
+        assert_eq!(debouncer.update(true, at(0)), None);
+        assert_eq!(debouncer.deadline(), Some(at(10)));
+        assert_eq!(debouncer.update(true, at(9)), None);
+        assert_eq!(debouncer.update(true, at(10)), Some(Event::Pressed));
+        assert_eq!(debouncer.deadline(), None);
+
+        assert_eq!(debouncer.update(false, at(50)), None);
+        assert_eq!(debouncer.deadline(), Some(at(60)));
+        assert_eq!(debouncer.update(false, at(60)), Some(Event::Released));
+        assert_eq!(debouncer.deadline(), None);
+    }
+
+    #[test]
+    fn deferred_bounce_within_window() {
+        let mut debouncer = Debouncer::new(Debounce::deferred(10));
+        assert_eq!(debouncer.update(true, at(0)), None);
+        assert_eq!(debouncer.update(false, at(3)), None);
+        assert_eq!(debouncer.deadline(), None);
+        // Every edge restarts the window.
+        assert_eq!(debouncer.update(true, at(4)), None);
+        assert_eq!(debouncer.deadline(), Some(at(14)));
+        assert_eq!(debouncer.update(true, at(13)), None);
+        assert_eq!(debouncer.update(true, at(14)), Some(Event::Pressed));
+    }
+
+    #[test]
+    fn deferred_glitch_is_ignored() {
+        let mut debouncer = Debouncer::new(Debounce::deferred(10));
+        assert_eq!(debouncer.update(true, at(0)), None);
+        assert_eq!(debouncer.update(false, at(2)), None);
+        assert_eq!(debouncer.update(false, at(20)), None);
+        assert_eq!(debouncer.deadline(), None);
+    }
+}
@@ -0,0 +1,166 @@
+use embassy_time::{Duration, Instant};
+
+/// Point of the acceleration curve: a detent turned less than `interval`
+/// after the previous one in the same direction counts as `steps` detents.
+#[derive(Clone, Copy)]
+pub struct AccelerationStep {
+    pub interval: Duration,
+    pub steps: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Counter-clockwise.
+    Left,
+    /// Clockwise.
+    Right,
+}
+
+/// Scales detents by how fast the encoder is turned.
+pub struct Accelerator {
+    curve: &'static [AccelerationStep],
+    last: Option<(Direction, Instant)>,
+}
+
+impl Accelerator {
+    /// `curve` has to be sorted by `interval`, it is empty to disable acceleration.
+    pub const fn new(curve: &'static [AccelerationStep]) -> Self {
+        Accelerator { curve, last: None }
+    }
+
+    /// Returns how many steps the detent turned at `now` counts as.
+    pub fn steps(&mut self, direction: Direction, now: Instant) -> u8 {
+        let interval = match self.last {
+            Some((last_direction, at)) if last_direction == direction => Some(now - at),
+            _ => None,
+        };
+        self.last = Some((direction, now));
+
+        interval
+            .and_then(|interval| self.curve.iter().find(|point| interval < point.interval))
+            .map_or(1, |point| point.steps.max(1))
+    }
+}
+
+/// Encoder steps of one direction that are not handled yet.
+///
+/// Only one event per direction is queued at a time, it picks up all steps
+/// accumulated until it is handled. This keeps fast spins from flooding
+/// the input queues and sends them in one go instead of dropping steps.
+#[derive(Clone, Copy)]
+pub struct PendingSteps {
+    steps: u16,
+    queued: bool,
+}
+
+impl Default for PendingSteps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PendingSteps {
+    pub const fn new() -> Self {
+        PendingSteps { steps: 0, queued: false }
+    }
+
+    /// Adds steps, returns whether an event has to be queued for them.
+    pub fn add(&mut self, steps: u8) -> bool {
+        self.steps = self.steps.saturating_add(steps as u16);
+        !self.queued
+    }
+
+    /// Marks the event for the steps as queued, further steps go with it.
+    pub fn set_queued(&mut self) {
+        self.queued = true;
+    }
+
+    /// Takes the steps when their event is handled.
+    pub fn take(&mut self) -> u16 {
+        self.queued = false;
+        core::mem::take(&mut self.steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Direction::{Left, Right};
+
+    const CURVE: &[AccelerationStep] = &[
+        AccelerationStep { interval: Duration::from_millis(20), steps: 4 },
+        AccelerationStep { interval: Duration::from_millis(50), steps: 2 },
+    ];
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn curve() {
+        let mut accelerator = Accelerator::new(CURVE);
+        assert_eq!(accelerator.steps(Right, at(0)), 1);
+        assert_eq!(accelerator.steps(Right, at(100)), 1);
+        assert_eq!(accelerator.steps(Right, at(130)), 2);
+        assert_eq!(accelerator.steps(Right, at(140)), 4);
+        // The interval has to be shorter than the point's.
+        assert_eq!(accelerator.steps(Right, at(160)), 2);
+        assert_eq!(accelerator.steps(Right, at(210)), 1);
+    }
+
+    #[test]
+    fn direction_change_starts_over() {
+        let mut accelerator = Accelerator::new(CURVE);
+        assert_eq!(accelerator.steps(Right, at(0)), 1);
+        assert_eq!(accelerator.steps(Left, at(10)), 1);
+        assert_eq!(accelerator.steps(Left, at(20)), 4);
+    }
+
+    #[test]
+    fn without_curve() {
+        let mut accelerator = Accelerator::new(&[]);
+        assert_eq!(accelerator.steps(Right, at(0)), 1);
+        assert_eq!(accelerator.steps(Right, at(1)), 1);
+    }
+
+    #[test]
+    fn zero_steps_count_as_one() {
+        const ZERO: &[AccelerationStep] = &[AccelerationStep { interval: Duration::from_millis(20), steps: 0 }];
+        let mut accelerator = Accelerator::new(ZERO);
+        accelerator.steps(Right, at(0));
+        assert_eq!(accelerator.steps(Right, at(10)), 1);
+    }
+
+    #[test]
+    fn steps_coalesce_until_taken() {
+        let mut pending = PendingSteps::new();
+        assert!(pending.add(1));
+        pending.set_queued();
+        assert!(!pending.add(4));
+        assert!(!pending.add(2));
+        assert_eq!(pending.take(), 7);
+
+        assert!(pending.add(1));
+        assert_eq!(pending.take(), 1);
+        assert_eq!(pending.take(), 0);
+    }
+
+    #[test]
+    fn steps_until_queued() {
+        // Every step asks for an event until one could be queued.
+        let mut pending = PendingSteps::new();
+        assert!(pending.add(1));
+        assert!(pending.add(1));
+        pending.set_queued();
+        assert_eq!(pending.take(), 2);
+    }
+
+    #[test]
+    fn steps_saturate() {
+        let mut pending = PendingSteps::new();
+        for _ in 0..300 {
+            pending.add(u8::MAX);
+        }
+        assert_eq!(pending.take(), u16::MAX);
+    }
+}
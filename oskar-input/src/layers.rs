@@ -0,0 +1,368 @@
+use crate::{Event, Key, NUM_KEYS};
+
+/// Number of layers in a keymap. Layer 0 is the default base layer.
+pub const NUM_LAYERS: usize = 4;
+
+// Active layers are tracked as a bitmask.
+const _: () = assert!(NUM_LAYERS <= 8);
+
+/// What an action does on the layer stack.
+pub enum LayerAction<A> {
+    /// Falls through to the layers below.
+    Transparent,
+    MomentaryLayer(u8),
+    ToggleLayer(u8),
+    OneShotLayer(u8),
+    DefaultLayer(u8),
+    /// Sends `on` if any of the `lock` bits is set in the host's lock state,
+    /// `off` otherwise.
+    IfLock { lock: u8, on: A, off: A },
+    /// Any other action, it is returned to be sent to the host.
+    Other(A),
+}
+
+/// Actions of a keymap, implemented by the firmware's action type.
+pub trait Layered: Copy {
+    fn layer_action(self) -> LayerAction<Self>;
+}
+
+/// One layer of a keymap.
+pub trait Layout {
+    type Action: Layered;
+
+    fn get(&self, key: Key) -> Self::Action;
+}
+
+impl<A: Layered> Layout for [A; NUM_KEYS] {
+    type Action = A;
+
+    fn get(&self, key: Key) -> A {
+        self[key as usize]
+    }
+}
+
+/// Tracks which layers are active and resolves keys through them.
+///
+/// Layers are searched from the highest active layer down to the default
+/// layer, skipping `LayerAction::Transparent` entries. A key is released on
+/// the same layer it was pressed on, even if the active layers changed
+/// meanwhile.
+pub struct LayerState<A> {
+    default_layer: u8,
+    active: u8,
+    oneshot: Option<u8>,
+    pressed_on: [Option<u8>; NUM_KEYS],
+    /// What `LayerAction::IfLock` keys resolved to when they were pressed.
+    if_lock: [Option<A>; NUM_KEYS],
+}
+
+impl<A: Layered> Default for LayerState<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Layered> LayerState<A> {
+    pub const fn new() -> Self {
+        LayerState {
+            default_layer: 0,
+            active: 0,
+            oneshot: None,
+            pressed_on: [None; NUM_KEYS],
+            if_lock: [None; NUM_KEYS],
+        }
+    }
+
+    /// Feeds a key event through the layer stack.
+    ///
+    /// Layer switching actions are consumed here, every other action is
+    /// returned to the caller to be sent to the host.
+    /// `locks` is the host's lock state, used to resolve `LayerAction::IfLock`.
+    pub fn process<L: Layout<Action = A>>(&mut self, keymap: &[L; NUM_LAYERS], locks: u8, key: Key, event: Event) -> Option<A> {
+        let layer = match event {
+            Event::Pressed => {
+                let layer = self.lookup(keymap, key);
+                self.pressed_on[key as usize] = Some(layer);
+                layer
+            }
+            Event::Released => match self.pressed_on[key as usize].take() {
+                Some(layer) => layer,
+                None => self.lookup(keymap, key),
+            },
+        };
+
+        let code = keymap[layer as usize].get(key);
+        let code = match code.layer_action() {
+            // Release what was pressed, even if the lock changed meanwhile.
+            LayerAction::IfLock { lock, on, off } => match event {
+                Event::Pressed => {
+                    let code = if locks & lock != 0 { on } else { off };
+                    self.if_lock[key as usize] = Some(code);
+                    code
+                }
+                Event::Released => self.if_lock[key as usize].take().unwrap_or(off),
+            },
+            _ => code,
+        };
+
+        self.apply(event, code)
+    }
+
+    /// Applies an action that is already resolved, e.g. the outcome of a
+    /// tap-hold key. Returns the action if it isn't a layer switch.
+    pub fn apply(&mut self, event: Event, code: A) -> Option<A> {
+        match code.layer_action() {
+            LayerAction::MomentaryLayer(target) => {
+                match event {
+                    Event::Pressed => self.active |= layer_bit(target),
+                    Event::Released => self.active &= !layer_bit(target),
+                }
+                None
+            }
+            LayerAction::ToggleLayer(target) => {
+                if event == Event::Pressed {
+                    self.active ^= layer_bit(target);
+                }
+                None
+            }
+            LayerAction::OneShotLayer(target) => {
+                if event == Event::Pressed && (target as usize) < NUM_LAYERS {
+                    self.oneshot = Some(target);
+                }
+                None
+            }
+            LayerAction::DefaultLayer(target) => {
+                if event == Event::Pressed && (target as usize) < NUM_LAYERS {
+                    self.default_layer = target;
+                }
+                None
+            }
+            LayerAction::Transparent => None,
+            LayerAction::IfLock { .. } | LayerAction::Other(_) => {
+                if event == Event::Pressed {
+                    self.oneshot = None;
+                }
+                Some(code)
+            }
+        }
+    }
+
+    /// Whether an active layer binds `key` to something else than `LayerAction::Transparent`.
+    pub fn binds<L: Layout<Action = A>>(&self, keymap: &[L; NUM_LAYERS], key: Key) -> bool {
+        !matches!(self.binding(keymap, key).layer_action(), LayerAction::Transparent)
+    }
+
+    /// What `key` is bound to on the active layers, without pressing it.
+    pub fn binding<L: Layout<Action = A>>(&self, keymap: &[L; NUM_LAYERS], key: Key) -> A {
+        keymap[self.lookup(keymap, key) as usize].get(key)
+    }
+
+    /// Returns the highest active layer that does not pass `key` through.
+    fn lookup<L: Layout<Action = A>>(&self, keymap: &[L; NUM_LAYERS], key: Key) -> u8 {
+        let mut active = self.active;
+        if let Some(layer) = self.oneshot {
+            active |= layer_bit(layer);
+        }
+
+        for layer in (self.default_layer as usize + 1..NUM_LAYERS).rev() {
+            if active & (1 << layer) != 0 && !matches!(keymap[layer].get(key).layer_action(), LayerAction::Transparent) {
+                return layer as u8;
+            }
+        }
+
+        self.default_layer
+    }
+}
+
+fn layer_bit(layer: u8) -> u8 {
+    if (layer as usize) < NUM_LAYERS {
+        1 << layer
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::{Pressed, Released};
+    use Key::{Key1, Key2, Key3};
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Action {
+        Transparent,
+        Key(u8),
+        Momentary(u8),
+        Toggle(u8),
+        OneShot(u8),
+        Default(u8),
+        /// Caps Lock.
+        IfCaps(u8, u8),
+    }
+
+    impl Layered for Action {
+        fn layer_action(self) -> LayerAction<Self> {
+            match self {
+                Action::Transparent => LayerAction::Transparent,
+                Action::Momentary(layer) => LayerAction::MomentaryLayer(layer),
+                Action::Toggle(layer) => LayerAction::ToggleLayer(layer),
+                Action::OneShot(layer) => LayerAction::OneShotLayer(layer),
+                Action::Default(layer) => LayerAction::DefaultLayer(layer),
+                Action::IfCaps(on, off) => LayerAction::IfLock {
+                    lock: CAPS_LOCK,
+                    on: Action::Key(on),
+                    off: Action::Key(off),
+                },
+                action => LayerAction::Other(action),
+            }
+        }
+    }
+
+    const CAPS_LOCK: u8 = 0x02;
+
+    type Keymap = [[Action; NUM_KEYS]; NUM_LAYERS];
+
+    /// Key1 switches layers as given, Key2 and Key3 send the layer number
+    /// times ten plus one or two. Layer 1 passes Key3 through.
+    fn keymap(switch: Action) -> Keymap {
+        let mut keymap = [[Action::Transparent; NUM_KEYS]; NUM_LAYERS];
+        for (layer, keys) in keymap.iter_mut().enumerate() {
+            keys[Key2 as usize] = Action::Key(layer as u8 * 10 + 1);
+            keys[Key3 as usize] = Action::Key(layer as u8 * 10 + 2);
+        }
+        keymap[0][Key1 as usize] = switch;
+        keymap[1][Key3 as usize] = Action::Transparent;
+        keymap
+    }
+
+    fn tap(layers: &mut LayerState<Action>, keymap: &Keymap, key: Key) -> Option<Action> {
+        let code = layers.process(keymap, 0, key, Pressed);
+        layers.process(keymap, 0, key, Released);
+        code
+    }
+
+    #[test]
+    fn default_layer() {
+        let keymap = keymap(Action::Transparent);
+        let mut layers = LayerState::new();
+        assert_eq!(layers.process(&keymap, 0, Key2, Pressed), Some(Action::Key(1)));
+        assert_eq!(layers.process(&keymap, 0, Key2, Released), Some(Action::Key(1)));
+        assert_eq!(layers.process(&keymap, 0, Key1, Pressed), None);
+        assert!(!layers.binds(&keymap, Key1));
+        assert!(layers.binds(&keymap, Key2));
+    }
+
+    #[test]
+    fn momentary() {
+        let keymap = keymap(Action::Momentary(2));
+        let mut layers = LayerState::new();
+        assert_eq!(layers.process(&keymap, 0, Key1, Pressed), None);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(21)));
+        assert_eq!(layers.process(&keymap, 0, Key1, Released), None);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(1)));
+    }
+
+    #[test]
+    fn released_on_the_layer_it_was_pressed_on() {
+        let keymap = keymap(Action::Momentary(2));
+        let mut layers = LayerState::new();
+        layers.process(&keymap, 0, Key1, Pressed);
+        assert_eq!(layers.process(&keymap, 0, Key2, Pressed), Some(Action::Key(21)));
+        layers.process(&keymap, 0, Key1, Released);
+        assert_eq!(layers.process(&keymap, 0, Key2, Released), Some(Action::Key(21)));
+    }
+
+    #[test]
+    fn toggle() {
+        let keymap = keymap(Action::Toggle(3));
+        let mut layers = LayerState::new();
+        tap(&mut layers, &keymap, Key1);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(31)));
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(31)));
+        tap(&mut layers, &keymap, Key1);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(1)));
+    }
+
+    #[test]
+    fn one_shot_expires_after_the_next_key() {
+        let keymap = keymap(Action::OneShot(2));
+        let mut layers = LayerState::new();
+        tap(&mut layers, &keymap, Key1);
+        assert!(layers.binds(&keymap, Key2));
+        assert_eq!(layers.binding(&keymap, Key2), Action::Key(21));
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(21)));
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(1)));
+    }
+
+    #[test]
+    fn one_shot_held_key_released_on_its_layer() {
+        let keymap = keymap(Action::OneShot(2));
+        let mut layers = LayerState::new();
+        tap(&mut layers, &keymap, Key1);
+        assert_eq!(layers.process(&keymap, 0, Key2, Pressed), Some(Action::Key(21)));
+        assert_eq!(layers.process(&keymap, 0, Key3, Pressed), Some(Action::Key(2)));
+        assert_eq!(layers.process(&keymap, 0, Key2, Released), Some(Action::Key(21)));
+    }
+
+    #[test]
+    fn default_layer_switch() {
+        let mut keymap = keymap(Action::Default(2));
+        keymap[2][Key1 as usize] = Action::Default(0);
+        let mut layers = LayerState::new();
+        tap(&mut layers, &keymap, Key1);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(21)));
+        tap(&mut layers, &keymap, Key1);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(1)));
+    }
+
+    #[test]
+    fn layers_below_the_default_are_inactive() {
+        let mut keymap = keymap(Action::Default(2));
+        keymap[2][Key1 as usize] = Action::Toggle(1);
+        let mut layers = LayerState::new();
+        tap(&mut layers, &keymap, Key1);
+        tap(&mut layers, &keymap, Key1);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(21)));
+    }
+
+    #[test]
+    fn transparent_falls_through() {
+        let keymap = keymap(Action::Momentary(1));
+        let mut layers = LayerState::new();
+        layers.process(&keymap, 0, Key1, Pressed);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(11)));
+        // Layer 1 passes Key3 to the default layer.
+        assert_eq!(tap(&mut layers, &keymap, Key3), Some(Action::Key(2)));
+        // Key1 is transparent on layer 1 and still releases the layer.
+        layers.process(&keymap, 0, Key1, Released);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(1)));
+    }
+
+    #[test]
+    fn highest_active_layer_wins() {
+        let mut keymap = keymap(Action::Toggle(1));
+        keymap[0][Key3 as usize] = Action::Toggle(3);
+        let mut layers = LayerState::new();
+        tap(&mut layers, &keymap, Key1);
+        tap(&mut layers, &keymap, Key3);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(31)));
+    }
+
+    #[test]
+    fn layers_out_of_range_are_ignored() {
+        let keymap = keymap(Action::Momentary(NUM_LAYERS as u8));
+        let mut layers = LayerState::new();
+        layers.process(&keymap, 0, Key1, Pressed);
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(1)));
+    }
+
+    #[test]
+    fn if_lock_releases_what_was_pressed() {
+        let mut keymap = keymap(Action::Transparent);
+        keymap[0][Key2 as usize] = Action::IfCaps(7, 8);
+        let mut layers = LayerState::new();
+        assert_eq!(layers.process(&keymap, CAPS_LOCK, Key2, Pressed), Some(Action::Key(7)));
+        assert_eq!(layers.process(&keymap, 0, Key2, Released), Some(Action::Key(7)));
+        assert_eq!(tap(&mut layers, &keymap, Key2), Some(Action::Key(8)));
+    }
+}
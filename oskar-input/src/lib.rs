@@ -0,0 +1,58 @@
+//! Key event processing of the OSKAR firmware.
+//!
+//! Everything in here only works on the events and timestamps passed in, so
+//! it builds for the host and is tested there:
+//!
+//! ```sh
+//! cd oskar-input
+//! cargo test --target $(rustc -vV | sed -n 's/host: //p')
+//! ```
+
+#![no_std]
+
+pub mod combo;
+pub mod debounce;
+pub mod encoder;
+pub mod layers;
+pub mod queue;
+pub mod tap_hold;
+
+/// Inputs in wire order, see `oskar_protocol::KEY_NAMES`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    EncoderLeft,
+    EncoderRight,
+    EncoderButton,
+    Key1,
+    Key2,
+    Key3,
+    /// Encoder turned while `EncoderButton` is held.
+    EncoderPressedLeft,
+    EncoderPressedRight,
+}
+
+pub const NUM_KEYS: usize = oskar_protocol::NUM_KEYS;
+
+impl Key {
+    pub const ALL: [Key; NUM_KEYS] = [
+        Key::EncoderLeft,
+        Key::EncoderRight,
+        Key::EncoderButton,
+        Key::Key1,
+        Key::Key2,
+        Key::Key3,
+        Key::EncoderPressedLeft,
+        Key::EncoderPressedRight,
+    ];
+
+    /// Whether the key is an encoder step, which only reports a press.
+    pub const fn is_encoder_step(self) -> bool {
+        matches!(self, Key::EncoderLeft | Key::EncoderRight | Key::EncoderPressedLeft | Key::EncoderPressedRight)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Pressed,
+    Released,
+}
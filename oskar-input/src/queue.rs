@@ -0,0 +1,180 @@
+use crate::{Event, Key, NUM_KEYS};
+use embassy_time::Instant;
+use heapless::Deque;
+
+/// Events queued per subscriber of the firmware's input bus.
+pub const QUEUE_SIZE: usize = 16;
+
+/// Slots kept free for releases, one per key.
+const RELEASE_SLOTS: usize = NUM_KEYS;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputEvent {
+    pub key: Key,
+    pub event: Event,
+    /// When the input changed, after debouncing.
+    pub at: Instant,
+}
+
+/// Key events waiting for one consumer.
+///
+/// A full queue drops presses, but never the release of a queued press:
+/// presses are only queued while there is room left for the release of
+/// every key. The release of a dropped press is dropped, too, as is the
+/// release of a key pressed before the queue was created.
+pub struct InputQueue {
+    events: Deque<InputEvent, QUEUE_SIZE>,
+    /// Keys whose press was queued, their release is always queued, too.
+    held: u8,
+    /// Keys whose press was dropped.
+    dropped: u8,
+    overflows: u32,
+}
+
+impl Default for InputQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputQueue {
+    pub const fn new() -> Self {
+        InputQueue {
+            events: Deque::new(),
+            held: 0,
+            dropped: 0,
+            overflows: 0,
+        }
+    }
+
+    /// Queues an event. Returns whether it was queued.
+    pub fn push(&mut self, event: InputEvent) -> bool {
+        let bit = 1 << event.key as u8;
+        // Encoder steps are never released.
+        let tracked = !event.key.is_encoder_step();
+
+        match event.event {
+            Event::Pressed if self.events.len() >= QUEUE_SIZE - RELEASE_SLOTS => {
+                if tracked {
+                    self.dropped |= bit;
+                }
+                self.overflows = self.overflows.wrapping_add(1);
+                return false;
+            }
+            Event::Pressed => {
+                if tracked {
+                    self.held |= bit;
+                }
+            }
+            Event::Released if self.held & bit != 0 => self.held &= !bit,
+            Event::Released => {
+                if self.dropped & bit != 0 {
+                    self.dropped &= !bit;
+                    self.overflows = self.overflows.wrapping_add(1);
+                }
+                return false;
+            }
+        }
+        // Every held key has a slot for its release left.
+        self.events.push_back(event).is_ok()
+    }
+
+    /// Takes the next event, if there is one.
+    pub fn pop(&mut self) -> Option<InputEvent> {
+        self.events.pop_front()
+    }
+
+    /// Number of events dropped because the queue was full.
+    pub fn overflows(&self) -> u32 {
+        self.overflows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::{Pressed, Released};
+    use Key::{EncoderRight, Key1, Key2};
+
+    fn event(key: Key, event: Event, ms: u64) -> InputEvent {
+        InputEvent {
+            key,
+            event,
+            at: Instant::from_millis(ms),
+        }
+    }
+
+    /// Fills the queue with encoder steps up to the release slots.
+    fn filled() -> InputQueue {
+        let mut queue = InputQueue::new();
+        for ms in 0..(QUEUE_SIZE - RELEASE_SLOTS) as u64 {
+            assert!(queue.push(event(EncoderRight, Pressed, ms)));
+        }
+        queue
+    }
+
+    #[test]
+    fn in_order() {
+        let mut queue = InputQueue::new();
+        assert!(queue.push(event(Key1, Pressed, 0)));
+        assert!(queue.push(event(Key2, Pressed, 10)));
+        assert!(queue.push(event(Key1, Released, 20)));
+        assert_eq!(queue.pop(), Some(event(Key1, Pressed, 0)));
+        assert_eq!(queue.pop(), Some(event(Key2, Pressed, 10)));
+        assert_eq!(queue.pop(), Some(event(Key1, Released, 20)));
+        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.overflows(), 0);
+    }
+
+    #[test]
+    fn full_queue_keeps_releases() {
+        let mut queue = InputQueue::new();
+        assert!(queue.push(event(Key1, Pressed, 0)));
+        for ms in 1..(QUEUE_SIZE - RELEASE_SLOTS) as u64 {
+            assert!(queue.push(event(EncoderRight, Pressed, ms)));
+        }
+        assert!(!queue.push(event(EncoderRight, Pressed, 20)));
+        assert!(queue.push(event(Key1, Released, 30)));
+
+        for _ in 0..QUEUE_SIZE - RELEASE_SLOTS {
+            assert!(queue.pop().is_some());
+        }
+        assert_eq!(queue.pop(), Some(event(Key1, Released, 30)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn dropped_press_drops_release() {
+        let mut queue = filled();
+        assert!(!queue.push(event(Key1, Pressed, 20)));
+        assert!(!queue.push(event(Key1, Released, 30)));
+        assert_eq!(queue.overflows(), 2);
+
+        // Once there is room again the key works as before.
+        queue.pop();
+        assert!(queue.push(event(Key1, Pressed, 40)));
+        assert!(queue.push(event(Key1, Released, 50)));
+        assert_eq!(queue.overflows(), 2);
+    }
+
+    #[test]
+    fn dropped_steps_count() {
+        let mut queue = filled();
+        assert!(!queue.push(event(EncoderRight, Pressed, 20)));
+        assert!(!queue.push(event(EncoderRight, Pressed, 30)));
+        assert_eq!(queue.overflows(), 2);
+    }
+
+    #[test]
+    fn release_of_key_held_before() {
+        // The press happened before the queue was there, its release isn't
+        // queued and doesn't count as dropped.
+        let mut queue = InputQueue::new();
+        assert!(!queue.push(event(Key1, Released, 0)));
+        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.overflows(), 0);
+
+        assert!(queue.push(event(Key1, Pressed, 10)));
+        assert!(queue.push(event(Key1, Released, 20)));
+    }
+}
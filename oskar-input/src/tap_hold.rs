@@ -0,0 +1,554 @@
+use crate::{Event, Key, NUM_KEYS};
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+use oskar_protocol::{BasicAction, TapHold};
+
+/// Events that can be held back while a tap-hold key is undecided. Once the
+/// queue is full the key is resolved as held.
+const QUEUE_SIZE: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum TapHoldEvent<A> {
+    /// A key event from the input, to be resolved through the layers.
+    Key(Key, Event, Instant),
+    /// A tap-hold key or a combo was pressed or released as `code`.
+    Resolved(Key, Event, A),
+}
+
+#[derive(Clone, Copy)]
+enum Decision {
+    Tap,
+    Hold,
+}
+
+/// Actions for one number of taps of a `TapDance`.
+#[derive(Clone, Copy)]
+pub struct TapDanceStep<A> {
+    /// Sent when the key is tapped this many times.
+    pub tap: A,
+    /// Sent instead of `tap` when the last tap is held longer than the term.
+    /// `None` holds `tap`.
+    pub hold: Option<A>,
+}
+
+/// Different actions for single, double, triple... taps of a key.
+#[derive(Clone, Copy)]
+pub struct TapDance<A: 'static> {
+    /// Actions per number of taps, starting with a single tap. Further taps
+    /// repeat the last entry.
+    pub steps: &'static [TapDanceStep<A>],
+    /// Time after a press or release of the key until the taps are counted.
+    pub term: Duration,
+}
+
+#[derive(Clone, Copy)]
+struct PendingTapHold {
+    key: Key,
+    config: TapHold,
+    pressed_at: Instant,
+}
+
+impl PendingTapHold {
+    fn deadline(&self) -> Instant {
+        self.pressed_at + Duration::from_millis(self.config.term_ms as u64)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PendingTapDance<A: 'static> {
+    key: Key,
+    dance: TapDance<A>,
+    taps: u8,
+    pressed: bool,
+    /// Time of the last press or release of the key.
+    changed_at: Instant,
+}
+
+impl<A: Copy> PendingTapDance<A> {
+    fn deadline(&self) -> Instant {
+        self.changed_at + self.dance.term
+    }
+
+    /// Returns the action for the taps so far and whether the key is still held.
+    fn finish(&self, timed_out: bool) -> (A, bool) {
+        let step = self.dance.steps[(self.taps as usize).min(self.dance.steps.len()) - 1];
+        match (self.pressed, timed_out) {
+            (true, true) => (step.hold.unwrap_or(step.tap), true),
+            (pressed, _) => (step.tap, pressed),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Pending<A: 'static> {
+    TapHold(PendingTapHold),
+    TapDance(PendingTapDance<A>),
+}
+
+/// Decides whether tap-hold keys are tapped or held, and counts the taps of
+/// tap dance keys.
+///
+/// Key events pass through in order. While a tap-hold key or tap dance is
+/// undecided all later events are held back, and replayed once it is
+/// decided, so they are resolved with the hold action's layer already
+/// applied. Time only comes from the timestamps passed in, there is no
+/// clock in here. `A` is the action type the keys resolve to.
+pub struct TapHoldState<A: 'static> {
+    pending: Option<Pending<A>>,
+    /// Tap dance that was decided as tapped, its release is sent next.
+    tapped: Option<(Key, A)>,
+    /// Held back events, with the action of events that are already resolved.
+    events: Deque<(Key, Event, Instant, Option<A>), QUEUE_SIZE>,
+    /// What each decided tap-hold key was pressed as, until it is released.
+    active: [Option<A>; NUM_KEYS],
+}
+
+impl<A: Copy + From<BasicAction>> Default for TapHoldState<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Copy + From<BasicAction>> TapHoldState<A> {
+    pub const fn new() -> Self {
+        TapHoldState {
+            pending: None,
+            tapped: None,
+            events: Deque::new(),
+            active: [None; NUM_KEYS],
+        }
+    }
+
+    /// Queues an input event, call `next` until it returns `None` afterwards.
+    /// Returns `false` if the queue is full and the event was not queued.
+    /// `next` always returns an event then, as a full queue decides the
+    /// pending key, so call it and try again.
+    #[must_use]
+    pub fn push(&mut self, key: Key, event: Event, at: Instant) -> bool {
+        self.events.push_back((key, event, at, None)).is_ok()
+    }
+
+    /// Queues an event that is already resolved to `code`, e.g. a combo.
+    /// It comes out of `next` as `TapHoldEvent::Resolved`. Fails like `push`.
+    #[must_use]
+    pub fn push_resolved(&mut self, key: Key, event: Event, code: A, at: Instant) -> bool {
+        self.events.push_back((key, event, at, Some(code))).is_ok()
+    }
+
+    /// Whether `push` would fail.
+    pub fn is_full(&self) -> bool {
+        self.events.is_full()
+    }
+
+    /// Time at which the pending key is decided, `next` has to be called then.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.pending? {
+            Pending::TapHold(pending) => Some(pending.deadline()),
+            Pending::TapDance(pending) => Some(pending.deadline()),
+        }
+    }
+
+    /// Marks `key` as an undecided tap-hold key. Called for a `TapHoldEvent::Key`
+    /// press that resolved to a tap-hold action.
+    pub fn start(&mut self, key: Key, config: TapHold, at: Instant) {
+        self.pending = Some(Pending::TapHold(PendingTapHold {
+            key,
+            config,
+            pressed_at: at,
+        }));
+    }
+
+    /// Starts counting the taps of `key`. Called for a `TapHoldEvent::Key`
+    /// press that resolved to a tap dance, `dance` must have steps.
+    pub fn start_dance(&mut self, key: Key, dance: TapDance<A>, at: Instant) {
+        self.pending = Some(Pending::TapDance(PendingTapDance {
+            key,
+            dance,
+            taps: 1,
+            pressed: true,
+            changed_at: at,
+        }));
+    }
+
+    /// Returns what a tap-hold or tap dance key was pressed as, when it is released.
+    pub fn release(&mut self, key: Key) -> Option<A> {
+        self.active[key as usize].take()
+    }
+
+    /// Returns the next event to process, or `None` if there is none or a
+    /// tap-hold key or tap dance is still undecided at `now`.
+    pub fn next(&mut self, now: Instant) -> Option<TapHoldEvent<A>> {
+        if let Some((key, code)) = self.tapped.take() {
+            return Some(TapHoldEvent::Resolved(key, Event::Released, code));
+        }
+
+        match self.pending {
+            Some(Pending::TapHold(pending)) => {
+                let code = match self.decide(&pending, now)? {
+                    Decision::Tap => pending.config.tap.into(),
+                    Decision::Hold => pending.config.hold.into(),
+                };
+                self.pending = None;
+                self.active[pending.key as usize] = Some(code);
+                return Some(TapHoldEvent::Resolved(pending.key, Event::Pressed, code));
+            }
+            Some(Pending::TapDance(pending)) => {
+                let (code, pressed) = self.count_taps(pending, now)?;
+                self.pending = None;
+                if pressed {
+                    self.active[pending.key as usize] = Some(code);
+                } else {
+                    self.tapped = Some((pending.key, code));
+                }
+                return Some(TapHoldEvent::Resolved(pending.key, Event::Pressed, code));
+            }
+            None => {}
+        }
+
+        match self.events.pop_front()? {
+            (key, event, _, Some(code)) => Some(TapHoldEvent::Resolved(key, event, code)),
+            (key, event, at, None) => Some(TapHoldEvent::Key(key, event, at)),
+        }
+    }
+
+    fn decide(&self, pending: &PendingTapHold, now: Instant) -> Option<Decision> {
+        let deadline = pending.deadline();
+        let config = &pending.config;
+        // Keys pressed after the pending key, for permissive hold.
+        let mut pressed_after = 0u8;
+
+        for &(key, event, at, _) in self.events.iter() {
+            if at >= deadline {
+                return Some(Decision::Hold);
+            }
+
+            let bit = 1 << key as u8;
+            match event {
+                Event::Released if key == pending.key => return Some(Decision::Tap),
+                Event::Pressed if config.hold_on_other_key_press => return Some(Decision::Hold),
+                // Encoder steps never report a release, they are a complete tap.
+                Event::Pressed if key.is_encoder_step() && config.permissive_hold => {
+                    return Some(Decision::Hold);
+                }
+                Event::Pressed => pressed_after |= bit,
+                Event::Released if pressed_after & bit != 0 && config.permissive_hold => {
+                    return Some(Decision::Hold);
+                }
+                Event::Released => {}
+            }
+        }
+
+        if now >= deadline || self.events.is_full() {
+            Some(Decision::Hold)
+        } else {
+            None
+        }
+    }
+
+    /// Takes the events of a tap dance key from the queue. Returns the action
+    /// and whether the key is still held once the dance is over, which is
+    /// when the term passes without another tap, another key is used or the
+    /// last step is reached.
+    fn count_taps(&mut self, mut pending: PendingTapDance<A>, now: Instant) -> Option<(A, bool)> {
+        while let Some(&(key, event, at, _)) = self.events.front() {
+            if at >= pending.deadline() {
+                return Some(pending.finish(true));
+            }
+            if key != pending.key {
+                return Some(pending.finish(false));
+            }
+
+            self.events.pop_front();
+            pending.changed_at = at;
+            match event {
+                Event::Pressed => {
+                    pending.taps = pending.taps.saturating_add(1);
+                    pending.pressed = true;
+                }
+                Event::Released => {
+                    pending.pressed = false;
+                    if pending.taps as usize >= pending.dance.steps.len() {
+                        return Some(pending.finish(false));
+                    }
+                }
+            }
+        }
+
+        if now >= pending.deadline() {
+            return Some(pending.finish(true));
+        }
+        self.pending = Some(Pending::TapDance(pending));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::{Pressed, Released};
+    use Key::{Key1, Key2};
+
+    const TAP: BasicAction = BasicAction::Keycode(0x04);
+    const HOLD: BasicAction = BasicAction::MomentaryLayer(1);
+    const TAP_HOLD: TapHold = TapHold::new(TAP, HOLD);
+
+    const DANCE: TapDance<BasicAction> = TapDance {
+        steps: &[
+            TapDanceStep {
+                tap: BasicAction::Keycode(0x1E),
+                hold: Some(BasicAction::Modifiers(0x01)),
+            },
+            TapDanceStep {
+                tap: BasicAction::Keycode(0x1F),
+                hold: None,
+            },
+            TapDanceStep {
+                tap: BasicAction::Keycode(0x20),
+                hold: None,
+            },
+        ],
+        term: Duration::from_millis(100),
+    };
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    /// Presses `Key1` at 0 ms and makes it an undecided tap-hold key.
+    fn pressed(config: TapHold) -> TapHoldState<BasicAction> {
+        let mut state = TapHoldState::new();
+        assert!(state.push(Key1, Pressed, at(0)));
+        assert_eq!(state.next(at(0)), Some(TapHoldEvent::Key(Key1, Pressed, at(0))));
+        state.start(Key1, config, at(0));
+        assert_eq!(state.next(at(0)), None);
+        state
+    }
+
+    /// Presses `Key1` at 0 ms and starts counting its taps.
+    fn dancing() -> TapHoldState<BasicAction> {
+        let mut state = TapHoldState::new();
+        assert!(state.push(Key1, Pressed, at(0)));
+        assert_eq!(state.next(at(0)), Some(TapHoldEvent::Key(Key1, Pressed, at(0))));
+        state.start_dance(Key1, DANCE, at(0));
+        state
+    }
+
+    fn resolved(key: Key, event: Event, code: BasicAction) -> Option<TapHoldEvent<BasicAction>> {
+        Some(TapHoldEvent::Resolved(key, event, code))
+    }
+
+    #[test]
+    fn tap_before_term() {
+        let mut state = pressed(TAP_HOLD);
+        assert_eq!(state.deadline(), Some(at(200)));
+        assert!(state.push(Key1, Released, at(150)));
+        assert_eq!(state.next(at(150)), resolved(Key1, Pressed, TAP));
+        assert_eq!(state.next(at(150)), Some(TapHoldEvent::Key(Key1, Released, at(150))));
+        assert_eq!(state.release(Key1), Some(TAP));
+        assert_eq!(state.next(at(150)), None);
+        assert_eq!(state.deadline(), None);
+    }
+
+    #[test]
+    fn hold_after_term() {
+        let mut state = pressed(TAP_HOLD);
+        assert_eq!(state.next(at(199)), None);
+        assert_eq!(state.next(at(200)), resolved(Key1, Pressed, HOLD));
+        assert_eq!(state.next(at(200)), None);
+
+        assert!(state.push(Key1, Released, at(500)));
+        assert_eq!(state.next(at(500)), Some(TapHoldEvent::Key(Key1, Released, at(500))));
+        assert_eq!(state.release(Key1), Some(HOLD));
+    }
+
+    #[test]
+    fn release_after_term_is_hold() {
+        // The release is only seen late, its timestamp decides.
+        let mut state = pressed(TAP_HOLD);
+        assert!(state.push(Key1, Released, at(250)));
+        assert_eq!(state.next(at(250)), resolved(Key1, Pressed, HOLD));
+    }
+
+    #[test]
+    fn other_keys_wait_for_the_decision() {
+        let mut state = pressed(TAP_HOLD);
+        assert!(state.push(Key2, Pressed, at(50)));
+        assert_eq!(state.next(at(50)), None);
+        assert!(state.push(Key2, Released, at(80)));
+        assert_eq!(state.next(at(80)), None);
+
+        assert_eq!(state.next(at(200)), resolved(Key1, Pressed, HOLD));
+        assert_eq!(state.next(at(200)), Some(TapHoldEvent::Key(Key2, Pressed, at(50))));
+        assert_eq!(state.next(at(200)), Some(TapHoldEvent::Key(Key2, Released, at(80))));
+        assert_eq!(state.next(at(200)), None);
+    }
+
+    #[test]
+    fn tap_with_other_key_inside() {
+        let mut state = pressed(TAP_HOLD);
+        assert!(state.push(Key2, Pressed, at(50)));
+        assert!(state.push(Key1, Released, at(100)));
+        assert_eq!(state.next(at(100)), resolved(Key1, Pressed, TAP));
+        assert_eq!(state.next(at(100)), Some(TapHoldEvent::Key(Key2, Pressed, at(50))));
+        assert_eq!(state.next(at(100)), Some(TapHoldEvent::Key(Key1, Released, at(100))));
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let mut state = pressed(TapHold {
+            hold_on_other_key_press: true,
+            ..TAP_HOLD
+        });
+        assert!(state.push(Key2, Pressed, at(50)));
+        assert_eq!(state.next(at(50)), resolved(Key1, Pressed, HOLD));
+        assert_eq!(state.next(at(50)), Some(TapHoldEvent::Key(Key2, Pressed, at(50))));
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let config = TapHold {
+            permissive_hold: true,
+            ..TAP_HOLD
+        };
+        let mut state = pressed(config);
+        assert!(state.push(Key2, Pressed, at(50)));
+        assert_eq!(state.next(at(50)), None);
+        assert!(state.push(Key2, Released, at(80)));
+        assert_eq!(state.next(at(80)), resolved(Key1, Pressed, HOLD));
+        assert_eq!(state.next(at(80)), Some(TapHoldEvent::Key(Key2, Pressed, at(50))));
+        assert_eq!(state.next(at(80)), Some(TapHoldEvent::Key(Key2, Released, at(80))));
+
+        // A key that was already down before doesn't count.
+        let mut state = pressed(config);
+        assert!(state.push(Key2, Released, at(50)));
+        assert!(state.push(Key1, Released, at(80)));
+        assert_eq!(state.next(at(80)), resolved(Key1, Pressed, TAP));
+    }
+
+    #[test]
+    fn permissive_hold_encoder_step() {
+        let mut state = pressed(TapHold {
+            permissive_hold: true,
+            ..TAP_HOLD
+        });
+        assert!(state.push(Key::EncoderRight, Pressed, at(50)));
+        assert_eq!(state.next(at(50)), resolved(Key1, Pressed, HOLD));
+        assert_eq!(state.next(at(50)), Some(TapHoldEvent::Key(Key::EncoderRight, Pressed, at(50))));
+
+        // Without permissive hold the step waits like any other key.
+        let mut state = pressed(TAP_HOLD);
+        assert!(state.push(Key::EncoderRight, Pressed, at(50)));
+        assert_eq!(state.next(at(50)), None);
+    }
+
+    #[test]
+    fn full_queue_is_hold() {
+        let mut state = pressed(TAP_HOLD);
+        for i in 0..QUEUE_SIZE as u64 / 2 {
+            assert!(state.push(Key2, Pressed, at(10 + 2 * i)));
+            assert!(state.push(Key2, Released, at(11 + 2 * i)));
+            let expected = if i + 1 < QUEUE_SIZE as u64 / 2 { None } else { resolved(Key1, Pressed, HOLD) };
+            assert_eq!(state.next(at(11 + 2 * i)), expected);
+        }
+        for _ in 0..QUEUE_SIZE {
+            assert!(matches!(state.next(at(50)), Some(TapHoldEvent::Key(Key2, _, _))));
+        }
+        assert_eq!(state.next(at(50)), None);
+    }
+
+    #[test]
+    fn push_to_full_queue_fails() {
+        let mut state = pressed(TAP_HOLD);
+        for i in 0..QUEUE_SIZE as u64 {
+            assert!(state.push(Key2, if i % 2 == 0 { Pressed } else { Released }, at(10 + i)));
+        }
+        assert!(state.is_full());
+        assert!(!state.push(Key2, Pressed, at(30)));
+        assert!(!state.push_resolved(Key2, Pressed, HOLD, at(30)));
+
+        // Nothing queued is lost, the full queue decides the pending key.
+        assert_eq!(state.next(at(30)), resolved(Key1, Pressed, HOLD));
+        assert!(matches!(state.next(at(30)), Some(TapHoldEvent::Key(Key2, Pressed, _))));
+        assert!(state.push(Key2, Pressed, at(30)));
+        for _ in 0..QUEUE_SIZE {
+            assert!(matches!(state.next(at(30)), Some(TapHoldEvent::Key(Key2, _, _))));
+        }
+        assert_eq!(state.next(at(30)), None);
+    }
+
+    #[test]
+    fn resolved_events_pass_through() {
+        let mut state = TapHoldState::new();
+        assert!(state.push_resolved(Key2, Pressed, HOLD, at(0)));
+        assert_eq!(state.next(at(0)), resolved(Key2, Pressed, HOLD));
+    }
+
+    #[test]
+    fn single_tap() {
+        let mut state = dancing();
+        assert_eq!(state.deadline(), Some(at(100)));
+        assert!(state.push(Key1, Released, at(50)));
+        assert_eq!(state.next(at(50)), None);
+        assert_eq!(state.deadline(), Some(at(150)));
+        assert_eq!(state.next(at(150)), resolved(Key1, Pressed, DANCE.steps[0].tap));
+        assert_eq!(state.next(at(150)), resolved(Key1, Released, DANCE.steps[0].tap));
+        assert_eq!(state.next(at(150)), None);
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut state = dancing();
+        assert!(state.push(Key1, Released, at(50)));
+        assert!(state.push(Key1, Pressed, at(100)));
+        assert!(state.push(Key1, Released, at(150)));
+        assert_eq!(state.next(at(150)), None);
+        assert_eq!(state.next(at(250)), resolved(Key1, Pressed, DANCE.steps[1].tap));
+        assert_eq!(state.next(at(250)), resolved(Key1, Released, DANCE.steps[1].tap));
+    }
+
+    #[test]
+    fn held_tap() {
+        let mut state = dancing();
+        assert_eq!(state.next(at(99)), None);
+        assert_eq!(state.next(at(100)), resolved(Key1, Pressed, DANCE.steps[0].hold.unwrap()));
+        assert!(state.push(Key1, Released, at(300)));
+        assert_eq!(state.next(at(300)), Some(TapHoldEvent::Key(Key1, Released, at(300))));
+        assert_eq!(state.release(Key1), DANCE.steps[0].hold);
+
+        // Steps without a hold action hold their tap.
+        let mut state = dancing();
+        assert!(state.push(Key1, Released, at(20)));
+        assert!(state.push(Key1, Pressed, at(40)));
+        assert_eq!(state.next(at(140)), resolved(Key1, Pressed, DANCE.steps[1].tap));
+        assert_eq!(state.release(Key1), Some(DANCE.steps[1].tap));
+    }
+
+    #[test]
+    fn last_step_ends_the_dance() {
+        let mut state = dancing();
+        assert!(state.push(Key1, Released, at(20)));
+        assert!(state.push(Key1, Pressed, at(40)));
+        assert!(state.push(Key1, Released, at(60)));
+        assert!(state.push(Key1, Pressed, at(80)));
+        assert_eq!(state.next(at(80)), None);
+        // The third release reaches `steps.len()`, no need to wait for the term.
+        assert!(state.push(Key1, Released, at(100)));
+        assert_eq!(state.next(at(100)), resolved(Key1, Pressed, DANCE.steps[2].tap));
+        assert_eq!(state.next(at(100)), resolved(Key1, Released, DANCE.steps[2].tap));
+        assert_eq!(state.deadline(), None);
+
+        // A fourth tap starts over.
+        assert!(state.push(Key1, Pressed, at(120)));
+        assert_eq!(state.next(at(120)), Some(TapHoldEvent::Key(Key1, Pressed, at(120))));
+    }
+
+    #[test]
+    fn other_key_ends_the_dance() {
+        let mut state = dancing();
+        assert!(state.push(Key1, Released, at(20)));
+        assert!(state.push(Key2, Pressed, at(40)));
+        assert_eq!(state.next(at(40)), resolved(Key1, Pressed, DANCE.steps[0].tap));
+        assert_eq!(state.next(at(40)), resolved(Key1, Released, DANCE.steps[0].tap));
+        assert_eq!(state.next(at(40)), Some(TapHoldEvent::Key(Key2, Pressed, at(40))));
+    }
+}
@@ -0,0 +1,337 @@
+use crate::Error;
+
+/// Wire representation of a key binding.
+///
+/// Key and media codes are the raw HID usage IDs, so the encoding does not
+/// depend on the usage enums the firmware uses internally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Action {
+    Transparent,
+    Keycode(u8),
+    Media(u8),
+    /// Modifier bit mask as in the boot keyboard report plus up to six
+    /// keycodes, unused slots are 0.
+    Chord { modifiers: u8, keycodes: [u8; 6] },
+    /// Plays the macro with this index, see `macros`.
+    Macro(u8),
+    MomentaryLayer(u8),
+    ToggleLayer(u8),
+    OneShotLayer(u8),
+    DefaultLayer(u8),
+    /// Different actions for tapping and holding the key.
+    TapHold(TapHold),
+    /// Mouse button bit mask, bit 0 is the left button.
+    MouseButton(u8),
+    /// Moves the cursor repeatedly while held.
+    MouseMove { x: i8, y: i8 },
+    /// Scrolls by this many notches, positive is up.
+    Wheel(i8),
+    /// Scrolls horizontally by this many notches, positive is right.
+    Pan(i8),
+    /// Any usage of the Consumer page (0x0C).
+    Consumer(u16),
+    /// Usage of the System Control collection of the Generic Desktop page, 0x81 to 0xB7.
+    System(u8),
+    /// Sends `on` if any of the host's lock LEDs in the `lock` bit mask (as
+    /// in the boot keyboard LED report) is lit, `off` otherwise.
+    IfLock { lock: u8, on: BasicAction, off: BasicAction },
+    /// Counts consecutive taps and sends a different action per count, as
+    /// defined by the tap dance with this index in the firmware.
+    TapDance(u8),
+    /// Sends the binding of the current encoder mode for the direction the
+    /// encoder is turned in, only useful on encoder keys.
+    EncoderMode,
+    /// Switches the encoder to its next mode.
+    NextEncoderMode,
+}
+
+/// Actions that fit into half of a `TapHold`, encoded as tag and value with
+/// the tags of the corresponding `Action`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum BasicAction {
+    Keycode(u8),
+    Media(u8),
+    /// Modifier bit mask as in the boot keyboard report.
+    Modifiers(u8),
+    Macro(u8),
+    MomentaryLayer(u8),
+    ToggleLayer(u8),
+    OneShotLayer(u8),
+    DefaultLayer(u8),
+}
+
+impl BasicAction {
+    fn encode(&self) -> [u8; 2] {
+        match *self {
+            BasicAction::Keycode(code) => [0x01, code],
+            BasicAction::Media(code) => [0x02, code],
+            BasicAction::MomentaryLayer(layer) => [0x03, layer],
+            BasicAction::ToggleLayer(layer) => [0x04, layer],
+            BasicAction::OneShotLayer(layer) => [0x05, layer],
+            BasicAction::DefaultLayer(layer) => [0x06, layer],
+            BasicAction::Modifiers(modifiers) => [0x07, modifiers],
+            BasicAction::Macro(index) => [0x08, index],
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<BasicAction, Error> {
+        let action = match bytes[0] {
+            0x01 => BasicAction::Keycode(bytes[1]),
+            0x02 => BasicAction::Media(bytes[1]),
+            0x03 => BasicAction::MomentaryLayer(bytes[1]),
+            0x04 => BasicAction::ToggleLayer(bytes[1]),
+            0x05 => BasicAction::OneShotLayer(bytes[1]),
+            0x06 => BasicAction::DefaultLayer(bytes[1]),
+            0x07 => BasicAction::Modifiers(bytes[1]),
+            0x08 => BasicAction::Macro(bytes[1]),
+            tag => return Err(Error::UnknownAction(tag)),
+        };
+        Ok(action)
+    }
+}
+
+/// Sends `tap` if the key is released within `term_ms`, `hold` otherwise.
+///
+/// With `hold_on_other_key_press` pressing another key while undecided
+/// selects `hold` right away. With `permissive_hold` the same happens once
+/// another key is pressed and released while the key is still held.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TapHold {
+    pub tap: BasicAction,
+    pub hold: BasicAction,
+    #[cfg_attr(feature = "serde", serde(default = "default_term_ms"))]
+    pub term_ms: u16,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub hold_on_other_key_press: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub permissive_hold: bool,
+}
+
+impl TapHold {
+    pub const DEFAULT_TERM_MS: u16 = 200;
+
+    const HOLD_ON_OTHER_KEY_PRESS: u8 = 0x01;
+    const PERMISSIVE_HOLD: u8 = 0x02;
+
+    /// Tap-hold with the default term and neither option set.
+    pub const fn new(tap: BasicAction, hold: BasicAction) -> Self {
+        TapHold {
+            tap,
+            hold,
+            term_ms: Self::DEFAULT_TERM_MS,
+            hold_on_other_key_press: false,
+            permissive_hold: false,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn default_term_ms() -> u16 {
+    TapHold::DEFAULT_TERM_MS
+}
+
+impl Action {
+    /// Size of a single encoded action, in reports and in flash.
+    pub const SIZE: usize = 8;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        match *self {
+            Action::Transparent => bytes[0] = 0x00,
+            Action::Keycode(code) => bytes[..2].copy_from_slice(&[0x01, code]),
+            Action::Media(code) => bytes[..2].copy_from_slice(&[0x02, code]),
+            Action::MomentaryLayer(layer) => bytes[..2].copy_from_slice(&[0x03, layer]),
+            Action::ToggleLayer(layer) => bytes[..2].copy_from_slice(&[0x04, layer]),
+            Action::OneShotLayer(layer) => bytes[..2].copy_from_slice(&[0x05, layer]),
+            Action::DefaultLayer(layer) => bytes[..2].copy_from_slice(&[0x06, layer]),
+            Action::Chord {
+                modifiers,
+                keycodes,
+            } => {
+                bytes[..2].copy_from_slice(&[0x07, modifiers]);
+                bytes[2..8].copy_from_slice(&keycodes);
+            }
+            Action::Macro(index) => bytes[..2].copy_from_slice(&[0x08, index]),
+            Action::TapHold(tap_hold) => {
+                let mut flags = 0;
+                if tap_hold.hold_on_other_key_press {
+                    flags |= TapHold::HOLD_ON_OTHER_KEY_PRESS;
+                }
+                if tap_hold.permissive_hold {
+                    flags |= TapHold::PERMISSIVE_HOLD;
+                }
+                bytes[0] = 0x09;
+                bytes[1..3].copy_from_slice(&tap_hold.tap.encode());
+                bytes[3..5].copy_from_slice(&tap_hold.hold.encode());
+                bytes[5..7].copy_from_slice(&tap_hold.term_ms.to_le_bytes());
+                bytes[7] = flags;
+            }
+            Action::MouseButton(buttons) => bytes[..2].copy_from_slice(&[0x0A, buttons]),
+            Action::MouseMove { x, y } => bytes[..3].copy_from_slice(&[0x0B, x as u8, y as u8]),
+            Action::Wheel(notches) => bytes[..2].copy_from_slice(&[0x0C, notches as u8]),
+            Action::Pan(notches) => bytes[..2].copy_from_slice(&[0x0D, notches as u8]),
+            Action::Consumer(usage) => {
+                bytes[0] = 0x0E;
+                bytes[1..3].copy_from_slice(&usage.to_le_bytes());
+            }
+            Action::System(usage) => bytes[..2].copy_from_slice(&[0x0F, usage]),
+            Action::IfLock { lock, on, off } => {
+                bytes[..2].copy_from_slice(&[0x10, lock]);
+                bytes[2..4].copy_from_slice(&on.encode());
+                bytes[4..6].copy_from_slice(&off.encode());
+            }
+            Action::TapDance(index) => bytes[..2].copy_from_slice(&[0x11, index]),
+            Action::EncoderMode => bytes[0] = 0x12,
+            Action::NextEncoderMode => bytes[0] = 0x13,
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Action, Error> {
+        if bytes.len() < Self::SIZE {
+            return Err(Error::Truncated);
+        }
+
+        let action = match bytes[0] {
+            0x00 => Action::Transparent,
+            0x01 => Action::Keycode(bytes[1]),
+            0x02 => Action::Media(bytes[1]),
+            0x03 => Action::MomentaryLayer(bytes[1]),
+            0x04 => Action::ToggleLayer(bytes[1]),
+            0x05 => Action::OneShotLayer(bytes[1]),
+            0x06 => Action::DefaultLayer(bytes[1]),
+            0x07 => {
+                let mut keycodes = [0u8; 6];
+                keycodes.copy_from_slice(&bytes[2..8]);
+                Action::Chord {
+                    modifiers: bytes[1],
+                    keycodes,
+                }
+            }
+            0x08 => Action::Macro(bytes[1]),
+            0x09 => Action::TapHold(TapHold {
+                tap: BasicAction::decode(&bytes[1..3])?,
+                hold: BasicAction::decode(&bytes[3..5])?,
+                term_ms: u16::from_le_bytes([bytes[5], bytes[6]]),
+                hold_on_other_key_press: bytes[7] & TapHold::HOLD_ON_OTHER_KEY_PRESS != 0,
+                permissive_hold: bytes[7] & TapHold::PERMISSIVE_HOLD != 0,
+            }),
+            0x0A => Action::MouseButton(bytes[1]),
+            0x0B => Action::MouseMove {
+                x: bytes[1] as i8,
+                y: bytes[2] as i8,
+            },
+            0x0C => Action::Wheel(bytes[1] as i8),
+            0x0D => Action::Pan(bytes[1] as i8),
+            0x0E => Action::Consumer(u16::from_le_bytes([bytes[1], bytes[2]])),
+            0x0F => Action::System(bytes[1]),
+            0x10 => Action::IfLock {
+                lock: bytes[1],
+                on: BasicAction::decode(&bytes[2..4])?,
+                off: BasicAction::decode(&bytes[4..6])?,
+            },
+            0x11 => Action::TapDance(bytes[1]),
+            0x12 => Action::EncoderMode,
+            0x13 => Action::NextEncoderMode,
+            tag => return Err(Error::UnknownAction(tag)),
+        };
+        Ok(action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACTIONS: [Action; 21] = [
+        Action::Transparent,
+        Action::Keycode(0x04),
+        Action::Media(0xE9),
+        Action::Chord {
+            modifiers: 0x03,
+            keycodes: [0x06, 0x19, 0, 0, 0, 0xFF],
+        },
+        Action::Macro(7),
+        Action::MomentaryLayer(1),
+        Action::ToggleLayer(2),
+        Action::OneShotLayer(3),
+        Action::DefaultLayer(0),
+        Action::TapHold(TapHold {
+            tap: BasicAction::Keycode(0x29),
+            hold: BasicAction::Modifiers(0x01),
+            term_ms: 300,
+            hold_on_other_key_press: false,
+            permissive_hold: true,
+        }),
+        Action::TapHold(TapHold {
+            tap: BasicAction::Macro(1),
+            hold: BasicAction::MomentaryLayer(2),
+            term_ms: 0xFFFF,
+            hold_on_other_key_press: true,
+            permissive_hold: false,
+        }),
+        Action::MouseButton(0x05),
+        Action::MouseMove { x: -10, y: 127 },
+        Action::Wheel(-1),
+        Action::Pan(3),
+        Action::Consumer(0x0223),
+        Action::System(0x82),
+        Action::IfLock {
+            lock: 0x02,
+            on: BasicAction::Media(0xE2),
+            off: BasicAction::ToggleLayer(1),
+        },
+        Action::TapDance(0),
+        Action::EncoderMode,
+        Action::NextEncoderMode,
+    ];
+
+    #[test]
+    fn round_trip() {
+        for action in ACTIONS {
+            assert_eq!(Action::decode(&action.encode()), Ok(action));
+        }
+    }
+
+    #[test]
+    fn basic_actions_round_trip() {
+        let actions = [
+            BasicAction::Keycode(0x04),
+            BasicAction::Media(0xE9),
+            BasicAction::Modifiers(0x22),
+            BasicAction::Macro(3),
+            BasicAction::MomentaryLayer(1),
+            BasicAction::ToggleLayer(2),
+            BasicAction::OneShotLayer(3),
+            BasicAction::DefaultLayer(0),
+        ];
+        for action in actions {
+            assert_eq!(BasicAction::decode(&action.encode()), Ok(action));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = Action::Keycode(0x04).encode();
+        assert_eq!(Action::decode(&bytes[..Action::SIZE - 1]), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn unknown_tag() {
+        let mut bytes = [0u8; Action::SIZE];
+        bytes[0] = 0x14;
+        assert_eq!(Action::decode(&bytes), Err(Error::UnknownAction(0x14)));
+    }
+
+    #[test]
+    fn unknown_tap_hold_half() {
+        let mut bytes = Action::TapHold(TapHold::new(BasicAction::Keycode(1), BasicAction::Keycode(2))).encode();
+        bytes[3] = 0x00;
+        assert_eq!(Action::decode(&bytes), Err(Error::UnknownAction(0x00)));
+    }
+}
@@ -0,0 +1,68 @@
+//! Configuration protocol spoken over the OSKAR vendor HID interface.
+//!
+//! Every request is a single output report, answered by a single input report:
+//!
+//! ```text
+//! request:  [version, command, payload...]
+//! response: [version, command, status, payload...]
+//! ```
+//!
+//! This crate is shared by the firmware and `oskarctl` so both sides always
+//! agree on the encoding. It also holds the command parser of the shell on
+//! the firmware's debug console, see `shell`.
+
+#![no_std]
+
+mod action;
+pub mod macros;
+mod message;
+pub mod shell;
+
+pub use action::{Action, BasicAction, TapHold};
+pub use message::{Command, DeviceMode, Request, Response, Status};
+
+pub const VID: u16 = 0x1ced;
+pub const PID: u16 = 0xc0fe;
+
+/// Usage page and usage of the vendor defined configuration interface.
+pub const USAGE_PAGE: u16 = 0xFF60;
+pub const USAGE: u16 = 0x61;
+
+pub const REPORT_SIZE: usize = 64;
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Bytes of the macro buffer transferred per request.
+pub const MACRO_CHUNK_SIZE: usize = 56;
+
+/// Keys of a layer transferred per `GetLayout` request.
+pub const LAYOUT_CHUNK_KEYS: usize = 7;
+
+/// Inputs in wire order. The last two are the encoder turned while its
+/// button is held.
+pub const NUM_KEYS: usize = 8;
+pub const KEY_NAMES: [&str; NUM_KEYS] = [
+    "encoder_left",
+    "encoder_right",
+    "encoder_button",
+    "key1",
+    "key2",
+    "key3",
+    "encoder_pressed_left",
+    "encoder_pressed_right",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The report is shorter than the message it should contain.
+    Truncated,
+    /// The peer speaks a different protocol version.
+    VersionMismatch(u8),
+    UnknownCommand(u8),
+    UnknownAction(u8),
+    UnknownStatus(u8),
+    UnknownDeviceMode(u8),
+    /// The response belongs to a different request.
+    UnexpectedResponse(u8),
+    /// The device rejected the request.
+    Status(Status),
+}
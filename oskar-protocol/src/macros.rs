@@ -0,0 +1,282 @@
+//! Byte encoding of key macros.
+//!
+//! All macros live in one buffer of `MACRO_BUFFER_SIZE` bytes, each one
+//! terminated by `END`. `Action::Macro(n)` plays the n-th macro in the buffer.
+//! Bytes that are not opcodes are UTF-8 text that gets typed as is.
+
+use crate::MACRO_CHUNK_SIZE;
+
+/// Size of the macro buffer kept in RAM and in flash.
+pub const MACRO_BUFFER_SIZE: usize = 512;
+
+const END: u8 = 0x00;
+const TAP: u8 = 0x01;
+const PRESS: u8 = 0x02;
+const RELEASE: u8 = 0x03;
+const DELAY: u8 = 0x04;
+const KEY_DELAY: u8 = 0x05;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacroStep<'a> {
+    /// Presses and releases a keycode.
+    Tap(u8),
+    Press(u8),
+    Release(u8),
+    /// Waits for the given number of milliseconds.
+    Delay(u16),
+    /// Sets the delay between key events for the rest of the macro, in milliseconds.
+    KeyDelay(u16),
+    /// Types a string, `\n` and `\t` are sent as Enter and Tab.
+    Text(&'a str),
+}
+
+impl MacroStep<'_> {
+    /// Appends the step to `buf` at `offset`, returns the new offset or `None`
+    /// if the buffer is too small.
+    pub fn encode_into(&self, buf: &mut [u8], offset: usize) -> Option<usize> {
+        let mut bytes = [0u8; 3];
+        let encoded: &[u8] = match *self {
+            MacroStep::Tap(code) => {
+                bytes[..2].copy_from_slice(&[TAP, code]);
+                &bytes[..2]
+            }
+            MacroStep::Press(code) => {
+                bytes[..2].copy_from_slice(&[PRESS, code]);
+                &bytes[..2]
+            }
+            MacroStep::Release(code) => {
+                bytes[..2].copy_from_slice(&[RELEASE, code]);
+                &bytes[..2]
+            }
+            MacroStep::Delay(ms) => {
+                bytes[0] = DELAY;
+                bytes[1..].copy_from_slice(&ms.to_le_bytes());
+                &bytes
+            }
+            MacroStep::KeyDelay(ms) => {
+                bytes[0] = KEY_DELAY;
+                bytes[1..].copy_from_slice(&ms.to_le_bytes());
+                &bytes
+            }
+            MacroStep::Text(text) => text.as_bytes(),
+        };
+
+        let end = offset + encoded.len();
+        let dest = buf.get_mut(offset..end)?;
+        dest.copy_from_slice(encoded);
+
+        // Other control characters would be read back as opcodes.
+        if let MacroStep::Text(_) = self {
+            for byte in dest.iter_mut() {
+                if *byte < b' ' && *byte != b'\n' && *byte != b'\t' {
+                    *byte = b' ';
+                }
+            }
+        }
+        Some(end)
+    }
+}
+
+/// Encodes a whole macro including its terminator, returns the new offset.
+pub fn encode_macro(steps: &[MacroStep<'_>], buf: &mut [u8], mut offset: usize) -> Option<usize> {
+    for step in steps {
+        offset = step.encode_into(buf, offset)?;
+    }
+    *buf.get_mut(offset)? = END;
+    Some(offset + 1)
+}
+
+/// Returns the encoded steps of the `index`-th macro, without its terminator.
+pub fn find_macro(buf: &[u8], index: u8) -> Option<&[u8]> {
+    let mut start = 0;
+    for _ in 0..index {
+        start += macro_len(buf.get(start..)?)? + 1;
+    }
+    let len = macro_len(buf.get(start..)?)?;
+    Some(&buf[start..start + len])
+}
+
+/// Copies the chunk at `offset` as sent by `GetMacros`, zero padded past the
+/// end of `buf`. `None` if `offset` is past the end.
+pub fn read_chunk(buf: &[u8], offset: usize) -> Option<[u8; MACRO_CHUNK_SIZE]> {
+    let bytes = buf.get(offset..)?;
+    let len = MACRO_CHUNK_SIZE.min(bytes.len());
+    let mut data = [0u8; MACRO_CHUNK_SIZE];
+    data[..len].copy_from_slice(&bytes[..len]);
+    Some(data)
+}
+
+/// Writes `data` to `offset` as requested by `SetMacros`. `None` if it
+/// doesn't fit, `buf` is left untouched then.
+pub fn write_chunk(buf: &mut [u8], offset: usize, data: &[u8]) -> Option<()> {
+    buf.get_mut(offset..offset.checked_add(data.len())?)?.copy_from_slice(data);
+    Some(())
+}
+
+/// Length of the macro at the start of `buf`, up to its terminator.
+fn macro_len(buf: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        match buf[offset] {
+            END => return Some(offset),
+            TAP | PRESS | RELEASE => offset += 2,
+            DELAY | KEY_DELAY => offset += 3,
+            _ => offset += 1,
+        }
+    }
+    None
+}
+
+/// Iterates over the steps of an encoded macro as returned by `find_macro`.
+pub struct MacroSteps<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> MacroSteps<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        MacroSteps { buf }
+    }
+}
+
+impl<'a> Iterator for MacroSteps<'a> {
+    type Item = MacroStep<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (&opcode, rest) = self.buf.split_first()?;
+            let (step, len) = match opcode {
+                END => return None,
+                TAP | PRESS | RELEASE => {
+                    let code = *rest.first()?;
+                    let step = match opcode {
+                        TAP => MacroStep::Tap(code),
+                        PRESS => MacroStep::Press(code),
+                        _ => MacroStep::Release(code),
+                    };
+                    (Some(step), 2)
+                }
+                DELAY | KEY_DELAY => {
+                    let ms = u16::from_le_bytes([*rest.first()?, *rest.get(1)?]);
+                    let step = match opcode {
+                        DELAY => MacroStep::Delay(ms),
+                        _ => MacroStep::KeyDelay(ms),
+                    };
+                    (Some(step), 3)
+                }
+                _ => {
+                    let len = self
+                        .buf
+                        .iter()
+                        .position(|byte| *byte <= KEY_DELAY)
+                        .unwrap_or(self.buf.len());
+                    // Skip text that isn't valid UTF-8 instead of typing garbage.
+                    (core::str::from_utf8(&self.buf[..len]).ok().map(MacroStep::Text), len)
+                }
+            };
+
+            self.buf = &self.buf[len.min(self.buf.len())..];
+            if step.is_some() {
+                return step;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST: [MacroStep<'static>; 7] = [
+        MacroStep::KeyDelay(20),
+        MacroStep::Text("ls -la\n"),
+        MacroStep::Press(0xE0),
+        MacroStep::Tap(0x06),
+        MacroStep::Release(0xE0),
+        MacroStep::Delay(500),
+        MacroStep::Text("\tdone"),
+    ];
+    const SECOND: [MacroStep<'static>; 1] = [MacroStep::Tap(0x28)];
+
+    fn buffer() -> [u8; MACRO_BUFFER_SIZE] {
+        let mut buf = [0u8; MACRO_BUFFER_SIZE];
+        let offset = encode_macro(&FIRST, &mut buf, 0).unwrap();
+        encode_macro(&SECOND, &mut buf, offset).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = buffer();
+        assert!(MacroSteps::new(find_macro(&buf, 0).unwrap()).eq(FIRST));
+        assert!(MacroSteps::new(find_macro(&buf, 1).unwrap()).eq(SECOND));
+        // The rest of the buffer is empty macros.
+        assert_eq!(find_macro(&buf, 2), Some(&[][..]));
+    }
+
+    #[test]
+    fn control_characters_in_text() {
+        let mut buf = [0u8; 16];
+        encode_macro(&[MacroStep::Text("a\x01b\x1bc")], &mut buf, 0).unwrap();
+        assert!(MacroSteps::new(find_macro(&buf, 0).unwrap()).eq([MacroStep::Text("a b c")]));
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0u8; 8];
+        assert_eq!(encode_macro(&[MacroStep::Text("1234567")], &mut buf, 0), Some(8));
+        assert_eq!(encode_macro(&[MacroStep::Text("12345678")], &mut buf, 0), None);
+        assert_eq!(encode_macro(&[MacroStep::Delay(1)], &mut buf, 6), None);
+    }
+
+    #[test]
+    fn unterminated_macro() {
+        let buf = [TAP, 0x04, b'x'];
+        assert_eq!(find_macro(&buf, 0), None);
+        // An opcode cut off at the end of the buffer.
+        assert_eq!(find_macro(&[DELAY, 0x10], 0), None);
+    }
+
+    #[test]
+    fn truncated_steps() {
+        assert!(MacroSteps::new(&[TAP]).eq([]));
+        assert!(MacroSteps::new(&[b'h', b'i', DELAY, 0x01]).eq([MacroStep::Text("hi")]));
+    }
+
+    #[test]
+    fn invalid_utf8_is_skipped() {
+        let buf = [0xC3, 0x28, TAP, 0x04];
+        assert!(MacroSteps::new(&buf).eq([MacroStep::Tap(0x04)]));
+    }
+
+    #[test]
+    fn read_chunks() {
+        let buf = buffer();
+        assert_eq!(read_chunk(&buf, 0).unwrap()[..], buf[..MACRO_CHUNK_SIZE]);
+
+        let last = MACRO_BUFFER_SIZE - 8;
+        let mut buf = buf;
+        buf[last..].fill(0xAA);
+        let chunk = read_chunk(&buf, last).unwrap();
+        assert_eq!(chunk[..8], [0xAA; 8]);
+        assert_eq!(chunk[8..], [0; MACRO_CHUNK_SIZE - 8]);
+
+        assert_eq!(read_chunk(&buf, MACRO_BUFFER_SIZE), Some([0; MACRO_CHUNK_SIZE]));
+        assert_eq!(read_chunk(&buf, MACRO_BUFFER_SIZE + 1), None);
+        assert_eq!(read_chunk(&buf, u16::MAX as usize), None);
+    }
+
+    #[test]
+    fn write_chunks() {
+        let mut buf = [0u8; MACRO_BUFFER_SIZE];
+        assert_eq!(write_chunk(&mut buf, 10, &[1, 2, 3]), Some(()));
+        assert_eq!(buf[9..14], [0, 1, 2, 3, 0]);
+
+        assert_eq!(write_chunk(&mut buf, MACRO_BUFFER_SIZE - 3, &[4, 5, 6]), Some(()));
+        assert_eq!(buf[MACRO_BUFFER_SIZE - 3..], [4, 5, 6]);
+
+        assert_eq!(write_chunk(&mut buf, MACRO_BUFFER_SIZE - 2, &[7, 8, 9]), None);
+        assert_eq!(write_chunk(&mut buf, MACRO_BUFFER_SIZE + 1, &[]), None);
+        assert_eq!(write_chunk(&mut buf, usize::MAX, &[1]), None);
+        assert_eq!(buf[MACRO_BUFFER_SIZE - 3..], [4, 5, 6]);
+    }
+}
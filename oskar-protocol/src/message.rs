@@ -0,0 +1,559 @@
+use crate::{Action, Error, LAYOUT_CHUNK_KEYS, MACRO_CHUNK_SIZE, PROTOCOL_VERSION, REPORT_SIZE};
+
+const HEADER_SIZE: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Command {
+    GetVersion = 0x01,
+    GetKey = 0x02,
+    SetKey = 0x03,
+    GetLayout = 0x04,
+    Save = 0x05,
+    ResetDefaults = 0x06,
+    GetInfo = 0x07,
+    RebootBootloader = 0x08,
+    GetMacros = 0x09,
+    SetMacros = 0x0A,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let command = match value {
+            0x01 => Command::GetVersion,
+            0x02 => Command::GetKey,
+            0x03 => Command::SetKey,
+            0x04 => Command::GetLayout,
+            0x05 => Command::Save,
+            0x06 => Command::ResetDefaults,
+            0x07 => Command::GetInfo,
+            0x08 => Command::RebootBootloader,
+            0x09 => Command::GetMacros,
+            0x0A => Command::SetMacros,
+            _ => return Err(Error::UnknownCommand(value)),
+        };
+        Ok(command)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0x00,
+    UnknownCommand = 0x01,
+    InvalidArgument = 0x02,
+    FlashError = 0x03,
+    VersionMismatch = 0x04,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let status = match value {
+            0x00 => Status::Ok,
+            0x01 => Status::UnknownCommand,
+            0x02 => Status::InvalidArgument,
+            0x03 => Status::FlashError,
+            0x04 => Status::VersionMismatch,
+            _ => return Err(Error::UnknownStatus(value)),
+        };
+        Ok(status)
+    }
+}
+
+impl From<Error> for Status {
+    fn from(val: Error) -> Self {
+        match val {
+            Error::VersionMismatch(_) => Status::VersionMismatch,
+            Error::UnknownCommand(_) => Status::UnknownCommand,
+            Error::Status(status) => status,
+            _ => Status::InvalidArgument,
+        }
+    }
+}
+
+/// Position of the selector switch when the device booted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeviceMode {
+    Keyboard = 0x00,
+    Picoprog = 0x01,
+    Universal = 0x02,
+}
+
+impl TryFrom<u8> for DeviceMode {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let mode = match value {
+            0x00 => DeviceMode::Keyboard,
+            0x01 => DeviceMode::Picoprog,
+            0x02 => DeviceMode::Universal,
+            _ => return Err(Error::UnknownDeviceMode(value)),
+        };
+        Ok(mode)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    GetVersion,
+    GetKey { layer: u8, key: u8 },
+    SetKey { layer: u8, key: u8, action: Action },
+    /// Reads `LAYOUT_CHUNK_KEYS` keys of a layer, starting with key `first`.
+    GetLayout { layer: u8, first: u8 },
+    /// Writes the current keymap to flash.
+    Save,
+    /// Replaces the current keymap with the compiled default, flash is not touched.
+    ResetDefaults,
+    GetInfo,
+    /// Reboots into the RP2040 USB bootloader after the response was sent.
+    RebootBootloader,
+    /// Reads `MACRO_CHUNK_SIZE` bytes of the macro buffer.
+    GetMacros { offset: u16 },
+    /// Writes the first `len` bytes of `data` into the macro buffer.
+    SetMacros {
+        offset: u16,
+        len: u8,
+        data: [u8; MACRO_CHUNK_SIZE],
+    },
+}
+
+impl Request {
+    pub fn command(&self) -> Command {
+        match self {
+            Request::GetVersion => Command::GetVersion,
+            Request::GetKey { .. } => Command::GetKey,
+            Request::SetKey { .. } => Command::SetKey,
+            Request::GetLayout { .. } => Command::GetLayout,
+            Request::Save => Command::Save,
+            Request::ResetDefaults => Command::ResetDefaults,
+            Request::GetInfo => Command::GetInfo,
+            Request::RebootBootloader => Command::RebootBootloader,
+            Request::GetMacros { .. } => Command::GetMacros,
+            Request::SetMacros { .. } => Command::SetMacros,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; REPORT_SIZE] {
+        let mut report = [0u8; REPORT_SIZE];
+        report[0] = PROTOCOL_VERSION;
+        report[1] = self.command() as u8;
+
+        let payload = &mut report[2..];
+        match *self {
+            Request::GetKey { layer, key } => {
+                payload[0] = layer;
+                payload[1] = key;
+            }
+            Request::SetKey { layer, key, action } => {
+                payload[0] = layer;
+                payload[1] = key;
+                payload[2..2 + Action::SIZE].copy_from_slice(&action.encode());
+            }
+            Request::GetLayout { layer, first } => {
+                payload[0] = layer;
+                payload[1] = first;
+            }
+            Request::GetMacros { offset } => payload[..2].copy_from_slice(&offset.to_le_bytes()),
+            Request::SetMacros { offset, len, data } => {
+                payload[..2].copy_from_slice(&offset.to_le_bytes());
+                payload[2] = len;
+                payload[3..3 + MACRO_CHUNK_SIZE].copy_from_slice(&data);
+            }
+            _ => {}
+        }
+        report
+    }
+
+    pub fn decode(report: &[u8]) -> Result<Request, Error> {
+        if report.len() < REPORT_SIZE {
+            return Err(Error::Truncated);
+        }
+        if report[0] != PROTOCOL_VERSION {
+            return Err(Error::VersionMismatch(report[0]));
+        }
+
+        let payload = &report[2..];
+        let request = match Command::try_from(report[1])? {
+            Command::GetVersion => Request::GetVersion,
+            Command::GetKey => Request::GetKey {
+                layer: payload[0],
+                key: payload[1],
+            },
+            Command::SetKey => Request::SetKey {
+                layer: payload[0],
+                key: payload[1],
+                action: Action::decode(&payload[2..])?,
+            },
+            Command::GetLayout => Request::GetLayout {
+                layer: payload[0],
+                first: payload[1],
+            },
+            Command::Save => Request::Save,
+            Command::ResetDefaults => Request::ResetDefaults,
+            Command::GetInfo => Request::GetInfo,
+            Command::RebootBootloader => Request::RebootBootloader,
+            Command::GetMacros => Request::GetMacros {
+                offset: u16::from_le_bytes([payload[0], payload[1]]),
+            },
+            Command::SetMacros => {
+                let len = payload[2];
+                if len as usize > MACRO_CHUNK_SIZE {
+                    return Err(Error::Status(Status::InvalidArgument));
+                }
+                let mut data = [0u8; MACRO_CHUNK_SIZE];
+                data.copy_from_slice(&payload[3..3 + MACRO_CHUNK_SIZE]);
+                Request::SetMacros {
+                    offset: u16::from_le_bytes([payload[0], payload[1]]),
+                    len,
+                    data,
+                }
+            }
+        };
+        Ok(request)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Response {
+    Version {
+        protocol: u8,
+        major: u8,
+        minor: u8,
+        patch: u8,
+    },
+    Key {
+        layer: u8,
+        key: u8,
+        action: Action,
+    },
+    /// Keys `first..first + LAYOUT_CHUNK_KEYS` of a layer, entries past the
+    /// last key are `Action::Transparent`.
+    Layout {
+        layer: u8,
+        first: u8,
+        actions: [Action; LAYOUT_CHUNK_KEYS],
+    },
+    Info {
+        mode: DeviceMode,
+        num_layers: u8,
+        num_keys: u8,
+    },
+    Macros {
+        offset: u16,
+        data: [u8; MACRO_CHUNK_SIZE],
+    },
+    /// Acknowledges requests that don't return any data.
+    Done,
+}
+
+impl Response {
+    /// Encodes the answer to the request with the raw command id `command`.
+    pub fn encode(command: u8, result: &Result<Response, Status>) -> [u8; REPORT_SIZE] {
+        let mut report = [0u8; REPORT_SIZE];
+        report[0] = PROTOCOL_VERSION;
+        report[1] = command;
+
+        let response = match result {
+            Ok(response) => response,
+            Err(status) => {
+                report[2] = *status as u8;
+                return report;
+            }
+        };
+
+        report[2] = Status::Ok as u8;
+        let payload = &mut report[HEADER_SIZE..];
+        match *response {
+            Response::Version {
+                protocol,
+                major,
+                minor,
+                patch,
+            } => payload[..4].copy_from_slice(&[protocol, major, minor, patch]),
+            Response::Key { layer, key, action } => {
+                payload[0] = layer;
+                payload[1] = key;
+                payload[2..2 + Action::SIZE].copy_from_slice(&action.encode());
+            }
+            Response::Layout { layer, first, actions } => {
+                payload[0] = layer;
+                payload[1] = first;
+                for (index, action) in actions.iter().enumerate() {
+                    let offset = 2 + index * Action::SIZE;
+                    payload[offset..offset + Action::SIZE].copy_from_slice(&action.encode());
+                }
+            }
+            Response::Info {
+                mode,
+                num_layers,
+                num_keys,
+            } => payload[..3].copy_from_slice(&[mode as u8, num_layers, num_keys]),
+            Response::Macros { offset, data } => {
+                payload[..2].copy_from_slice(&offset.to_le_bytes());
+                payload[2..2 + MACRO_CHUNK_SIZE].copy_from_slice(&data);
+            }
+            Response::Done => {}
+        }
+        report
+    }
+
+    /// Decodes the answer to a request of type `command`.
+    pub fn decode(command: Command, report: &[u8]) -> Result<Response, Error> {
+        if report.len() < REPORT_SIZE {
+            return Err(Error::Truncated);
+        }
+        if report[0] != PROTOCOL_VERSION {
+            return Err(Error::VersionMismatch(report[0]));
+        }
+        if report[1] != command as u8 {
+            return Err(Error::UnexpectedResponse(report[1]));
+        }
+        match Status::try_from(report[2])? {
+            Status::Ok => {}
+            status => return Err(Error::Status(status)),
+        }
+
+        let payload = &report[HEADER_SIZE..];
+        let response = match command {
+            Command::GetVersion => Response::Version {
+                protocol: payload[0],
+                major: payload[1],
+                minor: payload[2],
+                patch: payload[3],
+            },
+            Command::GetKey => Response::Key {
+                layer: payload[0],
+                key: payload[1],
+                action: Action::decode(&payload[2..])?,
+            },
+            Command::GetLayout => {
+                let mut actions = [Action::Transparent; LAYOUT_CHUNK_KEYS];
+                for (index, action) in actions.iter_mut().enumerate() {
+                    *action = Action::decode(&payload[2 + index * Action::SIZE..])?;
+                }
+                Response::Layout {
+                    layer: payload[0],
+                    first: payload[1],
+                    actions,
+                }
+            }
+            Command::GetInfo => Response::Info {
+                mode: DeviceMode::try_from(payload[0])?,
+                num_layers: payload[1],
+                num_keys: payload[2],
+            },
+            Command::GetMacros => {
+                let mut data = [0u8; MACRO_CHUNK_SIZE];
+                data.copy_from_slice(&payload[2..2 + MACRO_CHUNK_SIZE]);
+                Response::Macros {
+                    offset: u16::from_le_bytes([payload[0], payload[1]]),
+                    data,
+                }
+            }
+            Command::SetKey
+            | Command::Save
+            | Command::ResetDefaults
+            | Command::RebootBootloader
+            | Command::SetMacros => Response::Done,
+        };
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BasicAction, TapHold};
+
+    fn macro_data() -> [u8; MACRO_CHUNK_SIZE] {
+        let mut data = [0u8; MACRO_CHUNK_SIZE];
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = index as u8 + 1;
+        }
+        data
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::GetVersion,
+            Request::GetKey { layer: 1, key: 5 },
+            Request::SetKey {
+                layer: 3,
+                key: 7,
+                action: Action::TapHold(TapHold::new(BasicAction::Keycode(0x04), BasicAction::Modifiers(0x02))),
+            },
+            Request::GetLayout { layer: 2, first: 7 },
+            Request::Save,
+            Request::ResetDefaults,
+            Request::GetInfo,
+            Request::RebootBootloader,
+            Request::GetMacros { offset: 0x1234 },
+            Request::SetMacros {
+                offset: 448,
+                len: MACRO_CHUNK_SIZE as u8,
+                data: macro_data(),
+            },
+        ];
+        for request in requests {
+            let report = request.encode();
+            assert_eq!(report[0], PROTOCOL_VERSION);
+            assert_eq!(report[1], request.command() as u8);
+            assert_eq!(Request::decode(&report), Ok(request));
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let mut actions = [Action::Transparent; LAYOUT_CHUNK_KEYS];
+        actions[0] = Action::Keycode(0x1E);
+        actions[LAYOUT_CHUNK_KEYS - 1] = Action::Consumer(0x0192);
+        let responses = [
+            (
+                Command::GetVersion,
+                Response::Version {
+                    protocol: PROTOCOL_VERSION,
+                    major: 1,
+                    minor: 2,
+                    patch: 3,
+                },
+            ),
+            (
+                Command::GetKey,
+                Response::Key {
+                    layer: 0,
+                    key: 2,
+                    action: Action::Wheel(-2),
+                },
+            ),
+            (
+                Command::GetLayout,
+                Response::Layout {
+                    layer: 1,
+                    first: 0,
+                    actions,
+                },
+            ),
+            (
+                Command::GetInfo,
+                Response::Info {
+                    mode: DeviceMode::Universal,
+                    num_layers: 4,
+                    num_keys: 8,
+                },
+            ),
+            (
+                Command::GetMacros,
+                Response::Macros {
+                    offset: 56,
+                    data: macro_data(),
+                },
+            ),
+            (Command::SetKey, Response::Done),
+            (Command::Save, Response::Done),
+            (Command::ResetDefaults, Response::Done),
+            (Command::RebootBootloader, Response::Done),
+            (Command::SetMacros, Response::Done),
+        ];
+        for (command, response) in responses {
+            let report = Response::encode(command as u8, &Ok(response));
+            assert_eq!(Response::decode(command, &report), Ok(response));
+        }
+    }
+
+    #[test]
+    fn truncated_reports() {
+        let report = Request::GetVersion.encode();
+        assert_eq!(Request::decode(&report[..REPORT_SIZE - 1]), Err(Error::Truncated));
+        let report = Response::encode(Command::GetVersion as u8, &Ok(Response::Done));
+        assert_eq!(Response::decode(Command::GetVersion, &report[..10]), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut report = Request::GetInfo.encode();
+        report[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(Request::decode(&report), Err(Error::VersionMismatch(PROTOCOL_VERSION + 1)));
+        assert_eq!(Status::from(Error::VersionMismatch(0)), Status::VersionMismatch);
+
+        let mut report = Response::encode(Command::GetInfo as u8, &Ok(Response::Done));
+        report[0] = 0;
+        assert_eq!(Response::decode(Command::GetInfo, &report), Err(Error::VersionMismatch(0)));
+    }
+
+    #[test]
+    fn unknown_command() {
+        let mut report = Request::GetVersion.encode();
+        report[1] = 0x7F;
+        assert_eq!(Request::decode(&report), Err(Error::UnknownCommand(0x7F)));
+        assert_eq!(Status::from(Error::UnknownCommand(0x7F)), Status::UnknownCommand);
+    }
+
+    #[test]
+    fn unknown_action_in_request() {
+        let mut report = Request::SetKey {
+            layer: 0,
+            key: 0,
+            action: Action::Transparent,
+        }
+        .encode();
+        report[4] = 0xEE;
+        assert_eq!(Request::decode(&report), Err(Error::UnknownAction(0xEE)));
+        assert_eq!(Status::from(Error::UnknownAction(0xEE)), Status::InvalidArgument);
+    }
+
+    #[test]
+    fn set_macros_longer_than_a_chunk() {
+        let mut report = Request::SetMacros {
+            offset: 0,
+            len: 0,
+            data: [0; MACRO_CHUNK_SIZE],
+        }
+        .encode();
+        report[4] = MACRO_CHUNK_SIZE as u8 + 1;
+        assert_eq!(Request::decode(&report), Err(Error::Status(Status::InvalidArgument)));
+    }
+
+    #[test]
+    fn error_status() {
+        let report = Response::encode(Command::SetKey as u8, &Err(Status::InvalidArgument));
+        assert_eq!(Response::decode(Command::SetKey, &report), Err(Error::Status(Status::InvalidArgument)));
+        let report = Response::encode(Command::Save as u8, &Err(Status::FlashError));
+        assert_eq!(Response::decode(Command::Save, &report), Err(Error::Status(Status::FlashError)));
+    }
+
+    #[test]
+    fn unknown_status() {
+        let mut report = Response::encode(Command::Save as u8, &Ok(Response::Done));
+        report[2] = 0x42;
+        assert_eq!(Response::decode(Command::Save, &report), Err(Error::UnknownStatus(0x42)));
+    }
+
+    #[test]
+    fn response_to_another_request() {
+        let report = Response::encode(Command::GetInfo as u8, &Ok(Response::Done));
+        assert_eq!(
+            Response::decode(Command::GetVersion, &report),
+            Err(Error::UnexpectedResponse(Command::GetInfo as u8))
+        );
+    }
+
+    #[test]
+    fn unknown_device_mode() {
+        let mut report = Response::encode(
+            Command::GetInfo as u8,
+            &Ok(Response::Info {
+                mode: DeviceMode::Keyboard,
+                num_layers: 4,
+                num_keys: 8,
+            }),
+        );
+        report[HEADER_SIZE] = 0x09;
+        assert_eq!(Response::decode(Command::GetInfo, &report), Err(Error::UnknownDeviceMode(0x09)));
+    }
+}
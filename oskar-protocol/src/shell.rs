@@ -0,0 +1,678 @@
+//! Commands of the shell on the firmware's debug console.
+//!
+//! Parsing and line editing don't depend on the firmware, so they can be
+//! tested on the host. Actions are written like their names in oskarctl
+//! keymaps, followed by their values, e.g. `keycode 0x04` or
+//! `chord 0x01 0x06`.
+
+use crate::{Action, BasicAction, KEY_NAMES};
+use core::fmt;
+
+/// Longest line the shell accepts.
+pub const LINE_SIZE: usize = 80;
+
+pub const HELP: &str = "\
+status                      mode, uptime and USB state\r
+uart baud [<rate>]          show or set the UART baud rate\r
+uart lines [<mapping>]      show or set how DTR and RTS drive the reset and boot pins,\r
+                            direct, esptool or stm32\r
+spi freq [<hz>]             show or set the serprog SPI clock, k and M suffixes work\r
+keymap show [<layer>]       show the keymap\r
+keymap set <layer> <key> <action>\r
+                            bind a key, e.g. keymap set 0 key1 keycode 0x04\r
+keymap save                 write the keymap to flash\r
+led <r> <g> <b> | off | auto\r
+                            override the LED colors\r
+log [<level>]               show or set the log level, off to trace\r
+flash-id                    JEDEC and unique ID of the on-board flash\r
+reboot                      restart the firmware\r
+bootsel                     restart into the USB bootloader\r
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Status,
+    /// Shows or sets the UART baud rate.
+    UartBaud(Option<u32>),
+    /// Shows or sets how the UART control lines drive the reset and boot pins.
+    UartLines(Option<LineMapping>),
+    /// Shows or sets the SPI clock of the serprog port in Hz.
+    SpiFreq(Option<u32>),
+    /// Shows a layer, or all of them.
+    KeymapShow(Option<u8>),
+    /// Binds a key, `key` indexes `KEY_NAMES`.
+    KeymapSet { layer: u8, key: usize, action: Action },
+    KeymapSave,
+    /// Sets all LEDs to a color, `None` hands them back to the firmware.
+    Led(Option<[u8; 3]>),
+    /// Shows or sets the log level.
+    Log(Option<LogLevel>),
+    FlashId,
+    Reboot,
+    Bootsel,
+}
+
+/// Log levels in the order of `log::LevelFilter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 6] = [
+        LogLevel::Off,
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+/// How DTR and RTS from the host drive the reset and boot pins of a target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineMapping {
+    /// Reset follows DTR and boot follows RTS, both low while set, like the
+    /// pins of a USB serial adapter.
+    Direct,
+    /// The two transistor auto-reset circuit of ESP32 boards: reset is low
+    /// while only RTS is set, boot while only DTR is set.
+    Esptool,
+    /// Reset is low while DTR is set, boot (BOOT0) high while RTS is set.
+    Stm32,
+}
+
+impl LineMapping {
+    pub const ALL: [LineMapping; 3] = [LineMapping::Direct, LineMapping::Esptool, LineMapping::Stm32];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            LineMapping::Direct => "direct",
+            LineMapping::Esptool => "esptool",
+            LineMapping::Stm32 => "stm32",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    UnknownCommand,
+    MissingArgument,
+    TooManyArguments,
+    InvalidNumber,
+    UnknownKey,
+    UnknownAction,
+    /// Tap-hold and lock dependent actions only fit into oskarctl keymaps.
+    UnsupportedAction,
+    UnknownLevel,
+    UnknownMapping,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ParseError::UnknownCommand => "unknown command, try help",
+            ParseError::MissingArgument => "missing argument",
+            ParseError::TooManyArguments => "too many arguments",
+            ParseError::InvalidNumber => "invalid number",
+            ParseError::UnknownKey => "unknown key",
+            ParseError::UnknownAction => "unknown action",
+            ParseError::UnsupportedAction => "tap_hold and if_lock can only be set with oskarctl",
+            ParseError::UnknownLevel => "unknown log level",
+            ParseError::UnknownMapping => "unknown line mapping",
+        };
+        f.write_str(message)
+    }
+}
+
+/// Parses a command line, `None` for an empty one.
+pub fn parse(line: &str) -> Result<Option<Command>, ParseError> {
+    let mut args = Args(line.split_ascii_whitespace());
+    let Some(name) = args.0.next() else {
+        return Ok(None);
+    };
+
+    let command = match name {
+        "help" | "?" => Command::Help,
+        "status" => Command::Status,
+        "uart" => match args.next()? {
+            "baud" => Command::UartBaud(args.optional(rate)?),
+            "lines" => Command::UartLines(args.optional(|name| {
+                LineMapping::ALL.into_iter().find(|mapping| mapping.name() == name).ok_or(ParseError::UnknownMapping)
+            })?),
+            _ => return Err(ParseError::UnknownCommand),
+        },
+        "spi" => match args.next()? {
+            "freq" => Command::SpiFreq(args.optional(rate)?),
+            _ => return Err(ParseError::UnknownCommand),
+        },
+        "keymap" => match args.next()? {
+            "show" => Command::KeymapShow(args.optional(number)?),
+            "set" => {
+                let layer = number(args.next()?)?;
+                let key = args.next()?;
+                let key = KEY_NAMES.iter().position(|name| *name == key).ok_or(ParseError::UnknownKey)?;
+                let action = parse_action(&mut args)?;
+                Command::KeymapSet { layer, key, action }
+            }
+            "save" => Command::KeymapSave,
+            _ => return Err(ParseError::UnknownCommand),
+        },
+        "led" => match args.next()? {
+            "auto" => Command::Led(None),
+            "off" => Command::Led(Some([0; 3])),
+            r => Command::Led(Some([number(r)?, number(args.next()?)?, number(args.next()?)?])),
+        },
+        "log" => Command::Log(args.optional(|name| {
+            LogLevel::ALL.into_iter().find(|level| level.name() == name).ok_or(ParseError::UnknownLevel)
+        })?),
+        "flash-id" => Command::FlashId,
+        "reboot" => Command::Reboot,
+        "bootsel" => Command::Bootsel,
+        _ => return Err(ParseError::UnknownCommand),
+    };
+
+    match args.0.next() {
+        Some(_) => Err(ParseError::TooManyArguments),
+        None => Ok(Some(command)),
+    }
+}
+
+struct Args<'a>(core::str::SplitAsciiWhitespace<'a>);
+
+impl<'a> Args<'a> {
+    fn next(&mut self) -> Result<&'a str, ParseError> {
+        self.0.next().ok_or(ParseError::MissingArgument)
+    }
+
+    fn optional<T>(&mut self, parse: impl FnOnce(&str) -> Result<T, ParseError>) -> Result<Option<T>, ParseError> {
+        self.0.next().map(parse).transpose()
+    }
+}
+
+fn parse_action(args: &mut Args) -> Result<Action, ParseError> {
+    let action = match args.next()? {
+        "transparent" => Action::Transparent,
+        "keycode" => Action::Keycode(number(args.next()?)?),
+        "media" => Action::Media(number(args.next()?)?),
+        "chord" => {
+            let modifiers = number(args.next()?)?;
+            let mut keycodes = [0u8; 6];
+            for keycode in keycodes.iter_mut() {
+                match args.0.next() {
+                    Some(arg) => *keycode = number(arg)?,
+                    None => break,
+                }
+            }
+            Action::Chord { modifiers, keycodes }
+        }
+        "macro" => Action::Macro(number(args.next()?)?),
+        "momentary_layer" => Action::MomentaryLayer(number(args.next()?)?),
+        "toggle_layer" => Action::ToggleLayer(number(args.next()?)?),
+        "one_shot_layer" => Action::OneShotLayer(number(args.next()?)?),
+        "default_layer" => Action::DefaultLayer(number(args.next()?)?),
+        "mouse_button" => Action::MouseButton(number(args.next()?)?),
+        "mouse_move" => Action::MouseMove {
+            x: signed(args.next()?)?,
+            y: signed(args.next()?)?,
+        },
+        "wheel" => Action::Wheel(signed(args.next()?)?),
+        "pan" => Action::Pan(signed(args.next()?)?),
+        "consumer" => Action::Consumer(number(args.next()?)?),
+        "system" => Action::System(number(args.next()?)?),
+        "tap_dance" => Action::TapDance(number(args.next()?)?),
+        "encoder_mode" => Action::EncoderMode,
+        "next_encoder_mode" => Action::NextEncoderMode,
+        "tap_hold" | "if_lock" => return Err(ParseError::UnsupportedAction),
+        _ => return Err(ParseError::UnknownAction),
+    };
+    Ok(action)
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number.
+fn number<T: TryFrom<u32>>(arg: &str) -> Result<T, ParseError> {
+    let value = match arg.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    value.ok().and_then(|value| T::try_from(value).ok()).ok_or(ParseError::InvalidNumber)
+}
+
+fn signed(arg: &str) -> Result<i8, ParseError> {
+    arg.parse().map_err(|_| ParseError::InvalidNumber)
+}
+
+/// Parses a rate with an optional `k` or `M` suffix.
+fn rate(arg: &str) -> Result<u32, ParseError> {
+    let (digits, factor) = match arg.as_bytes().last() {
+        Some(b'k') => (&arg[..arg.len() - 1], 1_000),
+        Some(b'M') => (&arg[..arg.len() - 1], 1_000_000),
+        _ => (arg, 1),
+    };
+    number::<u32>(digits)?.checked_mul(factor).ok_or(ParseError::InvalidNumber)
+}
+
+/// Writes an action in the syntax `parse` accepts. Tap-hold and lock
+/// dependent actions are written in a similar form for reading only.
+pub struct DisplayAction(pub Action);
+
+impl fmt::Display for DisplayAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Action::Transparent => write!(f, "transparent"),
+            Action::Keycode(code) => write!(f, "keycode {:#04x}", code),
+            Action::Media(code) => write!(f, "media {:#04x}", code),
+            Action::Chord { modifiers, keycodes } => {
+                write!(f, "chord {:#04x}", modifiers)?;
+                for keycode in keycodes.iter().filter(|&&keycode| keycode != 0) {
+                    write!(f, " {:#04x}", keycode)?;
+                }
+                Ok(())
+            }
+            Action::Macro(index) => write!(f, "macro {}", index),
+            Action::MomentaryLayer(layer) => write!(f, "momentary_layer {}", layer),
+            Action::ToggleLayer(layer) => write!(f, "toggle_layer {}", layer),
+            Action::OneShotLayer(layer) => write!(f, "one_shot_layer {}", layer),
+            Action::DefaultLayer(layer) => write!(f, "default_layer {}", layer),
+            Action::TapHold(tap_hold) => write!(
+                f,
+                "tap_hold ({}) ({}) {} ms",
+                DisplayBasicAction(tap_hold.tap),
+                DisplayBasicAction(tap_hold.hold),
+                tap_hold.term_ms
+            ),
+            Action::MouseButton(buttons) => write!(f, "mouse_button {:#04x}", buttons),
+            Action::MouseMove { x, y } => write!(f, "mouse_move {} {}", x, y),
+            Action::Wheel(notches) => write!(f, "wheel {}", notches),
+            Action::Pan(notches) => write!(f, "pan {}", notches),
+            Action::Consumer(usage) => write!(f, "consumer {:#06x}", usage),
+            Action::System(usage) => write!(f, "system {:#04x}", usage),
+            Action::IfLock { lock, on, off } => {
+                write!(f, "if_lock {:#04x} ({}) ({})", lock, DisplayBasicAction(on), DisplayBasicAction(off))
+            }
+            Action::TapDance(index) => write!(f, "tap_dance {}", index),
+            Action::EncoderMode => write!(f, "encoder_mode"),
+            Action::NextEncoderMode => write!(f, "next_encoder_mode"),
+        }
+    }
+}
+
+struct DisplayBasicAction(BasicAction);
+
+impl fmt::Display for DisplayBasicAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            BasicAction::Keycode(code) => write!(f, "keycode {:#04x}", code),
+            BasicAction::Media(code) => write!(f, "media {:#04x}", code),
+            BasicAction::Modifiers(modifiers) => write!(f, "modifiers {:#04x}", modifiers),
+            BasicAction::Macro(index) => write!(f, "macro {}", index),
+            BasicAction::MomentaryLayer(layer) => write!(f, "momentary_layer {}", layer),
+            BasicAction::ToggleLayer(layer) => write!(f, "toggle_layer {}", layer),
+            BasicAction::OneShotLayer(layer) => write!(f, "one_shot_layer {}", layer),
+            BasicAction::DefaultLayer(layer) => write!(f, "default_layer {}", layer),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /// After ESC.
+    Start,
+    /// Inside a control sequence, up to its final byte.
+    Sequence,
+}
+
+/// Collects a line typed into a terminal, with backspace, Ctrl-U to clear
+/// the line and Ctrl-C to abort it. Escape sequences like the arrow keys are
+/// ignored.
+pub struct LineEditor {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+    escape: Escape,
+    /// The last line ended with CR, a following LF belongs to it.
+    after_cr: bool,
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        LineEditor {
+            buf: [0; LINE_SIZE],
+            len: 0,
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    /// Handles a byte from the terminal and passes what has to be echoed to
+    /// `echo`. Returns `true` once a line is complete, see `take_line`.
+    pub fn feed(&mut self, byte: u8, echo: &mut impl FnMut(&[u8])) -> bool {
+        let after_cr = core::mem::replace(&mut self.after_cr, false);
+        match self.escape {
+            Escape::Start => {
+                self.escape = if byte == b'[' { Escape::Sequence } else { Escape::None };
+                return false;
+            }
+            Escape::Sequence => {
+                if (0x40..=0x7E).contains(&byte) {
+                    self.escape = Escape::None;
+                }
+                return false;
+            }
+            Escape::None => {}
+        }
+
+        match byte {
+            b'\n' if after_cr => {}
+            b'\r' | b'\n' => {
+                self.after_cr = byte == b'\r';
+                echo(b"\r\n");
+                return true;
+            }
+            // Backspace and delete
+            0x08 | 0x7F if self.len > 0 => {
+                self.len -= 1;
+                echo(b"\x08 \x08");
+            }
+            // Ctrl-U
+            0x15 => {
+                for _ in 0..self.len {
+                    echo(b"\x08 \x08");
+                }
+                self.len = 0;
+            }
+            // Ctrl-C
+            0x03 => {
+                self.len = 0;
+                echo(b"^C\r\n");
+                return true;
+            }
+            0x1B => self.escape = Escape::Start,
+            0x20..=0x7E if self.len < LINE_SIZE => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                echo(&[byte]);
+            }
+            // Line full, ring the bell.
+            0x20..=0x7E => echo(b"\x07"),
+            _ => {}
+        }
+        false
+    }
+
+    /// Returns the completed line and starts a new one.
+    pub fn take_line(&mut self) -> &str {
+        let len = core::mem::take(&mut self.len);
+        // Only printable ASCII is stored.
+        core::str::from_utf8(&self.buf[..len]).unwrap_or("")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse(""), Ok(None));
+        assert_eq!(parse("   "), Ok(None));
+        assert_eq!(parse("help"), Ok(Some(Command::Help)));
+        assert_eq!(parse("?"), Ok(Some(Command::Help)));
+        assert_eq!(parse("  status  "), Ok(Some(Command::Status)));
+        assert_eq!(parse("keymap save"), Ok(Some(Command::KeymapSave)));
+        assert_eq!(parse("flash-id"), Ok(Some(Command::FlashId)));
+        assert_eq!(parse("reboot"), Ok(Some(Command::Reboot)));
+        assert_eq!(parse("bootsel"), Ok(Some(Command::Bootsel)));
+    }
+
+    #[test]
+    fn arguments() {
+        assert_eq!(parse("uart baud"), Ok(Some(Command::UartBaud(None))));
+        assert_eq!(parse("uart baud 115200"), Ok(Some(Command::UartBaud(Some(115_200)))));
+        assert_eq!(parse("uart baud 250k"), Ok(Some(Command::UartBaud(Some(250_000)))));
+        assert_eq!(parse("uart lines"), Ok(Some(Command::UartLines(None))));
+        assert_eq!(parse("uart lines esptool"), Ok(Some(Command::UartLines(Some(LineMapping::Esptool)))));
+        assert_eq!(parse("spi freq 12M"), Ok(Some(Command::SpiFreq(Some(12_000_000)))));
+        assert_eq!(parse("spi freq 0x100"), Ok(Some(Command::SpiFreq(Some(256)))));
+        assert_eq!(parse("keymap show"), Ok(Some(Command::KeymapShow(None))));
+        assert_eq!(parse("keymap show 2"), Ok(Some(Command::KeymapShow(Some(2)))));
+        assert_eq!(parse("led 255 0 0x10"), Ok(Some(Command::Led(Some([255, 0, 16])))));
+        assert_eq!(parse("led off"), Ok(Some(Command::Led(Some([0; 3])))));
+        assert_eq!(parse("led auto"), Ok(Some(Command::Led(None))));
+        assert_eq!(parse("log"), Ok(Some(Command::Log(None))));
+        assert_eq!(parse("log debug"), Ok(Some(Command::Log(Some(LogLevel::Debug)))));
+    }
+
+    #[test]
+    fn keymap_set() {
+        assert_eq!(
+            parse("keymap set 1 key3 keycode 0x04"),
+            Ok(Some(Command::KeymapSet {
+                layer: 1,
+                key: 5,
+                action: Action::Keycode(0x04),
+            }))
+        );
+        assert_eq!(
+            parse("keymap set 0 encoder_button chord 0x01 0x06 0x19"),
+            Ok(Some(Command::KeymapSet {
+                layer: 0,
+                key: 2,
+                action: Action::Chord {
+                    modifiers: 0x01,
+                    keycodes: [0x06, 0x19, 0, 0, 0, 0],
+                },
+            }))
+        );
+        assert_eq!(
+            parse("keymap set 0 key1 mouse_move -5 10"),
+            Ok(Some(Command::KeymapSet {
+                layer: 0,
+                key: 3,
+                action: Action::MouseMove { x: -5, y: 10 },
+            }))
+        );
+        assert_eq!(parse("keymap set 0 key9 transparent"), Err(ParseError::UnknownKey));
+        assert_eq!(parse("keymap set 0 key1 bogus 1"), Err(ParseError::UnknownAction));
+        assert_eq!(parse("keymap set 0 key1 tap_hold"), Err(ParseError::UnsupportedAction));
+        assert_eq!(parse("keymap set 0 key1 if_lock"), Err(ParseError::UnsupportedAction));
+        assert_eq!(parse("keymap set 0 key1"), Err(ParseError::MissingArgument));
+    }
+
+    #[test]
+    fn actions_print_as_parsed() {
+        let actions = [
+            Action::Transparent,
+            Action::Keycode(0x04),
+            Action::Chord {
+                modifiers: 0x03,
+                keycodes: [0x06, 0, 0, 0, 0, 0],
+            },
+            Action::MouseMove { x: -3, y: 0 },
+            Action::Consumer(0x0223),
+            Action::TapDance(1),
+            Action::NextEncoderMode,
+        ];
+        for action in actions {
+            let mut line = Line::default();
+            fmt::write(&mut line, format_args!("keymap set 0 key1 {}", DisplayAction(action))).unwrap();
+            assert_eq!(
+                parse(line.as_str()),
+                Ok(Some(Command::KeymapSet { layer: 0, key: 3, action })),
+                "{}",
+                line.as_str()
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_commands() {
+        assert_eq!(parse("frobnicate"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("uart parity"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("spi mode 0"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("keymap load"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("HELP"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("uart"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("reboot now"), Err(ParseError::TooManyArguments));
+        assert_eq!(parse("uart baud 9600 8N1"), Err(ParseError::TooManyArguments));
+        assert_eq!(parse("uart lines rs485"), Err(ParseError::UnknownMapping));
+        assert_eq!(parse("log verbose"), Err(ParseError::UnknownLevel));
+    }
+
+    #[test]
+    fn numeric_arguments() {
+        assert_eq!(parse("keymap show x"), Err(ParseError::InvalidNumber));
+        assert_eq!(parse("keymap show -1"), Err(ParseError::InvalidNumber));
+        // Too large for a layer.
+        assert_eq!(parse("keymap show 256"), Err(ParseError::InvalidNumber));
+        assert_eq!(parse("led 0x100 0 0"), Err(ParseError::InvalidNumber));
+        assert_eq!(parse("led 1 2"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("uart baud 0xZZ"), Err(ParseError::InvalidNumber));
+        assert_eq!(parse("uart baud k"), Err(ParseError::InvalidNumber));
+        assert_eq!(parse("uart baud 5G"), Err(ParseError::InvalidNumber));
+        // Overflows once the suffix is applied.
+        assert_eq!(parse("spi freq 5000M"), Err(ParseError::InvalidNumber));
+        assert_eq!(parse("spi freq 4294967296"), Err(ParseError::InvalidNumber));
+        assert_eq!(parse("keymap set 0 key1 wheel 128"), Err(ParseError::InvalidNumber));
+        assert_eq!(parse("keymap set 0 key1 wheel 0x10"), Err(ParseError::InvalidNumber));
+    }
+
+    /// Fixed size text buffer, for echoed bytes and formatted lines.
+    struct Line {
+        buf: [u8; 256],
+        len: usize,
+    }
+
+    impl Default for Line {
+        fn default() -> Self {
+            Line { buf: [0; 256], len: 0 }
+        }
+    }
+
+    impl Line {
+        fn push(&mut self, bytes: &[u8]) {
+            self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+
+        fn as_bytes(&self) -> &[u8] {
+            &self.buf[..self.len]
+        }
+
+        fn as_str(&self) -> &str {
+            core::str::from_utf8(self.as_bytes()).unwrap()
+        }
+    }
+
+    impl fmt::Write for Line {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.push(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    /// Feeds `input` to the editor, returns how many lines were completed
+    /// and what was echoed.
+    fn type_in(editor: &mut LineEditor, input: &[u8]) -> (usize, Line) {
+        let mut echo = Line::default();
+        let mut lines = 0;
+        for &byte in input {
+            if editor.feed(byte, &mut |bytes| echo.push(bytes)) {
+                lines += 1;
+            }
+        }
+        (lines, echo)
+    }
+
+    #[test]
+    fn line_editing() {
+        let mut editor = LineEditor::new();
+        let (lines, echo) = type_in(&mut editor, b"help\r");
+        assert_eq!(lines, 1);
+        assert_eq!(echo.as_bytes(), b"help\r\n");
+        assert_eq!(editor.take_line(), "help");
+        assert_eq!(editor.take_line(), "");
+
+        // The LF of CR LF doesn't end another line, a lone LF does.
+        assert_eq!(type_in(&mut editor, b"\nstatus\n").0, 1);
+        assert_eq!(editor.take_line(), "status");
+    }
+
+    #[test]
+    fn backspace() {
+        let mut editor = LineEditor::new();
+        let (lines, echo) = type_in(&mut editor, b"helq\x08p\x7F\x7Fl");
+        assert_eq!(lines, 0);
+        assert_eq!(echo.as_bytes(), b"helq\x08 \x08p\x08 \x08\x08 \x08l");
+        assert_eq!(type_in(&mut editor, b"\r").0, 1);
+        assert_eq!(editor.take_line(), "hel");
+
+        // Nothing to delete, nothing echoed.
+        let (_, echo) = type_in(&mut editor, b"\x08\x7F");
+        assert_eq!(echo.as_bytes(), b"");
+    }
+
+    #[test]
+    fn clear_and_abort() {
+        let mut editor = LineEditor::new();
+        let (_, echo) = type_in(&mut editor, b"ab\x15c");
+        assert_eq!(echo.as_bytes(), b"ab\x08 \x08\x08 \x08c");
+        let (lines, echo) = type_in(&mut editor, b"\x03");
+        assert_eq!(lines, 1);
+        assert_eq!(echo.as_bytes(), b"^C\r\n");
+        assert_eq!(editor.take_line(), "");
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let mut editor = LineEditor::new();
+        // Arrow keys, Delete with a parameter, and Alt-x.
+        let (_, echo) = type_in(&mut editor, b"a\x1b[A\x1b[D\x1b[3~b\x1bxc\r");
+        assert_eq!(echo.as_bytes(), b"abc\r\n");
+        assert_eq!(editor.take_line(), "abc");
+
+        // Bytes inside a sequence don't end the line.
+        assert_eq!(type_in(&mut editor, b"\x1b[1;5").0, 0);
+        assert_eq!(type_in(&mut editor, b"C\r").0, 1);
+        assert_eq!(editor.take_line(), "");
+    }
+
+    #[test]
+    fn line_overflow() {
+        let mut editor = LineEditor::new();
+        let full = [b'x'; LINE_SIZE];
+        let (_, echo) = type_in(&mut editor, &full);
+        assert_eq!(echo.as_bytes(), &full[..]);
+        // Further characters are dropped with a bell.
+        let (_, echo) = type_in(&mut editor, b"yz");
+        assert_eq!(echo.as_bytes(), b"\x07\x07");
+        // Deleting makes room again.
+        type_in(&mut editor, b"\x08y\r");
+        let line = editor.take_line();
+        assert_eq!(line.len(), LINE_SIZE);
+        assert!(line.ends_with("xy"));
+    }
+}
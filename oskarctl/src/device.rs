@@ -0,0 +1,128 @@
+use anyhow::{bail, Context, Result};
+use hidapi::{HidApi, HidDevice};
+use oskar_protocol::{Request, Response, PID, REPORT_SIZE, USAGE, USAGE_PAGE, VID};
+
+const READ_TIMEOUT_MS: i32 = 1000;
+
+/// Moves raw reports between the host and the configuration interface.
+///
+/// Implemented by the hidapi backed `HidTransport`, and by anything that wants
+/// to stand in for a real device.
+pub trait Transport {
+    fn send(&mut self, report: &[u8; REPORT_SIZE]) -> Result<()>;
+    fn receive(&mut self) -> Result<[u8; REPORT_SIZE]>;
+}
+
+impl<T: Transport> Transport for &mut T {
+    fn send(&mut self, report: &[u8; REPORT_SIZE]) -> Result<()> {
+        (**self).send(report)
+    }
+
+    fn receive(&mut self) -> Result<[u8; REPORT_SIZE]> {
+        (**self).receive()
+    }
+}
+
+pub struct HidTransport {
+    device: HidDevice,
+}
+
+impl Transport for HidTransport {
+    fn send(&mut self, report: &[u8; REPORT_SIZE]) -> Result<()> {
+        // hidapi expects the report ID in front, the interface doesn't use one.
+        let mut buf = [0u8; REPORT_SIZE + 1];
+        buf[1..].copy_from_slice(report);
+        self.device.write(&buf).context("failed to send request")?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<[u8; REPORT_SIZE]> {
+        let mut buf = [0u8; REPORT_SIZE];
+        let n = self
+            .device
+            .read_timeout(&mut buf, READ_TIMEOUT_MS)
+            .context("failed to read response")?;
+        if n == 0 {
+            bail!("timed out waiting for a response");
+        }
+        Ok(buf)
+    }
+}
+
+/// A keypad found on the bus.
+pub struct DeviceEntry {
+    pub serial: String,
+    /// Path of the configuration interface, `None` if the device was booted
+    /// in a mode that doesn't expose it.
+    pub config_path: Option<std::ffi::CString>,
+}
+
+pub fn list(api: &HidApi) -> Vec<DeviceEntry> {
+    let mut entries: Vec<DeviceEntry> = Vec::new();
+
+    for info in api.device_list() {
+        if info.vendor_id() != VID || info.product_id() != PID {
+            continue;
+        }
+
+        let serial = info.serial_number().unwrap_or_default().to_string();
+        let index = match entries.iter().position(|e| e.serial == serial) {
+            Some(index) => index,
+            None => {
+                entries.push(DeviceEntry {
+                    serial,
+                    config_path: None,
+                });
+                entries.len() - 1
+            }
+        };
+
+        if info.usage_page() == USAGE_PAGE && info.usage() == USAGE {
+            entries[index].config_path = Some(info.path().to_owned());
+        }
+    }
+
+    entries
+}
+
+/// Opens the configuration interface of the device with the given serial
+/// number, or of the only device connected if `serial` is `None`.
+pub fn open(api: &HidApi, serial: Option<&str>) -> Result<Client<HidTransport>> {
+    let mut entries: Vec<DeviceEntry> = list(api)
+        .into_iter()
+        .filter(|e| serial.is_none_or(|s| e.serial.eq_ignore_ascii_case(s)))
+        .collect();
+
+    let entry = match entries.len() {
+        0 => bail!("no OSKAR device found"),
+        1 => entries.remove(0),
+        _ => bail!("multiple OSKAR devices found, select one with --serial"),
+    };
+
+    let Some(path) = entry.config_path else {
+        bail!("device {} does not expose the configuration interface", entry.serial);
+    };
+
+    let device = api
+        .open_path(&path)
+        .with_context(|| format!("failed to open device {}", entry.serial))?;
+    Ok(Client::new(HidTransport { device }))
+}
+
+/// Request/response client on top of a `Transport`.
+pub struct Client<T: Transport> {
+    transport: T,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Self {
+        Client { transport }
+    }
+
+    pub fn request(&mut self, request: Request) -> Result<Response> {
+        self.transport.send(&request.encode())?;
+        let report = self.transport.receive()?;
+        Response::decode(request.command(), &report)
+            .map_err(|e| anyhow::anyhow!("{:?} failed: {:?}", request.command(), e))
+    }
+}
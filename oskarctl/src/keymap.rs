@@ -0,0 +1,170 @@
+use anyhow::{bail, Result};
+use oskar_protocol::macros::{encode_macro, find_macro, MacroStep, MacroSteps, MACRO_BUFFER_SIZE};
+use oskar_protocol::{Action, NUM_KEYS};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Keymap as stored in TOML or JSON files, one entry per layer.
+#[derive(Serialize, Deserialize)]
+pub struct KeymapFile {
+    pub layers: Vec<Layer>,
+    /// Played by `macro = n` actions, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub macros: Vec<Macro>,
+}
+
+/// Field order follows `oskar_protocol::KEY_NAMES`.
+#[derive(Serialize, Deserialize)]
+pub struct Layer {
+    pub encoder_left: Action,
+    pub encoder_right: Action,
+    pub encoder_button: Action,
+    pub key1: Action,
+    pub key2: Action,
+    pub key3: Action,
+    /// Encoder turned while its button is held, missing in older files.
+    #[serde(default = "transparent")]
+    pub encoder_pressed_left: Action,
+    #[serde(default = "transparent")]
+    pub encoder_pressed_right: Action,
+}
+
+fn transparent() -> Action {
+    Action::Transparent
+}
+
+impl Layer {
+    pub fn from_actions(actions: [Action; NUM_KEYS]) -> Self {
+        let [encoder_left, encoder_right, encoder_button, key1, key2, key3, encoder_pressed_left, encoder_pressed_right] = actions;
+        Layer {
+            encoder_left,
+            encoder_right,
+            encoder_button,
+            key1,
+            key2,
+            key3,
+            encoder_pressed_left,
+            encoder_pressed_right,
+        }
+    }
+
+    pub fn actions(&self) -> [Action; NUM_KEYS] {
+        [
+            self.encoder_left,
+            self.encoder_right,
+            self.encoder_button,
+            self.key1,
+            self.key2,
+            self.key3,
+            self.encoder_pressed_left,
+            self.encoder_pressed_right,
+        ]
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Macro {
+    pub steps: Vec<Step>,
+}
+
+/// Owned counterpart of `MacroStep`, keycodes are raw HID usage IDs.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Text(String),
+    Tap(u8),
+    Press(u8),
+    Release(u8),
+    Delay(u16),
+    KeyDelay(u16),
+}
+
+impl Step {
+    fn from_step(step: MacroStep<'_>) -> Self {
+        match step {
+            MacroStep::Text(text) => Step::Text(text.to_string()),
+            MacroStep::Tap(code) => Step::Tap(code),
+            MacroStep::Press(code) => Step::Press(code),
+            MacroStep::Release(code) => Step::Release(code),
+            MacroStep::Delay(ms) => Step::Delay(ms),
+            MacroStep::KeyDelay(ms) => Step::KeyDelay(ms),
+        }
+    }
+
+    fn as_step(&self) -> MacroStep<'_> {
+        match self {
+            Step::Text(text) => MacroStep::Text(text),
+            Step::Tap(code) => MacroStep::Tap(*code),
+            Step::Press(code) => MacroStep::Press(*code),
+            Step::Release(code) => MacroStep::Release(*code),
+            Step::Delay(ms) => MacroStep::Delay(*ms),
+            Step::KeyDelay(ms) => MacroStep::KeyDelay(*ms),
+        }
+    }
+}
+
+/// Decodes the device's macro buffer. Trailing empty macros are dropped.
+pub fn decode_macros(buf: &[u8]) -> Vec<Macro> {
+    let mut macros = Vec::new();
+    let mut rest = buf;
+    while let Some(encoded) = find_macro(rest, 0) {
+        let steps = MacroSteps::new(encoded).map(Step::from_step).collect();
+        macros.push(Macro { steps });
+        rest = &rest[encoded.len() + 1..];
+    }
+    while macros.last().is_some_and(|m| m.steps.is_empty()) {
+        macros.pop();
+    }
+    macros
+}
+
+/// Encodes macros into a buffer as expected by the device.
+pub fn encode_macros(macros: &[Macro]) -> Result<[u8; MACRO_BUFFER_SIZE]> {
+    let mut buf = [0u8; MACRO_BUFFER_SIZE];
+    let mut offset = 0;
+    for (index, m) in macros.iter().enumerate() {
+        let steps: Vec<MacroStep<'_>> = m.steps.iter().map(Step::as_step).collect();
+        offset = match encode_macro(&steps, &mut buf, offset) {
+            Some(end) => end,
+            None => bail!("macro {} doesn't fit, macros are limited to {} bytes", index, MACRO_BUFFER_SIZE),
+        };
+    }
+    Ok(buf)
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Guesses the format from the file extension, defaulting to TOML.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+}
+
+impl KeymapFile {
+    pub fn to_string(&self, format: Format) -> Result<String> {
+        let text = match format {
+            Format::Toml => toml::to_string_pretty(self)?,
+            Format::Json => serde_json::to_string_pretty(self)?,
+        };
+        Ok(text)
+    }
+
+    pub fn from_str(text: &str, format: Format) -> Result<Self> {
+        let keymap: KeymapFile = match format {
+            Format::Toml => toml::from_str(text)?,
+            Format::Json => serde_json::from_str(text)?,
+        };
+        if keymap.layers.is_empty() {
+            bail!("keymap has no layers");
+        }
+        Ok(keymap)
+    }
+}
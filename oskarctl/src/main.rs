@@ -0,0 +1,343 @@
+//! Host tool for configuring the OSKAR keypad over its vendor HID interface.
+
+mod device;
+mod keymap;
+#[cfg(test)]
+mod mock;
+
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use device::{Client, Transport};
+use hidapi::HidApi;
+use keymap::{Format, KeymapFile, Layer};
+use oskar_protocol::macros::MACRO_BUFFER_SIZE;
+use oskar_protocol::{Action, Request, Response, LAYOUT_CHUNK_KEYS, MACRO_CHUNK_SIZE, NUM_KEYS};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Serial number of the device, required if more than one is connected
+    #[arg(short, long, global = true)]
+    serial: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List connected devices
+    List,
+    /// Show firmware version and the mode the device booted in
+    Info,
+    /// Write the keymap to a file, or to stdout
+    Dump {
+        /// Output file, the format is derived from its extension
+        output: Option<PathBuf>,
+        /// Output format, overrides the file extension
+        #[arg(short, long, value_enum)]
+        format: Option<Format>,
+    },
+    /// Load a keymap from a file
+    Load {
+        input: PathBuf,
+        /// Input format, overrides the file extension
+        #[arg(short, long, value_enum)]
+        format: Option<Format>,
+        /// Persist the keymap to flash afterwards
+        #[arg(long)]
+        save: bool,
+    },
+    /// Persist the current keymap to flash
+    Save,
+    /// Restore the compiled default keymap, run `save` to persist it
+    Reset,
+    /// Reboot into the USB bootloader for flashing
+    Bootloader,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let api = HidApi::new()?;
+
+    if let Command::List = cli.command {
+        for entry in device::list(&api) {
+            let config = match entry.config_path {
+                Some(_) => "configurable",
+                None => "no configuration interface",
+            };
+            println!("{} ({})", entry.serial, config);
+        }
+        return Ok(());
+    }
+
+    let mut client = device::open(&api, cli.serial.as_deref())?;
+    run(&mut client, cli.command)
+}
+
+/// Runs a command that talks to the device.
+fn run<T: Transport>(client: &mut Client<T>, command: Command) -> Result<()> {
+    match command {
+        Command::List => unreachable!(),
+        Command::Info => info(client)?,
+        Command::Dump { output, format } => {
+            let format = format.unwrap_or_else(|| output.as_deref().map_or(Format::Toml, Format::from_path));
+            let text = dump(client)?.to_string(format)?;
+            match output {
+                Some(path) => std::fs::write(path, text)?,
+                None => print!("{}", text),
+            }
+        }
+        Command::Load {
+            input,
+            format,
+            save,
+        } => {
+            let format = format.unwrap_or_else(|| Format::from_path(&input));
+            let keymap = KeymapFile::from_str(&std::fs::read_to_string(&input)?, format)?;
+            load(client, &keymap)?;
+            if save {
+                client.request(Request::Save)?;
+            }
+        }
+        Command::Save => {
+            client.request(Request::Save)?;
+        }
+        Command::Reset => {
+            client.request(Request::ResetDefaults)?;
+        }
+        Command::Bootloader => {
+            client.request(Request::RebootBootloader)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn info<T: Transport>(client: &mut Client<T>) -> Result<()> {
+    if let Response::Version {
+        protocol,
+        major,
+        minor,
+        patch,
+    } = client.request(Request::GetVersion)?
+    {
+        println!("firmware: {}.{}.{} (protocol {})", major, minor, patch, protocol);
+    }
+
+    if let Response::Info {
+        mode,
+        num_layers,
+        num_keys,
+    } = client.request(Request::GetInfo)?
+    {
+        println!("mode:     {:?}", mode);
+        println!("layers:   {}", num_layers);
+        println!("keys:     {}", num_keys);
+    }
+
+    Ok(())
+}
+
+fn layer_count<T: Transport>(client: &mut Client<T>) -> Result<u8> {
+    match client.request(Request::GetInfo)? {
+        Response::Info { num_keys, .. } if num_keys as usize != NUM_KEYS => {
+            bail!("device has {} keys, expected {}", num_keys, NUM_KEYS)
+        }
+        Response::Info { num_layers, .. } => Ok(num_layers),
+        _ => bail!("unexpected response to GetInfo"),
+    }
+}
+
+fn dump<T: Transport>(client: &mut Client<T>) -> Result<KeymapFile> {
+    let mut layers = Vec::new();
+    for layer in 0..layer_count(client)? {
+        let mut actions = [Action::Transparent; NUM_KEYS];
+        for first in (0..NUM_KEYS).step_by(LAYOUT_CHUNK_KEYS) {
+            match client.request(Request::GetLayout { layer, first: first as u8 })? {
+                Response::Layout { actions: chunk, .. } => {
+                    let len = LAYOUT_CHUNK_KEYS.min(NUM_KEYS - first);
+                    actions[first..first + len].copy_from_slice(&chunk[..len]);
+                }
+                _ => bail!("unexpected response to GetLayout"),
+            }
+        }
+        layers.push(Layer::from_actions(actions));
+    }
+
+    let mut buf = [0u8; MACRO_BUFFER_SIZE];
+    for offset in (0..MACRO_BUFFER_SIZE).step_by(MACRO_CHUNK_SIZE) {
+        match client.request(Request::GetMacros { offset: offset as u16 })? {
+            Response::Macros { data, .. } => {
+                let len = MACRO_CHUNK_SIZE.min(MACRO_BUFFER_SIZE - offset);
+                buf[offset..offset + len].copy_from_slice(&data[..len]);
+            }
+            _ => bail!("unexpected response to GetMacros"),
+        }
+    }
+
+    Ok(KeymapFile {
+        layers,
+        macros: keymap::decode_macros(&buf),
+    })
+}
+
+fn load<T: Transport>(client: &mut Client<T>, keymap: &KeymapFile) -> Result<()> {
+    let num_layers = layer_count(client)?;
+    if keymap.layers.len() > num_layers as usize {
+        bail!("keymap has {} layers, the device supports {}", keymap.layers.len(), num_layers);
+    }
+
+    for (layer, actions) in keymap.layers.iter().enumerate() {
+        for (key, action) in actions.actions().into_iter().enumerate() {
+            client.request(Request::SetKey {
+                layer: layer as u8,
+                key: key as u8,
+                action,
+            })?;
+        }
+    }
+
+    let buf = keymap::encode_macros(&keymap.macros)?;
+    for (index, chunk) in buf.chunks(MACRO_CHUNK_SIZE).enumerate() {
+        let mut data = [0u8; MACRO_CHUNK_SIZE];
+        data[..chunk.len()].copy_from_slice(chunk);
+        client.request(Request::SetMacros {
+            offset: (index * MACRO_CHUNK_SIZE) as u16,
+            len: chunk.len() as u8,
+            data,
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keymap::{Macro, Step};
+    use mock::MockDevice;
+    use oskar_protocol::{BasicAction, DeviceMode, TapHold};
+
+    fn keymap() -> KeymapFile {
+        let mut actions = [Action::Transparent; NUM_KEYS];
+        actions[0] = Action::Media(0xEA);
+        actions[2] = Action::TapHold(TapHold {
+            tap: BasicAction::Keycode(0x2C),
+            hold: BasicAction::MomentaryLayer(1),
+            term_ms: 150,
+            hold_on_other_key_press: true,
+            permissive_hold: false,
+        });
+        actions[NUM_KEYS - 1] = Action::Keycode(0x04);
+        KeymapFile {
+            layers: vec![Layer::from_actions(actions), Layer::from_actions([Action::Keycode(0x05); NUM_KEYS])],
+            macros: vec![
+                Macro {
+                    steps: vec![Step::Text("a long line of text that takes more than one chunk to transfer".into())],
+                },
+                Macro {
+                    steps: vec![Step::Press(0xE0), Step::Tap(0x06), Step::Release(0xE0)],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn load_then_dump() {
+        let mut device = MockDevice::new(2);
+        let mut client = Client::new(&mut device);
+        let keymap = keymap();
+        load(&mut client, &keymap).unwrap();
+        let dumped = dump(&mut client).unwrap();
+
+        assert_eq!(dumped.to_string(Format::Toml).unwrap(), keymap.to_string(Format::Toml).unwrap());
+        assert_eq!(device.layers[1], [Action::Keycode(0x05); NUM_KEYS]);
+    }
+
+    #[test]
+    fn load_sets_every_key() {
+        let mut device = MockDevice::new(3);
+        load(&mut Client::new(&mut device), &keymap()).unwrap();
+
+        let set_keys = device.requests.iter().filter(|r| matches!(r, Request::SetKey { .. })).count();
+        assert_eq!(set_keys, 2 * NUM_KEYS);
+        assert_eq!(device.layers[2], [Action::Transparent; NUM_KEYS]);
+    }
+
+    #[test]
+    fn load_too_many_layers() {
+        let mut device = MockDevice::new(1);
+        let err = load(&mut Client::new(&mut device), &keymap()).unwrap_err();
+        assert_eq!(err.to_string(), "keymap has 2 layers, the device supports 1");
+        assert_eq!(device.requests, [Request::GetInfo]);
+    }
+
+    #[test]
+    fn dump_key_count_mismatch() {
+        let mut device = MockDevice::new(1);
+        device.num_keys = NUM_KEYS as u8 - 2;
+        let Err(err) = dump(&mut Client::new(&mut device)) else {
+            panic!("dump accepted a device with the wrong key count");
+        };
+        assert_eq!(err.to_string(), format!("device has {} keys, expected {}", NUM_KEYS - 2, NUM_KEYS));
+    }
+
+    #[test]
+    fn info_reads_version_and_mode() {
+        let mut device = MockDevice::new(1);
+        device.mode = DeviceMode::Keyboard;
+        run(&mut Client::new(&mut device), Command::Info).unwrap();
+        assert_eq!(device.requests, [Request::GetVersion, Request::GetInfo]);
+
+        let response = Client::new(&mut device).request(Request::GetInfo).unwrap();
+        assert_eq!(
+            response,
+            Response::Info {
+                mode: DeviceMode::Keyboard,
+                num_layers: 1,
+                num_keys: NUM_KEYS as u8,
+            }
+        );
+    }
+
+    #[test]
+    fn load_in_picoprog_mode() {
+        // The config interface is there in every mode, the keymap applies
+        // once the device is switched to a keyboard mode.
+        let mut device = MockDevice::new(2);
+        device.mode = DeviceMode::Picoprog;
+        run(&mut Client::new(&mut device), Command::Info).unwrap();
+        load(&mut Client::new(&mut device), &keymap()).unwrap();
+        assert_eq!(device.layers[1], [Action::Keycode(0x05); NUM_KEYS]);
+    }
+
+    #[test]
+    fn bootloader() {
+        let mut device = MockDevice::new(1);
+        run(&mut Client::new(&mut device), Command::Bootloader).unwrap();
+        assert_eq!(device.requests, [Request::RebootBootloader]);
+    }
+
+    #[test]
+    fn save_and_reset() {
+        let mut device = MockDevice::new(1);
+        run(&mut Client::new(&mut device), Command::Save).unwrap();
+        run(&mut Client::new(&mut device), Command::Reset).unwrap();
+        assert_eq!(device.requests, [Request::Save, Request::ResetDefaults]);
+    }
+
+    #[test]
+    fn error_status() {
+        let mut device = MockDevice::new(1);
+        let err = Client::new(&mut device).request(Request::GetKey { layer: 1, key: 0 }).unwrap_err();
+        assert_eq!(err.to_string(), "GetKey failed: Status(InvalidArgument)");
+    }
+
+    #[test]
+    fn no_response() {
+        let mut device = MockDevice::new(1);
+        let err = device.receive().unwrap_err();
+        assert_eq!(err.to_string(), "timed out waiting for a response");
+    }
+}
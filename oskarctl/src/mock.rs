@@ -0,0 +1,100 @@
+//! Stand-in for a keypad, answers requests like the firmware does.
+
+use crate::device::Transport;
+use anyhow::{Context, Result};
+use oskar_protocol::macros::{self, MACRO_BUFFER_SIZE};
+use oskar_protocol::{
+    Action, DeviceMode, Request, Response, Status, LAYOUT_CHUNK_KEYS, NUM_KEYS, PROTOCOL_VERSION,
+    REPORT_SIZE,
+};
+
+pub struct MockDevice {
+    pub mode: DeviceMode,
+    /// Reported by `GetInfo`, the keymap always has `NUM_KEYS` keys.
+    pub num_keys: u8,
+    pub layers: Vec<[Action; NUM_KEYS]>,
+    pub macros: [u8; MACRO_BUFFER_SIZE],
+    /// Every request received, in order.
+    pub requests: Vec<Request>,
+    /// Answer to the last request, until it is received.
+    response: Option<[u8; REPORT_SIZE]>,
+}
+
+impl MockDevice {
+    pub fn new(num_layers: usize) -> Self {
+        MockDevice {
+            mode: DeviceMode::Universal,
+            num_keys: NUM_KEYS as u8,
+            layers: vec![[Action::Transparent; NUM_KEYS]; num_layers],
+            macros: [0; MACRO_BUFFER_SIZE],
+            requests: Vec::new(),
+            response: None,
+        }
+    }
+
+    fn execute(&mut self, request: Request) -> Result<Response, Status> {
+        let response = match request {
+            Request::GetVersion => Response::Version {
+                protocol: PROTOCOL_VERSION,
+                major: 0,
+                minor: 1,
+                patch: 0,
+            },
+            Request::GetInfo => Response::Info {
+                mode: self.mode,
+                num_layers: self.layers.len() as u8,
+                num_keys: self.num_keys,
+            },
+            Request::GetKey { layer, key } => Response::Key {
+                layer,
+                key,
+                action: *self.key(layer, key)?,
+            },
+            Request::SetKey { layer, key, action } => {
+                *self.key(layer, key)? = action;
+                Response::Done
+            }
+            Request::GetLayout { layer, first } => {
+                let keys = self.layers.get(layer as usize).ok_or(Status::InvalidArgument)?;
+                let keys = keys.get(first as usize..).ok_or(Status::InvalidArgument)?;
+                let mut actions = [Action::Transparent; LAYOUT_CHUNK_KEYS];
+                for (action, key) in actions.iter_mut().zip(keys) {
+                    *action = *key;
+                }
+                Response::Layout { layer, first, actions }
+            }
+            Request::GetMacros { offset } => Response::Macros {
+                offset,
+                data: macros::read_chunk(&self.macros, offset as usize).ok_or(Status::InvalidArgument)?,
+            },
+            Request::SetMacros { offset, len, data } => {
+                macros::write_chunk(&mut self.macros, offset as usize, &data[..len as usize]).ok_or(Status::InvalidArgument)?;
+                Response::Done
+            }
+            Request::Save | Request::ResetDefaults | Request::RebootBootloader => Response::Done,
+        };
+        Ok(response)
+    }
+
+    fn key(&mut self, layer: u8, key: u8) -> Result<&mut Action, Status> {
+        self.layers
+            .get_mut(layer as usize)
+            .and_then(|keys| keys.get_mut(key as usize))
+            .ok_or(Status::InvalidArgument)
+    }
+}
+
+impl Transport for MockDevice {
+    fn send(&mut self, report: &[u8; REPORT_SIZE]) -> Result<()> {
+        let result = Request::decode(report).map_err(Status::from).and_then(|request| {
+            self.requests.push(request);
+            self.execute(request)
+        });
+        self.response = Some(Response::encode(report[1], &result));
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<[u8; REPORT_SIZE]> {
+        self.response.take().context("timed out waiting for a response")
+    }
+}
@@ -0,0 +1,335 @@
+//! CDC ACM class for the UART port.
+//!
+//! Works like `embassy_usb::class::cdc_acm`, which doesn't give access to its
+//! notification endpoint. This one sends serial state notifications, so the
+//! modem input lines reach the host, and signals changes of the line coding
+//! and of the control lines.
+
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::class::cdc_acm::{ParityType, StopBits};
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler};
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0A;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+/// Line coding, control line state and serial state notifications.
+const ACM_CAPABILITIES: u8 = 0x02;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+
+/// Bits of the serial state. ACM has no bit for CTS, so it is reported as
+/// carrier detect.
+pub const SERIAL_STATE_DCD: u16 = 1 << 0;
+pub const SERIAL_STATE_DSR: u16 = 1 << 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LineCoding {
+    data_rate: u32,
+    stop_bits: StopBits,
+    parity_type: ParityType,
+    data_bits: u8,
+}
+
+impl LineCoding {
+    pub fn data_rate(&self) -> u32 {
+        self.data_rate
+    }
+
+    pub fn stop_bits(&self) -> StopBits {
+        self.stop_bits
+    }
+
+    pub fn parity_type(&self) -> ParityType {
+        self.parity_type
+    }
+
+    pub fn data_bits(&self) -> u8 {
+        self.data_bits
+    }
+}
+
+/// 115200 8N1 like the UART after power-up, so the port keeps working until
+/// the host sets its own line coding.
+impl Default for LineCoding {
+    fn default() -> Self {
+        LineCoding {
+            data_rate: 115_200,
+            stop_bits: StopBits::One,
+            parity_type: ParityType::None,
+            data_bits: 8,
+        }
+    }
+}
+
+/// DTR and RTS as set by the host.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ControlLines {
+    pub dtr: bool,
+    pub rts: bool,
+}
+
+struct ControlShared {
+    line_coding: Mutex<CriticalSectionRawMutex, Cell<LineCoding>>,
+    control_lines: Mutex<CriticalSectionRawMutex, Cell<ControlLines>>,
+    line_coding_changed: Signal<CriticalSectionRawMutex, ()>,
+    control_lines_changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl State<'_> {
+    pub fn new() -> Self {
+        State {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared {
+                line_coding: Mutex::new(Cell::new(LineCoding::default())),
+                control_lines: Mutex::new(Cell::new(ControlLines::default())),
+                line_coding_changed: Signal::new(),
+                control_lines_changed: Signal::new(),
+            },
+        }
+    }
+}
+
+impl Default for State<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Control<'a> {
+    comm_if: InterfaceNumber,
+    shared: &'a ControlShared,
+}
+
+impl Control<'_> {
+    fn set_control_lines(&self, lines: ControlLines) {
+        self.shared.control_lines.lock(|current| current.set(lines));
+        self.shared.control_lines_changed.signal(());
+    }
+}
+
+impl Handler for Control<'_> {
+    fn reset(&mut self) {
+        self.set_control_lines(ControlLines::default());
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index) != (RequestType::Class, Recipient::Interface, u8::from(self.comm_if) as u16) {
+            return None;
+        }
+
+        match req.request {
+            // Not used by ACM, accepted and ignored.
+            REQ_SEND_ENCAPSULATED_COMMAND => Some(OutResponse::Accepted),
+            REQ_SET_LINE_CODING if data.len() >= 7 => {
+                let coding = LineCoding {
+                    data_rate: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                    stop_bits: data[4].into(),
+                    parity_type: data[5].into(),
+                    data_bits: data[6],
+                };
+                self.shared.line_coding.lock(|current| current.set(coding));
+                self.shared.line_coding_changed.signal(());
+                Some(OutResponse::Accepted)
+            }
+            REQ_SET_CONTROL_LINE_STATE => {
+                self.set_control_lines(ControlLines {
+                    dtr: req.value & 0x0001 != 0,
+                    rts: req.value & 0x0002 != 0,
+                });
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index) != (RequestType::Class, Recipient::Interface, u8::from(self.comm_if) as u16) {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_LINE_CODING if req.length == 7 && buf.len() >= 7 => {
+                let coding = self.shared.line_coding.lock(|current| current.get());
+                buf[0..4].copy_from_slice(&coding.data_rate.to_le_bytes());
+                buf[4] = coding.stop_bits as u8;
+                buf[5] = coding.parity_type as u8;
+                buf[6] = coding.data_bits;
+                Some(InResponse::Accepted(&buf[0..7]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+pub struct CdcAcmClass<'d, D: Driver<'d>> {
+    comm_if: InterfaceNumber,
+    comm_ep: D::EndpointIn,
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    shared: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> CdcAcmClass<'d, D> {
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, max_packet_size: u16) -> Self {
+        let mut func = builder.function(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE);
+
+        // Communication interface
+        let mut iface = func.interface();
+        let comm_if = iface.interface_number();
+        let data_if = u8::from(comm_if) + 1;
+        let mut alt = iface.alt_setting(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE, None);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01]);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_ACM, ACM_CAPABILITIES]);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_UNION, comm_if.into(), data_if]);
+        // Fits a serial state notification, 8 byte header and 2 byte state.
+        let comm_ep = alt.endpoint_interrupt_in(16, 10);
+
+        // Data interface
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(USB_CLASS_CDC_DATA, 0x00, CDC_PROTOCOL_NONE, None);
+        let read_ep = alt.endpoint_bulk_out(max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(max_packet_size);
+
+        drop(func);
+
+        let control = state.control.write(Control {
+            comm_if,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        CdcAcmClass {
+            comm_if,
+            comm_ep,
+            read_ep,
+            write_ep,
+            shared: &state.shared,
+        }
+    }
+
+    /// Handle on the line coding and the control lines, it stays usable
+    /// after `split`.
+    pub fn line_state(&self) -> LineState<'d> {
+        LineState { shared: self.shared }
+    }
+
+    pub fn split(self) -> (Sender<'d, D>, Receiver<'d, D>, Notifier<'d, D>) {
+        (
+            Sender { write_ep: self.write_ep },
+            Receiver { read_ep: self.read_ep },
+            Notifier {
+                comm_if: self.comm_if,
+                comm_ep: self.comm_ep,
+            },
+        )
+    }
+}
+
+pub struct Sender<'d, D: Driver<'d>> {
+    write_ep: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> Sender<'d, D> {
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.write_ep.write(data).await
+    }
+
+    pub async fn wait_connection(&mut self) {
+        self.write_ep.wait_enabled().await;
+    }
+}
+
+pub struct Receiver<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+}
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    pub async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.read_ep.read(data).await
+    }
+
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct LineState<'d> {
+    shared: &'d ControlShared,
+}
+
+impl LineState<'_> {
+    pub fn line_coding(&self) -> LineCoding {
+        self.shared.line_coding.lock(|current| current.get())
+    }
+
+    /// Waits until the host sets a line coding, even if it didn't change.
+    /// Only one task may wait.
+    pub async fn line_coding_changed(&self) {
+        self.shared.line_coding_changed.wait().await;
+    }
+
+    pub fn control_lines(&self) -> ControlLines {
+        self.shared.control_lines.lock(|current| current.get())
+    }
+
+    /// Waits until the host sets the control lines or resets the device.
+    /// Only one task may wait.
+    pub async fn control_lines_changed(&self) {
+        self.shared.control_lines_changed.wait().await;
+    }
+}
+
+pub struct Notifier<'d, D: Driver<'d>> {
+    comm_if: InterfaceNumber,
+    comm_ep: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> Notifier<'d, D> {
+    pub async fn wait_connection(&mut self) {
+        self.comm_ep.wait_enabled().await;
+    }
+
+    /// Sends the `SERIAL_STATE_*` bits in `state`. Waits until the host polls
+    /// the endpoint, which it only does while the port is open.
+    pub async fn serial_state(&mut self, state: u16) -> Result<(), EndpointError> {
+        let [state_low, state_high] = state.to_le_bytes();
+        let notification = [
+            0xA1, // Device to host, class, interface
+            NOTIFICATION_SERIAL_STATE,
+            0x00,
+            0x00,
+            u8::from(self.comm_if),
+            0x00,
+            0x02, // Length of the state
+            0x00,
+            state_low,
+            state_high,
+        ];
+        self.comm_ep.write(&notification).await
+    }
+}
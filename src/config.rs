@@ -0,0 +1,160 @@
+//! Vendor defined HID interface that lets a host tool read and write the keymap.
+//!
+//! The message encoding lives in the `oskar-protocol` crate, which is shared
+//! with `oskarctl`.
+
+use crate::hid::{Key, KEYMAP, NUM_KEYS};
+use crate::layouts::NUM_LAYERS;
+use crate::macros::MACROS;
+use crate::{storage, DeviceMode};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::hid::HidReaderWriter;
+use oskar_protocol::macros;
+use oskar_protocol::{Action, Request, Response, Status, LAYOUT_CHUNK_KEYS, PROTOCOL_VERSION};
+
+pub const REPORT_SIZE: usize = oskar_protocol::REPORT_SIZE;
+
+type ConfigHid = HidReaderWriter<'static, Driver<'static, USB>, REPORT_SIZE, REPORT_SIZE>;
+
+pub const CONFIG_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61, //       Usage (0x61)
+    0xA1, 0x01, //       Collection (Application)
+    0x09, 0x62, //         Usage (0x62)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //         Report Size (8)
+    0x95, REPORT_SIZE as u8, // Report Count (64)
+    0x81, 0x02, //         Input (Data, Variable, Absolute)
+    0x09, 0x63, //         Usage (0x63)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //         Report Size (8)
+    0x95, REPORT_SIZE as u8, // Report Count (64)
+    0x91, 0x02, //         Output (Data, Variable, Absolute)
+    0xC0, //             End Collection
+];
+
+impl From<DeviceMode> for oskar_protocol::DeviceMode {
+    fn from(val: DeviceMode) -> Self {
+        match val {
+            DeviceMode::Keyboard => oskar_protocol::DeviceMode::Keyboard,
+            DeviceMode::Picoprog => oskar_protocol::DeviceMode::Picoprog,
+            DeviceMode::Universal => oskar_protocol::DeviceMode::Universal,
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn config_task(class: ConfigHid, mode: DeviceMode) -> ! {
+    let (mut reader, mut writer) = class.split();
+    let mut report = [0u8; REPORT_SIZE];
+
+    loop {
+        reader.ready().await;
+
+        if let Err(e) = reader.read(&mut report).await {
+            log::error!("Failed to read config request: {:?}", e);
+            continue;
+        }
+
+        let request = Request::decode(&report);
+        let result = match request {
+            Ok(request) => execute(request, mode),
+            Err(e) => Err(Status::from(e)),
+        };
+
+        if let Err(e) = writer.write(&Response::encode(report[1], &result)).await {
+            log::error!("Failed to send config response: {:?}", e);
+        }
+
+        if let Ok(Request::RebootBootloader) = request {
+            // Give the host a chance to fetch the response before we drop off the bus.
+            Timer::after(Duration::from_millis(50)).await;
+            embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+        }
+    }
+}
+
+fn execute(request: Request, mode: DeviceMode) -> Result<Response, Status> {
+    let response = match request {
+        Request::GetVersion => Response::Version {
+            protocol: PROTOCOL_VERSION,
+            major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+            minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+            patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+        },
+        Request::GetInfo => Response::Info {
+            mode: mode.into(),
+            num_layers: NUM_LAYERS as u8,
+            num_keys: NUM_KEYS as u8,
+        },
+        Request::GetKey { layer, key } => {
+            let (layer, key) = parse_position(layer, key)?;
+            let action = KEYMAP.lock(|keymap| keymap.borrow()[layer].get(key));
+            Response::Key {
+                layer: layer as u8,
+                key: key as u8,
+                action: action.into(),
+            }
+        }
+        Request::SetKey { layer, key, action } => {
+            let (layer, key) = parse_position(layer, key)?;
+            KEYMAP.lock(|keymap| keymap.borrow_mut()[layer].set(key, action.into()));
+            Response::Done
+        }
+        Request::GetLayout { layer, first } => {
+            let layer = layer as usize;
+            let first = first as usize;
+            if layer >= NUM_LAYERS || first >= NUM_KEYS {
+                return Err(Status::InvalidArgument);
+            }
+            let layout = KEYMAP.lock(|keymap| keymap.borrow()[layer]);
+            let mut actions = [Action::Transparent; LAYOUT_CHUNK_KEYS];
+            for (action, key) in actions.iter_mut().zip(&Key::ALL[first..]) {
+                *action = layout.get(*key).into();
+            }
+            Response::Layout {
+                layer: layer as u8,
+                first: first as u8,
+                actions,
+            }
+        }
+        Request::Save => {
+            if let Err(e) = storage::save() {
+                log::error!("Failed to save config: {:?}", e);
+                return Err(Status::FlashError);
+            }
+            Response::Done
+        }
+        Request::ResetDefaults => {
+            storage::reset_defaults();
+            Response::Done
+        }
+        Request::GetMacros { offset } => {
+            let data = MACROS.lock(|buf| macros::read_chunk(&*buf.borrow(), offset as usize));
+            Response::Macros {
+                offset,
+                data: data.ok_or(Status::InvalidArgument)?,
+            }
+        }
+        Request::SetMacros { offset, len, data } => {
+            MACROS.lock(|buf| macros::write_chunk(&mut *buf.borrow_mut(), offset as usize, &data[..len as usize]))
+                .ok_or(Status::InvalidArgument)?;
+            Response::Done
+        }
+        Request::RebootBootloader => Response::Done,
+    };
+    Ok(response)
+}
+
+fn parse_position(layer: u8, key: u8) -> Result<(usize, Key), Status> {
+    let layer = layer as usize;
+    let index = key as usize;
+    if layer >= NUM_LAYERS || index >= NUM_KEYS {
+        return Err(Status::InvalidArgument);
+    }
+    Ok((layer, Key::ALL[index]))
+}
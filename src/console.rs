@@ -0,0 +1,215 @@
+//! Debug console on its own CDC ACM interface.
+//!
+//! `log` records are kept in a ring buffer and streamed to the console while
+//! a terminal is attached. Without a terminal the oldest records are
+//! overwritten. Every record goes to defmt as well. Input goes to the shell,
+//! see `shell`.
+
+use crate::input::{self, InputEvent};
+use crate::{shell, DeviceMode};
+use core::cell::{Cell, RefCell};
+use core::fmt::{self, Write};
+use defmt::Display2Format;
+use embassy_futures::select::select;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, Receiver, Sender};
+use embassy_usb::driver::EndpointError;
+use heapless::String;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use oskar_protocol::shell::{parse, LineEditor};
+
+/// Bytes of console output kept until a terminal reads them.
+const BUFFER_SIZE: usize = 2048;
+
+/// Longer records are cut off.
+const LINE_SIZE: usize = 160;
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+const PROMPT: &[u8] = b"> ";
+
+type ConsoleDriver = Driver<'static, USB>;
+
+struct LogBuffer {
+    data: [u8; BUFFER_SIZE],
+    start: usize,
+    len: usize,
+    /// Bytes overwritten before they were read.
+    lost: usize,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        LogBuffer {
+            data: [0; BUFFER_SIZE],
+            start: 0,
+            len: 0,
+            lost: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len == BUFFER_SIZE {
+                self.start = (self.start + 1) % BUFFER_SIZE;
+                self.len -= 1;
+                self.lost += 1;
+            }
+            self.data[(self.start + self.len) % BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let len = self.len.min(buf.len());
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.data[(self.start + i) % BUFFER_SIZE];
+        }
+        self.start = (self.start + len) % BUFFER_SIZE;
+        self.len -= len;
+        len
+    }
+}
+
+static BUFFER: Mutex<CriticalSectionRawMutex, RefCell<LogBuffer>> = Mutex::new(RefCell::new(LogBuffer::new()));
+static PENDING: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static LEVEL: Mutex<CriticalSectionRawMutex, Cell<LevelFilter>> = Mutex::new(Cell::new(DEFAULT_LEVEL));
+
+/// Installs the logger. Has to be called once from `main`, before anything logs.
+pub fn init() {
+    static LOGGER: ConsoleLogger = ConsoleLogger;
+    // Safe as long as no other task or interrupt handler runs yet. The target
+    // has no atomic compare and swap, so `set_logger` is not available.
+    unsafe {
+        let _ = log::set_logger_racy(&LOGGER);
+    }
+    // Filtering happens in `ConsoleLogger`, so the level can change at runtime.
+    log::set_max_level(LevelFilter::Trace);
+}
+
+/// Most verbose level that is logged.
+pub fn level() -> LevelFilter {
+    LEVEL.lock(|level| level.get())
+}
+
+pub fn set_level(filter: LevelFilter) {
+    LEVEL.lock(|level| level.set(filter));
+}
+
+/// Appends text to the console output.
+pub fn write(bytes: &[u8]) {
+    BUFFER.lock(|buffer| buffer.borrow_mut().push(bytes));
+    PENDING.signal(());
+}
+
+/// Formats into the console output.
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(s.as_bytes());
+        Ok(())
+    }
+}
+
+struct ConsoleLogger;
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match record.level() {
+            Level::Error => defmt::error!("{}", Display2Format(record.args())),
+            Level::Warn => defmt::warn!("{}", Display2Format(record.args())),
+            Level::Info => defmt::info!("{}", Display2Format(record.args())),
+            Level::Debug => defmt::debug!("{}", Display2Format(record.args())),
+            Level::Trace => defmt::trace!("{}", Display2Format(record.args())),
+        }
+
+        let mut line: String<LINE_SIZE> = String::new();
+        let now = Instant::now().as_millis();
+        let _ = write!(line, "{}.{:03} {:<5} {}", now / 1000, now % 1000, record.level(), record.args());
+        write(line.as_bytes());
+        write(b"\r\n");
+    }
+
+    fn flush(&self) {}
+}
+
+#[embassy_executor::task]
+pub async fn console_task(class: CdcAcmClass<'static, ConsoleDriver>, mode: DeviceMode) -> ! {
+    let (mut sender, mut receiver) = class.split();
+    loop {
+        sender.wait_connection().await;
+        // Both only return once the device is disconnected.
+        let _ = select(send(&mut sender), receive(&mut receiver, mode)).await;
+    }
+}
+
+/// Logs every key event at debug level, for checking the keys and the encoder.
+#[embassy_executor::task]
+pub async fn key_log_task() -> ! {
+    static INPUT: input::Subscriber = input::Subscriber::new();
+    input::subscribe(&INPUT);
+    loop {
+        let InputEvent { key, event, at } = INPUT.next().await;
+        log::debug!("{:?} {:?} at {} ms", key, event, at.as_millis());
+    }
+}
+
+/// Streams the buffered output.
+async fn send(sender: &mut Sender<'static, ConsoleDriver>) -> Result<(), EndpointError> {
+    let mut packet = [0u8; 64];
+    loop {
+        let (len, lost) = BUFFER.lock(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            (buffer.pop(&mut packet), core::mem::take(&mut buffer.lost))
+        });
+
+        if lost > 0 {
+            let mut note: String<64> = String::new();
+            let _ = write!(note, "\r\n[{} bytes lost]\r\n", lost);
+            sender.write_packet(note.as_bytes()).await?;
+        }
+
+        if len == 0 {
+            PENDING.wait().await;
+            continue;
+        }
+        sender.write_packet(&packet[..len]).await?;
+    }
+}
+
+/// Runs the shell on the typed lines.
+async fn receive(receiver: &mut Receiver<'static, ConsoleDriver>, mode: DeviceMode) -> Result<(), EndpointError> {
+    let mut editor = LineEditor::new();
+    let mut buf = [0u8; 64];
+    write(PROMPT);
+    loop {
+        let len = receiver.read_packet(&mut buf).await?;
+        for &byte in &buf[..len] {
+            if !editor.feed(byte, &mut write) {
+                continue;
+            }
+            match parse(editor.take_line()) {
+                Ok(Some(command)) => shell::execute(command, mode).await,
+                Ok(None) => {}
+                Err(e) => {
+                    let _ = write!(Writer, "{}\r\n", e);
+                }
+            }
+            write(PROMPT);
+        }
+    }
+}
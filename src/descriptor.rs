@@ -0,0 +1,102 @@
+//! Report descriptor of the media interface. Consumer control, system control
+//! and mouse share one interface and are told apart by their report ID, USB
+//! interfaces are scarce with the CDC interfaces of universal mode.
+
+use crate::mouse::MOUSE_REPORT_ID;
+
+pub const CONSUMER_REPORT_ID: u8 = 0x01;
+pub const SYSTEM_REPORT_ID: u8 = 0x03;
+
+pub const MEDIA_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C, //             Usage Page (Consumer)
+    0x09, 0x01, //             Usage (Consumer Control)
+    0xA1, 0x01, //             Collection (Application)
+    0x85, CONSUMER_REPORT_ID, // Report ID (1)
+    0x19, 0x00, //               Usage Minimum (0)
+    0x2A, 0xFF, 0xFF, //         Usage Maximum (0xFFFF)
+    0x15, 0x00, //               Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, // Logical Maximum (0xFFFF)
+    0x75, 0x10, //               Report Size (16)
+    0x95, 0x01, //               Report Count (1)
+    0x81, 0x00, //               Input (Data, Array, Absolute)
+    0xC0, //                   End Collection
+    0x05, 0x01, //             Usage Page (Generic Desktop)
+    0x09, 0x80, //             Usage (System Control)
+    0xA1, 0x01, //             Collection (Application)
+    0x85, SYSTEM_REPORT_ID, //   Report ID (3)
+    0x19, 0x81, //               Usage Minimum (System Power Down)
+    0x29, 0xB7, //               Usage Maximum (System Display LCD Autoscale)
+    0x16, 0x81, 0x00, //         Logical Minimum (0x81)
+    0x26, 0xB7, 0x00, //         Logical Maximum (0xB7)
+    0x75, 0x08, //               Report Size (8)
+    0x95, 0x01, //               Report Count (1)
+    0x81, 0x00, //               Input (Data, Array, Absolute)
+    0xC0, //                   End Collection
+    0x05, 0x01, //             Usage Page (Generic Desktop)
+    0x09, 0x02, //             Usage (Mouse)
+    0xA1, 0x01, //             Collection (Application)
+    0x85, MOUSE_REPORT_ID, //    Report ID (2)
+    0x09, 0x01, //               Usage (Pointer)
+    0xA1, 0x00, //               Collection (Physical)
+    0x05, 0x09, //                 Usage Page (Button)
+    0x19, 0x01, //                 Usage Minimum (1)
+    0x29, 0x05, //                 Usage Maximum (5)
+    0x15, 0x00, //                 Logical Minimum (0)
+    0x25, 0x01, //                 Logical Maximum (1)
+    0x75, 0x01, //                 Report Size (1)
+    0x95, 0x05, //                 Report Count (5)
+    0x81, 0x02, //                 Input (Data, Variable, Absolute)
+    0x75, 0x03, //                 Report Size (3)
+    0x95, 0x01, //                 Report Count (1)
+    0x81, 0x01, //                 Input (Constant)
+    0x05, 0x01, //                 Usage Page (Generic Desktop)
+    0x09, 0x30, //                 Usage (X)
+    0x09, 0x31, //                 Usage (Y)
+    0x15, 0x81, //                 Logical Minimum (-127)
+    0x25, 0x7F, //                 Logical Maximum (127)
+    0x75, 0x08, //                 Report Size (8)
+    0x95, 0x02, //                 Report Count (2)
+    0x81, 0x06, //                 Input (Data, Variable, Relative)
+    0xA1, 0x02, //                 Collection (Logical)
+    0x09, 0x48, //                   Usage (Resolution Multiplier)
+    0x15, 0x00, //                   Logical Minimum (0)
+    0x25, 0x01, //                   Logical Maximum (1)
+    0x35, 0x01, //                   Physical Minimum (1)
+    0x45, 0x78, //                   Physical Maximum (120)
+    0x75, 0x02, //                   Report Size (2)
+    0x95, 0x01, //                   Report Count (1)
+    0xB1, 0x02, //                   Feature (Data, Variable, Absolute)
+    0x35, 0x00, //                   Physical Minimum (0)
+    0x45, 0x00, //                   Physical Maximum (0)
+    0x09, 0x38, //                   Usage (Wheel)
+    0x16, 0x01, 0x80, //             Logical Minimum (-32767)
+    0x26, 0xFF, 0x7F, //             Logical Maximum (32767)
+    0x75, 0x10, //                   Report Size (16)
+    0x95, 0x01, //                   Report Count (1)
+    0x81, 0x06, //                   Input (Data, Variable, Relative)
+    0xC0, //                         End Collection
+    0xA1, 0x02, //                 Collection (Logical)
+    0x09, 0x48, //                   Usage (Resolution Multiplier)
+    0x15, 0x00, //                   Logical Minimum (0)
+    0x25, 0x01, //                   Logical Maximum (1)
+    0x35, 0x01, //                   Physical Minimum (1)
+    0x45, 0x78, //                   Physical Maximum (120)
+    0x75, 0x02, //                   Report Size (2)
+    0x95, 0x01, //                   Report Count (1)
+    0xB1, 0x02, //                   Feature (Data, Variable, Absolute)
+    0x35, 0x00, //                   Physical Minimum (0)
+    0x45, 0x00, //                   Physical Maximum (0)
+    0x05, 0x0C, //                   Usage Page (Consumer)
+    0x0A, 0x38, 0x02, //             Usage (AC Pan)
+    0x16, 0x01, 0x80, //             Logical Minimum (-32767)
+    0x26, 0xFF, 0x7F, //             Logical Maximum (32767)
+    0x75, 0x10, //                   Report Size (16)
+    0x95, 0x01, //                   Report Count (1)
+    0x81, 0x06, //                   Input (Data, Variable, Relative)
+    0xC0, //                         End Collection
+    0x75, 0x04, //                 Report Size (4)
+    0x95, 0x01, //                 Report Count (1)
+    0xB1, 0x01, //                 Feature (Constant)
+    0xC0, //                     End Collection
+    0xC0, //                   End Collection
+];
@@ -0,0 +1,134 @@
+use crate::hid::{Event, Key};
+use heapless::Vec;
+
+pub use oskar_input::encoder::{AccelerationStep, Accelerator, Direction, PendingSteps};
+
+/// Movement for every transition, indexed by `previous_state << 2 | state`
+/// where a state is `a << 1 | b`. Transitions that skip a state are invalid
+/// and count as no movement.
+const TRANSITIONS: [i8; 16] = [0, 1, -1, 0, -1, 0, 0, 1, 1, 0, 0, -1, 0, -1, 1, 0];
+
+/// State of both pins at a detent, both are pulled high.
+const REST_STATE: u8 = 0b11;
+
+#[derive(Clone, Copy)]
+pub struct EncoderConfig {
+    /// Quadrature states per detent, 4 for a full cycle per detent.
+    pub steps_per_detent: u8,
+    /// Swaps the direction.
+    pub invert: bool,
+    /// Acceleration curve, sorted by `interval`. Empty to disable acceleration.
+    pub acceleration: &'static [AccelerationStep],
+}
+
+/// State table based quadrature decoder.
+///
+/// Bounce on one pin just moves back and forth between two neighbouring
+/// states, which cancels out, so no extra debouncing is needed.
+pub struct QuadratureDecoder {
+    config: EncoderConfig,
+    state: u8,
+    steps: i8,
+}
+
+impl QuadratureDecoder {
+    pub const fn new(config: EncoderConfig) -> Self {
+        QuadratureDecoder {
+            config,
+            state: REST_STATE,
+            steps: 0,
+        }
+    }
+
+    /// Takes the current pin levels, returns the direction once a full detent was turned.
+    pub fn update(&mut self, a: bool, b: bool) -> Option<Direction> {
+        let state = (a as u8) << 1 | b as u8;
+        self.steps += TRANSITIONS[(self.state << 2 | state) as usize];
+        self.state = state;
+
+        let steps_per_detent = self.config.steps_per_detent.max(1) as i8;
+        let direction = if self.steps >= steps_per_detent {
+            Some(Direction::Right)
+        } else if self.steps <= -steps_per_detent {
+            Some(Direction::Left)
+        } else {
+            None
+        };
+
+        // Every detent is a rest state, drop steps left over from missed
+        // transitions there to get back in sync.
+        if direction.is_some() || state == REST_STATE {
+            self.steps = 0;
+        }
+
+        match (direction, self.config.invert) {
+            (Some(Direction::Left), true) => Some(Direction::Right),
+            (Some(Direction::Right), true) => Some(Direction::Left),
+            (direction, _) => direction,
+        }
+    }
+}
+
+/// Turns encoder steps into press-and-turn steps while the encoder button is
+/// held.
+///
+/// While press-and-turn is bound, a press of the button that only acts when
+/// pressed, e.g. a media key, is held back until it is released, and dropped
+/// if the encoder was turned meanwhile. Actions that act while held, like a
+/// layer or a tap-hold key, are pressed right away and stay pressed while
+/// the encoder is turned.
+pub struct PressTurn {
+    held: bool,
+    deferred: bool,
+    turned: bool,
+}
+
+impl PressTurn {
+    pub const fn new() -> Self {
+        PressTurn {
+            held: false,
+            deferred: false,
+            turned: false,
+        }
+    }
+
+    /// Takes an input event, the mask of bound press-and-turn keys
+    /// (`1 << key as u8`) and whether the button's action acts while held,
+    /// returns the events to handle instead.
+    pub fn update(&mut self, key: Key, event: Event, bound: u8, button_held: bool) -> Vec<(Key, Event), 2> {
+        let mut events = Vec::new();
+        match (key, event) {
+            (Key::EncoderButton, Event::Pressed) => {
+                self.held = true;
+                self.turned = false;
+                self.deferred = bound != 0 && !button_held;
+                if !self.deferred {
+                    let _ = events.push((key, event));
+                }
+            }
+            (Key::EncoderButton, Event::Released) => {
+                self.held = false;
+                if core::mem::take(&mut self.deferred) {
+                    if self.turned {
+                        return events;
+                    }
+                    let _ = events.push((key, Event::Pressed));
+                }
+                let _ = events.push((key, event));
+            }
+            (Key::EncoderLeft | Key::EncoderRight, _) => {
+                let pressed_key = if key == Key::EncoderLeft { Key::EncoderPressedLeft } else { Key::EncoderPressedRight };
+                if self.held && bound & 1 << pressed_key as u8 != 0 {
+                    self.turned = true;
+                    let _ = events.push((pressed_key, event));
+                } else {
+                    let _ = events.push((key, event));
+                }
+            }
+            _ => {
+                let _ = events.push((key, event));
+            }
+        }
+        events
+    }
+}
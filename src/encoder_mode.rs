@@ -0,0 +1,87 @@
+//! Modes of the encoder, cycled with `KeyType::NextEncoderMode`.
+//!
+//! Encoder keys bound to `KeyType::EncoderMode` send the binding of the
+//! current mode, which is shown in its color on an LED and stored in flash.
+
+use crate::hid::{Key, KeyType, ENCODER_MODES};
+use crate::led::{self, LedEvent};
+use crate::storage;
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use smart_leds::RGB8;
+
+/// Time without mode changes before the mode is written to flash, so
+/// cycling through the modes only writes the one it stops at.
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy)]
+pub struct EncoderMode {
+    /// Sent when turning left and right.
+    pub left: KeyType,
+    pub right: KeyType,
+    /// Color of the mode on the encoder mode LED.
+    pub color: RGB8,
+}
+
+static CURRENT: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// Time at which the current mode has to be saved, if it changed.
+static SAVE_AT: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> = Mutex::new(Cell::new(None));
+
+/// Index of the current mode in `ENCODER_MODES`.
+pub fn current() -> u8 {
+    CURRENT.lock(|current| current.get())
+}
+
+/// Selects the mode stored in flash, unknown modes select the first one.
+pub fn restore(index: u8) {
+    let index = if (index as usize) < ENCODER_MODES.len() { index } else { 0 };
+    CURRENT.lock(|current| current.set(index));
+}
+
+/// Switches to the next mode and shows it.
+pub fn next(now: Instant) {
+    let index = ((current() as usize + 1) % ENCODER_MODES.len()) as u8;
+    CURRENT.lock(|current| current.set(index));
+    SAVE_AT.lock(|save_at| save_at.set(Some(now + SAVE_DELAY)));
+    show();
+}
+
+/// Sends the color of the current mode to `led_task`.
+pub fn show() {
+    let Some(mode) = ENCODER_MODES.get(current() as usize) else {
+        return;
+    };
+    if led::LED_EVENTS.try_send(LedEvent::EncoderMode(mode.color)).is_err() {
+        defmt::warn!("LED event queue is full");
+    }
+}
+
+/// What the encoder key `key` sends in the current mode.
+pub fn binding(key: Key) -> KeyType {
+    match ENCODER_MODES.get(current() as usize) {
+        Some(mode) => match key {
+            Key::EncoderLeft | Key::EncoderPressedLeft => mode.left,
+            _ => mode.right,
+        },
+        None => KeyType::Transparent,
+    }
+}
+
+/// Time at which `save` has to be called.
+pub fn save_deadline() -> Option<Instant> {
+    SAVE_AT.lock(|save_at| save_at.get())
+}
+
+/// Writes the current mode to flash once `SAVE_DELAY` passed after the last change.
+pub fn save(now: Instant) {
+    if !save_deadline().is_some_and(|deadline| deadline <= now) {
+        return;
+    }
+    SAVE_AT.lock(|save_at| save_at.set(None));
+    if let Err(e) = storage::save_encoder_mode(current()) {
+        log::error!("Failed to save the encoder mode: {:?}", e);
+    }
+}
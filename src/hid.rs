@@ -1,60 +1,235 @@
 use crate::{EncoderResources, ButtonResources};
-use crate::layouts::{KeyLayout};
-use defmt::unreachable;
+use crate::descriptor::{CONSUMER_REPORT_ID, SYSTEM_REPORT_ID};
+use crate::encoder::{AccelerationStep, Accelerator, Direction, EncoderConfig, PendingSteps, PressTurn, QuadratureDecoder};
+use crate::encoder_mode::{self, EncoderMode};
+use crate::input::{self, InputEvent};
+use crate::keyboard;
+use crate::layouts::{KeyLayout, Keymap, LayerState};
+use crate::macros::{MacroEvent, MacroPlayer, MACROS};
+use crate::mouse::{self, MouseKeys};
+use crate::report::ReportState;
+use crate::usb;
 use defmt_rtt as _;
 use embassy_executor::{InterruptExecutor, Spawner};
-use embassy_futures::select::select_array;
-use embassy_rp::gpio::{Input, Level, Pull};
+use embassy_futures::select::{select, select_array, Either};
+use embassy_rp::gpio::{Input, Pull};
 use embassy_rp::interrupt;
 use embassy_rp::interrupt::{InterruptExt, Priority};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::Driver;
 use embassy_usb::class::hid::HidReaderWriter;
 use usbd_hid::descriptor::*;
-use embassy_sync::pubsub::PubSubChannel;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use core::cell::RefCell;
+use oskar_input::combo::{ComboEvent, ComboState};
+use oskar_input::debounce::{Debounce, Debouncer};
+use oskar_input::tap_hold::{TapHoldEvent, TapHoldState};
+use oskar_protocol::{Action, BasicAction, TapHold};
+use oskar_protocol::macros::{find_macro, MACRO_BUFFER_SIZE};
+use embassy_time::{Duration, Instant, Timer};
+use smart_leds::RGB8;
 type CustomHid = HidReaderWriter<'static, Driver<'static, USB>, 1, 8>;
-static KEY_EVENT_QUEUE: PubSubChannel::<CriticalSectionRawMutex, KeyEvent, 2, 2, 2> = PubSubChannel::new();
-
-#[derive(Clone)]
-#[derive(PartialEq)]
-enum Key {
-    EncoderLeft,
-    EncoderRight,
-    EncoderButton,
-    Key1,
-    Key2,
-    Key3,
-}
+type KeyboardHid = keyboard::KeyboardWriter;
 
-#[derive(Clone)]
-#[derive(PartialEq)]
-enum Event {
-    Pressed,
-    Released,
-}
-#[derive(Clone)]
-struct KeyEvent {
-    key: Key,
-    event: Event,
-}
+pub use oskar_input::{Event, Key, NUM_KEYS};
 
+pub type TapDance = oskar_input::tap_hold::TapDance<KeyType>;
+pub type TapDanceStep = oskar_input::tap_hold::TapDanceStep<KeyType>;
+pub type ComboConfig = oskar_input::combo::ComboConfig<KeyType>;
+
+#[derive(Clone, Copy)]
 pub enum KeyType {
     Media(MediaKey),
     Keycode(KeyboardUsage),
+    /// Modifier mask (see `MOD_*`) plus up to six keycodes sent together,
+    /// unused keycode slots are 0. Build it with `chord`.
+    Chord { modifiers: u8, keycodes: [u8; 6] },
+    /// Plays the macro with this index from `macros::MACROS` when pressed.
+    Macro(u8),
+    /// Falls through to the next active layer below.
+    Transparent,
+    /// Activates the layer while the key is held.
+    MomentaryLayer(u8),
+    /// Switches the layer on or off with each press.
+    ToggleLayer(u8),
+    /// Activates the layer for the next key press only.
+    OneShotLayer(u8),
+    /// Replaces the base layer that all other layers fall through to.
+    DefaultLayer(u8),
+    /// Sends one action when tapped and another one when held, see `tap_hold`.
+    TapHold(TapHold),
+    /// Holds the mouse buttons in this bit mask, see `MOUSE_*`.
+    MouseButton(u8),
+    /// Moves the cursor repeatedly while held, speeding up over time.
+    MouseMove { x: i8, y: i8 },
+    /// Scrolls by this many notches per press or encoder step, positive is up.
+    Wheel(i8),
+    /// Scrolls horizontally by this many notches, positive is right.
+    Pan(i8),
+    /// Any Consumer page usage ID, for usages missing from `MediaKey`.
+    Consumer(u16),
+    /// System Control usage ID, see `SYSTEM_*`.
+    System(u8),
+    /// Sends `on` while any of the host's locks in the `lock` mask is on,
+    /// `off` otherwise, see `keyboard::LOCK_*`.
+    IfLock { lock: u8, on: BasicAction, off: BasicAction },
+    /// Sends a different action per number of taps, as defined by the tap
+    /// dance with this index in `TAP_DANCES`.
+    TapDance(u8),
+    /// Sends the binding of the current encoder mode, see `ENCODER_MODES`.
+    /// Bound to the encoder keys, the direction comes from the key.
+    EncoderMode,
+    /// Switches to the next of the `ENCODER_MODES`.
+    NextEncoderMode,
 }
 
-const KEYLAYOUT:KeyLayout = KeyLayout {
-    encoder_left: KeyType::Media(MediaKey::VolumeDecrement),
-    encoder_right: KeyType::Media(MediaKey::VolumeIncrement),
-    encoder_button: KeyType::Media(MediaKey::Mute),
-    key1: KeyType::Keycode(KeyboardUsage::KeyboardOo),
-    key2: KeyType::Keycode(KeyboardUsage::KeyboardSs),
-    key3: KeyType::Keycode(KeyboardUsage::KeyboardFf),
-};
+pub const MOD_LCTRL: u8 = 0x01;
+pub const MOD_LSHIFT: u8 = 0x02;
+pub const MOD_LALT: u8 = 0x04;
+pub const MOD_LGUI: u8 = 0x08;
+pub const MOD_RCTRL: u8 = 0x10;
+pub const MOD_RSHIFT: u8 = 0x20;
+pub const MOD_RALT: u8 = 0x40;
+pub const MOD_RGUI: u8 = 0x80;
+
+pub const MOUSE_LEFT: u8 = 0x01;
+pub const MOUSE_RIGHT: u8 = 0x02;
+pub const MOUSE_MIDDLE: u8 = 0x04;
+pub const MOUSE_BACK: u8 = 0x08;
+pub const MOUSE_FORWARD: u8 = 0x10;
+
+pub const SYSTEM_POWER_DOWN: u8 = 0x81;
+pub const SYSTEM_SLEEP: u8 = 0x82;
+pub const SYSTEM_WAKE_UP: u8 = 0x83;
+
+/// Builds a `KeyType::Chord`, e.g. `chord(MOD_LCTRL | MOD_LSHIFT, &[KeyboardUsage::KeyboardPp])`.
+/// Keys beyond the sixth are ignored.
+pub const fn chord(modifiers: u8, keys: &[KeyboardUsage]) -> KeyType {
+    let mut keycodes = [0u8; 6];
+    let mut i = 0;
+    while i < keys.len() && i < keycodes.len() {
+        keycodes[i] = keys[i] as u8;
+        i += 1;
+    }
+    KeyType::Chord { modifiers, keycodes }
+}
+
+/// Builds a `KeyType::TapHold` with the default term, e.g.
+/// `tap_hold(BasicAction::Media(MediaKey::PlayPause as u8), BasicAction::MomentaryLayer(1))`.
+pub const fn tap_hold(tap: BasicAction, hold: BasicAction) -> KeyType {
+    KeyType::TapHold(TapHold::new(tap, hold))
+}
+
+impl KeyType {
+    /// Whether the action does something for as long as the key is held,
+    /// like a layer or a modifier, instead of only when it is pressed.
+    pub const fn acts_while_held(self) -> bool {
+        match self {
+            KeyType::MomentaryLayer(_) | KeyType::TapHold(_) | KeyType::TapDance(_) => true,
+            KeyType::MouseButton(_) | KeyType::MouseMove { .. } => true,
+            KeyType::Chord { keycodes, .. } => keycodes[0] == 0,
+            _ => false,
+        }
+    }
+}
+
+impl From<BasicAction> for KeyType {
+    fn from(val: BasicAction) -> Self {
+        match val {
+            BasicAction::Keycode(code) => KeyType::Keycode(KeyboardUsage::from(code)),
+            BasicAction::Media(code) => KeyType::Media(MediaKey::from(code)),
+            BasicAction::Modifiers(modifiers) => KeyType::Chord { modifiers, keycodes: [0; 6] },
+            BasicAction::Macro(index) => KeyType::Macro(index),
+            BasicAction::MomentaryLayer(layer) => KeyType::MomentaryLayer(layer),
+            BasicAction::ToggleLayer(layer) => KeyType::ToggleLayer(layer),
+            BasicAction::OneShotLayer(layer) => KeyType::OneShotLayer(layer),
+            BasicAction::DefaultLayer(layer) => KeyType::DefaultLayer(layer),
+        }
+    }
+}
+
+impl From<KeyType> for Action {
+    fn from(val: KeyType) -> Self {
+        match val {
+            KeyType::Transparent => Action::Transparent,
+            KeyType::Keycode(keyboard_usage) => Action::Keycode(keyboard_usage as u8),
+            KeyType::Media(media_key) => Action::Media(media_key as u8),
+            KeyType::Chord { modifiers, keycodes } => Action::Chord { modifiers, keycodes },
+            KeyType::Macro(index) => Action::Macro(index),
+            KeyType::MomentaryLayer(layer) => Action::MomentaryLayer(layer),
+            KeyType::ToggleLayer(layer) => Action::ToggleLayer(layer),
+            KeyType::OneShotLayer(layer) => Action::OneShotLayer(layer),
+            KeyType::DefaultLayer(layer) => Action::DefaultLayer(layer),
+            KeyType::TapHold(tap_hold) => Action::TapHold(tap_hold),
+            KeyType::MouseButton(buttons) => Action::MouseButton(buttons),
+            KeyType::MouseMove { x, y } => Action::MouseMove { x, y },
+            KeyType::Wheel(notches) => Action::Wheel(notches),
+            KeyType::Pan(notches) => Action::Pan(notches),
+            KeyType::Consumer(usage) => Action::Consumer(usage),
+            KeyType::System(usage) => Action::System(usage),
+            KeyType::IfLock { lock, on, off } => Action::IfLock { lock, on, off },
+            KeyType::TapDance(index) => Action::TapDance(index),
+            KeyType::EncoderMode => Action::EncoderMode,
+            KeyType::NextEncoderMode => Action::NextEncoderMode,
+        }
+    }
+}
+
+impl From<Action> for KeyType {
+    fn from(val: Action) -> Self {
+        match val {
+            Action::Transparent => KeyType::Transparent,
+            Action::Keycode(code) => KeyType::Keycode(KeyboardUsage::from(code)),
+            Action::Media(code) => KeyType::Media(MediaKey::from(code)),
+            Action::Chord { modifiers, keycodes } => KeyType::Chord { modifiers, keycodes },
+            Action::Macro(index) => KeyType::Macro(index),
+            Action::MomentaryLayer(layer) => KeyType::MomentaryLayer(layer),
+            Action::ToggleLayer(layer) => KeyType::ToggleLayer(layer),
+            Action::OneShotLayer(layer) => KeyType::OneShotLayer(layer),
+            Action::DefaultLayer(layer) => KeyType::DefaultLayer(layer),
+            Action::TapHold(tap_hold) => KeyType::TapHold(tap_hold),
+            Action::MouseButton(buttons) => KeyType::MouseButton(buttons),
+            Action::MouseMove { x, y } => KeyType::MouseMove { x, y },
+            Action::Wheel(notches) => KeyType::Wheel(notches),
+            Action::Pan(notches) => KeyType::Pan(notches),
+            Action::Consumer(usage) => KeyType::Consumer(usage),
+            Action::System(usage) => KeyType::System(usage),
+            Action::IfLock { lock, on, off } => KeyType::IfLock { lock, on, off },
+            Action::TapDance(index) => KeyType::TapDance(index),
+            Action::EncoderMode => KeyType::EncoderMode,
+            Action::NextEncoderMode => KeyType::NextEncoderMode,
+        }
+    }
+}
+
+/// Compiled in keymap, used when no valid keymap is stored in flash.
+pub const DEFAULT_KEYMAP: Keymap = [
+    KeyLayout {
+        encoder_left: KeyType::EncoderMode,
+        encoder_right: KeyType::EncoderMode,
+        encoder_button: KeyType::Media(MediaKey::Mute),
+        key1: KeyType::Keycode(KeyboardUsage::KeyboardOo),
+        key2: KeyType::Keycode(KeyboardUsage::KeyboardSs),
+        key3: KeyType::Keycode(KeyboardUsage::KeyboardFf),
+        encoder_pressed_left: KeyType::Media(MediaKey::PrevTrack),
+        encoder_pressed_right: KeyType::Media(MediaKey::NextTrack),
+    },
+    KeyLayout::TRANSPARENT,
+    KeyLayout::TRANSPARENT,
+    KeyLayout::TRANSPARENT,
+];
+
+/// The keymap in use, loaded from flash at boot.
+pub static KEYMAP: Mutex<CriticalSectionRawMutex, RefCell<Keymap>> = Mutex::new(RefCell::new(DEFAULT_KEYMAP));
 
 #[embassy_executor::task]
-pub async fn hid_task(spawner: Spawner, mut keyboard_class: CustomHid, mut multimedia_class: CustomHid, button_resources: ButtonResources, encoder_resources: EncoderResources) -> ! {
+pub async fn hid_task(spawner: Spawner, mut keyboard_class: KeyboardHid, mut multimedia_class: CustomHid, button_resources: ButtonResources, encoder_resources: EncoderResources) -> ! {
+
+    // Subscribe before the producers start so no event is missed.
+    static INPUT: input::Subscriber = input::Subscriber::new();
+    input::subscribe(&INPUT);
+    let mut overflows = 0;
 
     interrupt::SWI_IRQ_0.set_priority(Priority::P2);
     let spawner_encoder: embassy_executor::SendSpawner = EXECUTOR_ENCODER.start(interrupt::SWI_IRQ_0);
@@ -62,31 +237,211 @@ pub async fn hid_task(spawner: Spawner, mut keyboard_class: CustomHid, mut multi
 
     spawner.spawn(button_task(button_resources)).unwrap();
 
-    let mut sub = KEY_EVENT_QUEUE.subscriber().unwrap();
+    let mut layers = LayerState::new();
+    let mut reports = ReportState::new();
+    let mut press_turn = PressTurn::new();
+    let mut combos = ComboState::new(COMBO_CONFIG);
+    let mut tap_hold = TapHoldState::<KeyType>::new();
+    let mut mouse_keys = MouseKeys::new();
+    // Keys whose press was dropped while suspended, see `DISCARD_SUSPEND_KEYS`.
+    let mut discarded = [false; NUM_KEYS];
+
+    encoder_mode::show();
 
     loop {
-        let key_event: KeyEvent = sub.next_message_pure().await;
-
-        match key_event.key {
-            Key::EncoderLeft => {
-                (keyboard_class, multimedia_class) = handle_encoder_interaction(keyboard_class, multimedia_class, KEYLAYOUT.encoder_left).await;
-            },
-            Key::EncoderRight => {
-                (keyboard_class, multimedia_class) = handle_encoder_interaction(keyboard_class, multimedia_class, KEYLAYOUT.encoder_right).await;
-            },
-            Key::EncoderButton => {
-                (keyboard_class, multimedia_class) = send_code(keyboard_class, multimedia_class, KEYLAYOUT.encoder_button, key_event.event).await;
-            },
-            Key::Key1 => {
-                (keyboard_class, multimedia_class) = send_code(keyboard_class, multimedia_class, KEYLAYOUT.key1, key_event.event).await;
-            },
-            Key::Key2 => {
-                (keyboard_class, multimedia_class) = send_code(keyboard_class, multimedia_class, KEYLAYOUT.key2, key_event.event).await;
-            },
-            Key::Key3 => {
-                (keyboard_class, multimedia_class) = send_code(keyboard_class, multimedia_class, KEYLAYOUT.key3,key_event.event).await;
+        let key_event = if usb::suspended() {
+            // Nothing can be sent until the host resumes the bus, so only
+            // wait for keys.
+            match select(INPUT.next(), usb::wait_resumed()).await {
+                Either::First(key_event) => Some(key_event),
+                Either::Second(_) => None,
+            }
+        } else {
+            // Wake up when an undecided combo or tap-hold key times out, the cursor
+            // has to move, scrolling isn't finished, the idle rate is due or the
+            // encoder mode has to be saved, too.
+            let scroll = reports.scroll_pending().then(Instant::now);
+            let deadlines = [
+                combos.deadline(),
+                tap_hold.deadline(),
+                mouse_keys.deadline(),
+                scroll,
+                keyboard_class.idle_deadline(),
+                encoder_mode::save_deadline(),
+            ];
+            match deadlines.into_iter().flatten().min() {
+                Some(deadline) => match select(INPUT.next(), Timer::at(deadline)).await {
+                    Either::First(key_event) => Some(key_event),
+                    Either::Second(_) => None,
+                },
+                None => Some(INPUT.next().await),
             }
+        };
+
+        if INPUT.overflows() != overflows {
+            log::warn!("input queue full, dropped {} events", INPUT.overflows().wrapping_sub(overflows));
+            overflows = INPUT.overflows();
         }
+
+        // Events that still have to go through the combo and tap-hold stages.
+        let mut inputs = heapless::Vec::<(Key, Event, Instant), 2>::new();
+        if let Some(InputEvent { key, event, mut at }) = key_event {
+            if usb::suspended() {
+                usb::remote_wakeup();
+                if DISCARD_SUSPEND_KEYS {
+                    match (key, event) {
+                        (Key::EncoderLeft | Key::EncoderRight, _) => {
+                            take_encoder_steps(key);
+                            continue;
+                        }
+                        (_, Event::Pressed) => {
+                            discarded[key as usize] = true;
+                            continue;
+                        }
+                        (_, Event::Released) if core::mem::take(&mut discarded[key as usize]) => continue,
+                        // Keys held since before the suspend still have to be released.
+                        (_, Event::Released) => {}
+                    }
+                }
+                usb::wait_resumed().await;
+                // Keys queued while suspended count from the resume.
+                at = Instant::now();
+            } else if event == Event::Released && core::mem::take(&mut discarded[key as usize]) {
+                continue;
+            }
+
+            // Encoder steps while the button is held use the press-and-turn
+            // bindings if the active layers have them.
+            let (bound, button_held) = KEYMAP.lock(|keymap| {
+                let keymap = keymap.borrow();
+                let bound = [Key::EncoderPressedLeft, Key::EncoderPressedRight]
+                    .into_iter()
+                    .filter(|&key| layers.binds(&keymap, key))
+                    .fold(0, |mask, key| mask | 1 << key as u8);
+                (bound, layers.binding(&keymap, Key::EncoderButton).acts_while_held())
+            });
+            inputs = press_turn.update(key, event, bound, button_held).into_iter().map(|(key, event)| (key, event, at)).collect();
+        }
+
+        // Each stage only takes an event from the one before when it has
+        // nothing to return, which is never the case with a full queue.
+        let mut inputs = inputs.into_iter();
+        loop {
+            let Some(tap_hold_event) = tap_hold.next(Instant::now()) else {
+                let queued = if let Some(combo_event) = combos.next(Instant::now()) {
+                    match combo_event {
+                        ComboEvent::Key(key, event, at) => tap_hold.push(key, event, at),
+                        ComboEvent::Combo(key, event, code, at) => match combo_code(code) {
+                            Some(code) => tap_hold.push_resolved(key, event, code, at),
+                            None => true,
+                        },
+                    }
+                } else if let Some((key, event, at)) = inputs.next() {
+                    combos.push(key, event, at)
+                } else {
+                    break;
+                };
+                if !queued {
+                    log::error!("Combo or tap-hold queue full, dropped an event");
+                }
+                continue;
+            };
+
+            match tap_hold_event {
+                TapHoldEvent::Key(key, _, _) if key.is_encoder_step() => {
+                    // Encoder steps only report a press, release them right away so
+                    // momentary layers bound to the encoder don't get stuck.
+                    let locks = keyboard::lock_state();
+                    let code = KEYMAP.lock(|keymap| {
+                        let keymap = keymap.borrow();
+                        let code = layers.process(&keymap, locks, key, Event::Pressed);
+                        layers.process(&keymap, locks, key, Event::Released);
+                        code
+                    });
+
+                    // A step can't be held, tap-hold keys and tap dances on the encoder always tap once.
+                    let tap = match code {
+                        Some(KeyType::TapHold(config)) => Some(config.tap.into()),
+                        Some(KeyType::TapDance(index)) => Some(tap_dance(index).map_or(KeyType::Transparent, |dance| dance.steps[0].tap)),
+                        _ => None,
+                    };
+                    let code = match tap {
+                        Some(tap) => {
+                            let code = layers.apply(Event::Pressed, tap);
+                            layers.apply(Event::Released, tap);
+                            code
+                        }
+                        None => code,
+                    };
+                    let code = match code {
+                        Some(KeyType::EncoderMode) => Some(encoder_mode::binding(key)),
+                        code => code,
+                    };
+
+                    let steps = take_encoder_steps(key);
+                    match code {
+                        // Scrolling adds up, all steps go into one movement.
+                        Some(KeyType::Wheel(notches)) => {
+                            reports.scroll(mouse::wheel_units(notches) * steps as i32, 0);
+                            send_reports(&mut keyboard_class, &mut multimedia_class, &mut reports).await;
+                            continue;
+                        }
+                        Some(KeyType::Pan(notches)) => {
+                            reports.scroll(0, mouse::pan_units(notches) * steps as i32);
+                            send_reports(&mut keyboard_class, &mut multimedia_class, &mut reports).await;
+                            continue;
+                        }
+                        _ => {}
+                    }
+
+                    for _ in 0..steps.min(MAX_ENCODER_REPEATS) {
+                        if let Some(KeyType::NextEncoderMode) = code {
+                            encoder_mode::next(Instant::now());
+                        } else if let Some(KeyType::Macro(index)) = code {
+                            play_macro(&mut keyboard_class, &mut multimedia_class, &mut reports, index).await;
+                        } else if let Some(code) = code {
+                            reports.press(key, code);
+                            send_reports(&mut keyboard_class, &mut multimedia_class, &mut reports).await;
+                            reports.release(key);
+                            send_reports(&mut keyboard_class, &mut multimedia_class, &mut reports).await;
+                        }
+                    }
+                },
+                TapHoldEvent::Key(key, event, at) => {
+                    let code = KEYMAP.lock(|keymap| layers.process(&keymap.borrow(), keyboard::lock_state(), key, event));
+                    match (event, code) {
+                        (Event::Pressed, Some(KeyType::TapHold(config))) => tap_hold.start(key, config, at),
+                        (Event::Pressed, Some(KeyType::TapDance(index))) => match tap_dance(index) {
+                            Some(dance) => tap_hold.start_dance(key, dance, at),
+                            None => log::error!("Tap dance {} is not defined", index),
+                        },
+                        (Event::Released, Some(KeyType::TapHold(_) | KeyType::TapDance(_))) => {
+                            let code = tap_hold.release(key).and_then(|code| layers.apply(event, code));
+                            handle_key(&mut keyboard_class, &mut multimedia_class, &mut reports, key, event, code).await;
+                        },
+                        (event, code) => handle_key(&mut keyboard_class, &mut multimedia_class, &mut reports, key, event, code).await,
+                    }
+                },
+                TapHoldEvent::Resolved(key, event, code) => {
+                    let code = layers.apply(event, code);
+                    handle_key(&mut keyboard_class, &mut multimedia_class, &mut reports, key, event, code).await;
+                },
+            }
+        }
+
+        let speed = mouse_keys.update(reports.cursor_moving(), Instant::now());
+        if speed > 0 || reports.scroll_pending() {
+            reports.move_cursor(speed);
+            send_reports(&mut keyboard_class, &mut multimedia_class, &mut reports).await;
+        }
+
+        if keyboard_class.idle_deadline().is_some_and(|deadline| deadline <= Instant::now()) {
+            if let Err(e) = keyboard_class.repeat().await {
+                log::error!("Failed to repeat HID key report: {:?}", e);
+            }
+        }
+
+        encoder_mode::save(Instant::now());
     }
 }
 
@@ -101,22 +456,151 @@ unsafe fn SWI_IRQ_0() {
 #[embassy_executor::task]
 pub async fn encoder_task(r: EncoderResources) -> ! {
 
-    let encoder_left: Input<'_> = Input::new(r.encoder_left, Pull::None);
+    let mut encoder_left: Input<'_> = Input::new(r.encoder_left, Pull::None);
 
     let mut encoder_right: Input<'_> = Input::new(r.encoder_right, Pull::None);
 
-    let publisher = KEY_EVENT_QUEUE.publisher().unwrap();
+    let mut decoder = QuadratureDecoder::new(ENCODER_CONFIG);
+    let mut accelerator = Accelerator::new(ENCODER_CONFIG.acceleration);
 
     loop {
-        encoder_right.wait_for_falling_edge().await;
+        // Track every edge of both pins, the decoder needs to see each state.
+        select(encoder_left.wait_for_any_edge(), encoder_right.wait_for_any_edge()).await;
 
-        if encoder_left.get_level() == Level::Low {
-            publisher.publish_immediate(KeyEvent {key: Key::EncoderLeft, event: Event::Pressed});
-        } else {
-            publisher.publish_immediate(KeyEvent {key: Key::EncoderRight, event: Event::Pressed});
+        let Some(direction) = decoder.update(encoder_left.is_high(), encoder_right.is_high()) else {
+            continue;
         };
 
-        encoder_right.wait_for_rising_edge().await;
+        let now = Instant::now();
+        let steps = accelerator.steps(direction, now);
+        if !ENCODER_STEPS.lock(|pending| pending.borrow_mut()[direction as usize].add(steps)) {
+            continue;
+        }
+
+        let key = match direction {
+            Direction::Left => Key::EncoderLeft,
+            Direction::Right => Key::EncoderRight,
+        };
+        // If a queue is full the next step tries again.
+        if input::publish(key, Event::Pressed, now) {
+            ENCODER_STEPS.lock(|pending| pending.borrow_mut()[direction as usize].set_queued());
+        }
+    }
+}
+
+/// Detent resolution and direction of the rotary encoder.
+pub const ENCODER_CONFIG: EncoderConfig = EncoderConfig {
+    steps_per_detent: 4,
+    invert: false,
+    acceleration: &[
+        AccelerationStep { interval: Duration::from_millis(20), steps: 4 },
+        AccelerationStep { interval: Duration::from_millis(50), steps: 2 },
+    ],
+};
+
+/// Most times an encoder action other than scrolling is sent for the steps
+/// taken at once. Each time is a press and a release report, so a fast spin
+/// would hold up the other keys for a while; further steps are dropped.
+const MAX_ENCODER_REPEATS: u16 = 8;
+
+/// Encoder steps not yet handled by `hid_task`, per direction.
+static ENCODER_STEPS: Mutex<CriticalSectionRawMutex, RefCell<[PendingSteps; 2]>> =
+    Mutex::new(RefCell::new([PendingSteps::new(); 2]));
+
+/// Takes the steps accumulated for the encoder event `key`.
+fn take_encoder_steps(key: Key) -> u16 {
+    let direction = match key {
+        Key::EncoderLeft | Key::EncoderPressedLeft => Direction::Left,
+        _ => Direction::Right,
+    };
+    ENCODER_STEPS.lock(|pending| pending.borrow_mut()[direction as usize].take())
+}
+
+/// Modes of the encoder keys bound to `KeyType::EncoderMode`, switched with
+/// `KeyType::NextEncoderMode`. The first one is used until another is selected.
+pub const ENCODER_MODES: &[EncoderMode] = &[
+    // Volume
+    EncoderMode {
+        left: KeyType::Media(MediaKey::VolumeDecrement),
+        right: KeyType::Media(MediaKey::VolumeIncrement),
+        color: RGB8 { r: 0, g: 0, b: 10 },
+    },
+    // Scrolling
+    EncoderMode {
+        left: KeyType::Wheel(1),
+        right: KeyType::Wheel(-1),
+        color: RGB8 { r: 0, g: 10, b: 0 },
+    },
+    // Display brightness
+    EncoderMode {
+        left: KeyType::Consumer(0x0070),
+        right: KeyType::Consumer(0x006F),
+        color: RGB8 { r: 10, g: 8, b: 0 },
+    },
+    // Zoom
+    EncoderMode {
+        left: chord(MOD_LCTRL, &[KeyboardUsage::KeyboardDashUnderscore]),
+        right: chord(MOD_LCTRL, &[KeyboardUsage::KeyboardEqualPlus]),
+        color: RGB8 { r: 0, g: 8, b: 10 },
+    },
+    // Undo and redo
+    EncoderMode {
+        left: chord(MOD_LCTRL, &[KeyboardUsage::KeyboardZz]),
+        right: chord(MOD_LCTRL | MOD_LSHIFT, &[KeyboardUsage::KeyboardZz]),
+        color: RGB8 { r: 10, g: 0, b: 8 },
+    },
+];
+
+/// Tap dances referenced by `KeyType::TapDance`, by index.
+pub const TAP_DANCES: &[TapDance] = &[
+    // 0: Mute, microphone mute (F20) on a double tap and the audio output
+    // switcher of Windows 11 (Win+Ctrl+V) on a triple tap.
+    TapDance {
+        steps: &[
+            TapDanceStep { tap: KeyType::Media(MediaKey::Mute), hold: None },
+            TapDanceStep { tap: KeyType::Keycode(KeyboardUsage::KeyboardF20), hold: None },
+            TapDanceStep { tap: chord(MOD_LGUI | MOD_LCTRL, &[KeyboardUsage::KeyboardVv]), hold: None },
+        ],
+        term: Duration::from_millis(250),
+    },
+];
+
+/// Looks up a tap dance, `None` if it doesn't exist or has no steps.
+fn tap_dance(index: u8) -> Option<TapDance> {
+    TAP_DANCES.get(index as usize).filter(|dance| !dance.steps.is_empty()).copied()
+}
+
+/// What a combo sends. Combos can't wait for a tap-hold decision or count
+/// taps, they always tap once. `None` if its tap dance doesn't exist.
+fn combo_code(code: KeyType) -> Option<KeyType> {
+    match code {
+        KeyType::TapHold(config) => Some(config.tap.into()),
+        KeyType::TapDance(index) => tap_dance(index).map(|dance| dance.steps[0].tap),
+        KeyType::IfLock { lock, on, off } => Some(if keyboard::lock_state() & lock != 0 { on } else { off }.into()),
+        code => Some(code),
+    }
+}
+
+/// Keys pressed together that send their own action, see
+/// `oskar_input::combo::Combo`. Keys that are part of a combo are delayed by
+/// up to `term`, e.g.
+/// `Combo { keys: combo::keys(&[Key::Key1, Key::Key2]), code: KeyType::Keycode(KeyboardUsage::KeyboardEscape) }`.
+pub const COMBO_CONFIG: ComboConfig = ComboConfig {
+    combos: &[],
+    term: Duration::from_millis(50),
+};
+
+/// Whether keys pressed while the host suspended the bus are dropped, or
+/// handled once the host resumed it. Either way they ask the host to wake
+/// up, if it allows remote wakeup.
+pub const DISCARD_SUSPEND_KEYS: bool = true;
+
+/// Debouncing of the buttons. Encoder steps are not debounced here.
+pub const fn debounce(key: Key) -> Debounce {
+    match key {
+        // The encoder's push button chatters more than the key switches.
+        Key::EncoderButton => Debounce::deferred(10),
+        _ => Debounce::eager(5),
     }
 }
 
@@ -135,136 +619,119 @@ pub async fn button_task(r: ButtonResources) -> ! {
     let mut encoder_button: Input<'_> = Input::new(r.encoder_button, Pull::Up);
     encoder_button.set_schmitt(true);
 
-    let publisher = KEY_EVENT_QUEUE.publisher().unwrap();
+    let mut buttons = [
+        (Key::Key1, key1),
+        (Key::Key2, key2),
+        (Key::Key3, key3),
+        (Key::EncoderButton, encoder_button),
+    ];
+    let mut debouncers = buttons.each_ref().map(|(key, _)| Debouncer::new(debounce(*key)));
 
     loop {
 
-        let (_, index) = select_array([
-            key1.wait_for_any_edge(),
-            key2.wait_for_any_edge(),
-            key3.wait_for_any_edge(),
-            encoder_button.wait_for_any_edge(),
-        ])
-        .await;
-
-        match index {
-            0 => {
-                match key1.get_level() {
-                    Level::Low => publisher.publish_immediate(KeyEvent {key: Key::Key1, event: Event::Pressed}),
-                    Level::High => publisher.publish_immediate(KeyEvent {key: Key::Key1, event: Event::Released}),
-                }
-            }
-            1 => {
-                match key2.get_level() {
-                    Level::Low => publisher.publish_immediate(KeyEvent {key: Key::Key2, event: Event::Pressed}),
-                    Level::High => publisher.publish_immediate(KeyEvent {key: Key::Key2, event: Event::Released}),
-                }
+        let edges = select_array(buttons.each_mut().map(|(_, input)| input.wait_for_any_edge()));
+
+        // Wake up for changes the debouncers are still holding back, too.
+        match debouncers.iter().filter_map(|debouncer| debouncer.deadline()).min() {
+            Some(deadline) => {
+                select(edges, Timer::at(deadline)).await;
             }
-            2 => {
-                match key3.get_level() {
-                    Level::Low => publisher.publish_immediate(KeyEvent {key: Key::Key3, event: Event::Pressed}),
-                    Level::High => publisher.publish_immediate(KeyEvent {key: Key::Key3, event: Event::Released}),
-                }
+            None => {
+                edges.await;
             }
-            3 => {
-                match encoder_button.get_level() {
-                    Level::Low => publisher.publish_immediate(KeyEvent {key: Key::EncoderButton, event: Event::Pressed}),
-                    Level::High => publisher.publish_immediate(KeyEvent {key: Key::EncoderButton, event: Event::Released}),
-                }
+        }
+
+        let now = Instant::now();
+        for ((key, input), debouncer) in buttons.iter().zip(debouncers.iter_mut()) {
+            if let Some(event) = debouncer.update(input.is_low(), now) {
+                input::publish(*key, event, now);
             }
-            _ => unreachable!(),
-        };
+        }
     }
 }
 
-
-async fn handle_encoder_interaction(mut keyboard_class: CustomHid, mut media_class: CustomHid, code: KeyType) -> (CustomHid, CustomHid) {
-
-    match code {
-        KeyType::Media(media_key) =>    {
-
-            let mut report = MediaKeyboardReport {
-                usage_id: media_key as u16,
-            };
-
-            if let Err(e) = media_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
-            }
-
-            report = MediaKeyboardReport {
-                usage_id: 0x00 as u16,
-            };
-
-            if let Err(e) = media_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
-            }
+/// Sends the action a key was resolved to, `None` for keys without one.
+async fn handle_key(keyboard_class: &mut KeyboardHid, media_class: &mut CustomHid, reports: &mut ReportState, key: Key, event: Event, code: Option<KeyType>) {
+    match (event, code) {
+        (Event::Pressed, Some(KeyType::Macro(index))) => {
+            play_macro(keyboard_class, media_class, reports, index).await;
         },
+        (Event::Pressed, Some(KeyType::Wheel(notches))) => reports.scroll(mouse::wheel_units(notches), 0),
+        (Event::Pressed, Some(KeyType::Pan(notches))) => reports.scroll(0, mouse::pan_units(notches)),
+        (Event::Pressed, Some(KeyType::NextEncoderMode)) => encoder_mode::next(Instant::now()),
+        (Event::Pressed, Some(code)) => reports.press(key, code),
+        (Event::Pressed, None) => {},
+        (Event::Released, _) => reports.release(key),
+    }
+    send_reports(keyboard_class, media_class, reports).await;
+}
 
-        KeyType::Keycode(keyboard_usage) => {
-            let keycodes: [u8; 6] = [keyboard_usage as u8, 0, 0, 0, 0, 0];
-
-            let mut report: KeyboardReport = KeyboardReport {
-                keycodes: keycodes,
-                leds: 0,
-                modifier: 0,
-                reserved: 0,
-            };
-
-            if let Err(e) = keyboard_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
-            }
+/// Sends the keyboard, consumer, system and mouse reports that changed since the last call.
+async fn send_reports(keyboard_class: &mut KeyboardHid, media_class: &mut CustomHid, reports: &mut ReportState) {
+    if let Some(report) = reports.take_keyboard_report() {
+        if let Err(e) = keyboard_class.write(&report).await {
+            log::error!("Failed to send HID key press: {:?}", e);
+        }
+    }
 
-            report.keycodes = [0,0,0,0,0,0];
+    // Boot protocol hosts never poll the media interface, writes would block.
+    if keyboard::boot_protocol() {
+        reports.take_consumer_usage();
+        reports.take_system_usage();
+        reports.take_mouse_report();
+        return;
+    }
 
-            if let Err(e) = keyboard_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
-            }
-        },
-    };
+    if let Some(usage_id) = reports.take_consumer_usage() {
+        let [usage_low, usage_high] = usage_id.to_le_bytes();
 
+        if let Err(e) = media_class.write(&[CONSUMER_REPORT_ID, usage_low, usage_high]).await {
+            log::error!("Failed to send HID key press: {:?}", e);
+        }
+    }
 
+    if let Some(usage_id) = reports.take_system_usage() {
+        if let Err(e) = media_class.write(&[SYSTEM_REPORT_ID, usage_id]).await {
+            log::error!("Failed to send HID system control: {:?}", e);
+        }
+    }
 
-    return (keyboard_class, media_class)
+    if let Some(report) = reports.take_mouse_report() {
+        if let Err(e) = media_class.write(&report.to_bytes()).await {
+            log::error!("Failed to send HID mouse report: {:?}", e);
+        }
+    }
 }
 
-async fn send_code(mut keyboard_class: CustomHid, mut media_class: CustomHid , code: KeyType, event: Event) -> (CustomHid, CustomHid) {
-
-    match code {
-        KeyType::Media(media_key) =>    {
-
-            let code = match event {
-                Event::Pressed => media_key as u16,
-                Event::Released => 0x00 as u16,
-            };
-
-            let report = MediaKeyboardReport {
-                usage_id: code,
-            };
+/// Types the macro with the given index, keys held meanwhile stay pressed.
+async fn play_macro(keyboard_class: &mut KeyboardHid, media_class: &mut CustomHid, reports: &mut ReportState, index: u8) {
+    let mut buf = [0u8; MACRO_BUFFER_SIZE];
+    let len = MACROS.lock(|macros| {
+        let macros = macros.borrow();
+        let steps = find_macro(&*macros, index)?;
+        buf[..steps.len()].copy_from_slice(steps);
+        Some(steps.len())
+    });
+
+    let Some(len) = len else {
+        log::error!("Macro {} is not defined", index);
+        return;
+    };
 
-            if let Err(e) = media_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
+    for event in MacroPlayer::new(&buf[..len]) {
+        match event {
+            MacroEvent::Press(usage) => {
+                reports.macro_press(usage);
+                send_reports(keyboard_class, media_class, reports).await;
             }
-        },
-
-        KeyType::Keycode(keyboard_usage) => {
-            let keycodes: [u8; 6] = if event == Event::Pressed {
-                [keyboard_usage as u8, 0, 0, 0, 0, 0]
-            } else {
-                [0, 0, 0, 0, 0, 0]
-            };
-
-            let report: KeyboardReport = KeyboardReport {
-                keycodes: keycodes,
-                leds: 0,
-                modifier: 0,
-                reserved: 0,
-            };
-
-            if let Err(e) = keyboard_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
+            MacroEvent::Release(usage) => {
+                reports.macro_release(usage);
+                send_reports(keyboard_class, media_class, reports).await;
             }
-        },
-    };
+            MacroEvent::Wait(duration) => Timer::after(duration).await,
+        }
+    }
 
-    return (keyboard_class, media_class);
-}
\ No newline at end of file
+    reports.macro_release_all();
+    send_reports(keyboard_class, media_class, reports).await;
+}
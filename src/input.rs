@@ -0,0 +1,83 @@
+//! Input event bus from the tasks reading the buttons and the encoder to the
+//! tasks using the events.
+//!
+//! Every subscriber has its own queue, so a slow one doesn't hold up the
+//! others, and subscribers are chained into a list instead of taking slots
+//! of a fixed size channel. See `InputQueue` for what a full queue drops.
+
+use crate::hid::{Event, Key};
+use core::cell::{Cell, RefCell};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+use oskar_input::queue::InputQueue;
+
+pub use oskar_input::queue::InputEvent;
+
+pub struct Subscriber {
+    queue: Mutex<CriticalSectionRawMutex, RefCell<InputQueue>>,
+    signal: Signal<CriticalSectionRawMutex, ()>,
+    next: Mutex<CriticalSectionRawMutex, Cell<Option<&'static Subscriber>>>,
+}
+
+static SUBSCRIBERS: Mutex<CriticalSectionRawMutex, Cell<Option<&'static Subscriber>>> = Mutex::new(Cell::new(None));
+
+/// Adds a subscriber, it gets all events published from now on. Each
+/// subscriber must only be added once.
+pub fn subscribe(subscriber: &'static Subscriber) {
+    SUBSCRIBERS.lock(|first| {
+        subscriber.next.lock(|next| next.set(first.get()));
+        first.set(Some(subscriber));
+    });
+}
+
+/// Sends an event to all subscribers. Returns whether all of them queued it.
+pub fn publish(key: Key, event: Event, at: Instant) -> bool {
+    let event = InputEvent { key, event, at };
+    let mut queued = true;
+    let mut next = SUBSCRIBERS.lock(|first| first.get());
+    while let Some(subscriber) = next {
+        queued &= subscriber.push(event);
+        next = subscriber.next.lock(|next| next.get());
+    }
+    queued
+}
+
+impl Subscriber {
+    pub const fn new() -> Self {
+        Subscriber {
+            queue: Mutex::new(RefCell::new(InputQueue::new())),
+            signal: Signal::new(),
+            next: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    fn push(&self, event: InputEvent) -> bool {
+        let queued = self.queue.lock(|queue| queue.borrow_mut().push(event));
+        if queued {
+            self.signal.signal(());
+        }
+        queued
+    }
+
+    /// Takes the next event, if there is one.
+    pub fn try_next(&self) -> Option<InputEvent> {
+        self.queue.lock(|queue| queue.borrow_mut().pop())
+    }
+
+    /// Waits for the next event. Only one task may wait on a subscriber.
+    pub async fn next(&self) -> InputEvent {
+        loop {
+            if let Some(event) = self.try_next() {
+                return event;
+            }
+            self.signal.wait().await;
+        }
+    }
+
+    /// Number of events dropped because the queue was full.
+    pub fn overflows(&self) -> u32 {
+        self.queue.lock(|queue| queue.borrow().overflows())
+    }
+}
@@ -0,0 +1,246 @@
+//! Keyboard interface with boot protocol support.
+//!
+//! The HID class of embassy-usb can't advertise the boot subclass and rejects
+//! SET_PROTOCOL, which simple hosts like UEFI setup or GRUB rely on. So the
+//! keyboard interface is built here, with its own handling of the HID class
+//! requests.
+
+use core::cell::Cell;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{EndpointError, EndpointIn, EndpointOut};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler};
+use static_cell::StaticCell;
+use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
+
+pub const LOCK_NUM: u8 = 0x01;
+pub const LOCK_CAPS: u8 = 0x02;
+pub const LOCK_SCROLL: u8 = 0x04;
+pub const LOCK_COMPOSE: u8 = 0x08;
+pub const LOCK_KANA: u8 = 0x10;
+
+const USB_CLASS_HID: u8 = 0x03;
+const HID_SUBCLASS_BOOT: u8 = 0x01;
+const HID_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const HID_DESC_DESCTYPE_HID: u8 = 0x21;
+const HID_DESC_DESCTYPE_HID_REPORT: u8 = 0x22;
+
+const HID_REQ_GET_REPORT: u8 = 0x01;
+const HID_REQ_GET_IDLE: u8 = 0x02;
+const HID_REQ_GET_PROTOCOL: u8 = 0x03;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0A;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0B;
+
+/// Size of the boot keyboard input report. The report descriptor of
+/// `KeyboardReport` describes the same layout, so both protocols send the
+/// same bytes.
+const BOOT_REPORT_SIZE: usize = 8;
+
+/// Idle rate after reset in units of 4 ms, 500 ms as recommended for keyboards.
+const DEFAULT_IDLE: u8 = 125;
+
+type UsbDriver = Driver<'static, USB>;
+type KeyboardEndpointIn = <UsbDriver as embassy_usb::driver::Driver<'static>>::EndpointIn;
+type KeyboardEndpointOut = <UsbDriver as embassy_usb::driver::Driver<'static>>::EndpointOut;
+
+/// Lock state last reported by the host in the keyboard LED output report,
+/// see `LOCK_*`.
+static LOCK_STATE: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// Whether the host selected the boot protocol with SET_PROTOCOL.
+static BOOT_PROTOCOL: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Idle rate set by the host in units of 4 ms, 0 to only send changes.
+static IDLE: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(DEFAULT_IDLE));
+
+/// Last input report, returned by GET_REPORT and repeated at the idle rate.
+static LAST_REPORT: Mutex<CriticalSectionRawMutex, Cell<[u8; BOOT_REPORT_SIZE]>> = Mutex::new(Cell::new([0; BOOT_REPORT_SIZE]));
+
+pub fn lock_state() -> u8 {
+    LOCK_STATE.lock(|state| state.get())
+}
+
+/// Whether the host talks the boot protocol. Such hosts only know the
+/// keyboard interface, the other interfaces are never polled.
+pub fn boot_protocol() -> bool {
+    BOOT_PROTOCOL.lock(|boot| boot.get())
+}
+
+fn set_leds(data: &[u8]) {
+    if let Some(leds) = data.first() {
+        LOCK_STATE.lock(|state| state.set(*leds));
+    }
+}
+
+/// Adds the keyboard interface to the device.
+pub fn new(builder: &mut Builder<'static, UsbDriver>, poll_ms: u8) -> (KeyboardReader, KeyboardWriter) {
+    let report_descriptor = KeyboardReport::desc();
+    let [descriptor_len_low, descriptor_len_high] = (report_descriptor.len() as u16).to_le_bytes();
+    let hid_descriptor = [
+        0x11, 0x01, // bcdHID 1.11
+        0x00, //       bCountryCode
+        0x01, //       bNumDescriptors
+        HID_DESC_DESCTYPE_HID_REPORT,
+        descriptor_len_low,
+        descriptor_len_high,
+    ];
+
+    let mut function = builder.function(USB_CLASS_HID, HID_SUBCLASS_BOOT, HID_PROTOCOL_KEYBOARD);
+    let mut interface = function.interface();
+    let if_num = interface.interface_number();
+    let mut alt = interface.alt_setting(USB_CLASS_HID, HID_SUBCLASS_BOOT, HID_PROTOCOL_KEYBOARD, None);
+    alt.descriptor(HID_DESC_DESCTYPE_HID, &hid_descriptor);
+    let ep_in = alt.endpoint_interrupt_in(BOOT_REPORT_SIZE as u16, poll_ms);
+    let ep_out = alt.endpoint_interrupt_out(BOOT_REPORT_SIZE as u16, poll_ms);
+    drop(function);
+
+    static CONTROL: StaticCell<Control> = StaticCell::new();
+    let control = CONTROL.init(Control {
+        if_num,
+        report_descriptor,
+        hid_descriptor,
+    });
+    builder.handler(control);
+
+    (
+        KeyboardReader { ep_out },
+        KeyboardWriter {
+            ep_in,
+            last_write: Instant::from_ticks(0),
+        },
+    )
+}
+
+pub struct KeyboardWriter {
+    ep_in: KeyboardEndpointIn,
+    last_write: Instant,
+}
+
+impl KeyboardWriter {
+    pub async fn write(&mut self, report: &KeyboardReport) -> Result<(), EndpointError> {
+        let mut bytes = [0u8; BOOT_REPORT_SIZE];
+        bytes[0] = report.modifier;
+        bytes[2..].copy_from_slice(&report.keycodes);
+        LAST_REPORT.lock(|last| last.set(bytes));
+        self.write_bytes(&bytes).await
+    }
+
+    /// Time at which the last report has to be repeated for the idle rate.
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        match IDLE.lock(|idle| idle.get()) {
+            0 => None,
+            idle => Some(self.last_write + Duration::from_millis(idle as u64 * 4)),
+        }
+    }
+
+    /// Sends the last report again.
+    pub async fn repeat(&mut self) -> Result<(), EndpointError> {
+        let bytes = LAST_REPORT.lock(|last| last.get());
+        self.write_bytes(&bytes).await
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), EndpointError> {
+        self.last_write = Instant::now();
+        self.ep_in.write(bytes).await
+    }
+}
+
+pub struct KeyboardReader {
+    ep_out: KeyboardEndpointOut,
+}
+
+/// Reads the LED output reports sent on the interrupt OUT endpoint.
+#[embassy_executor::task]
+pub async fn keyboard_reader_task(mut reader: KeyboardReader) -> ! {
+    let mut buf = [0u8; BOOT_REPORT_SIZE];
+    loop {
+        reader.ep_out.wait_enabled().await;
+        match reader.ep_out.read(&mut buf).await {
+            Ok(len) => set_leds(&buf[..len]),
+            Err(EndpointError::Disabled) => {}
+            Err(e) => log::error!("Failed to read keyboard LEDs: {:?}", e),
+        }
+    }
+}
+
+/// Answers the HID class requests of the keyboard interface.
+struct Control {
+    if_num: InterfaceNumber,
+    report_descriptor: &'static [u8],
+    hid_descriptor: [u8; 7],
+}
+
+impl Handler for Control {
+    fn reset(&mut self) {
+        // Hosts have to select the boot protocol again after a reset.
+        BOOT_PROTOCOL.lock(|boot| boot.set(false));
+        IDLE.lock(|idle| idle.set(DEFAULT_IDLE));
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index) != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16) {
+            return None;
+        }
+
+        let response = match req.request {
+            HID_REQ_SET_REPORT => {
+                set_leds(data);
+                OutResponse::Accepted
+            }
+            HID_REQ_SET_IDLE => {
+                // Only the rate for all reports, the keyboard has no report IDs.
+                if req.value as u8 == 0 {
+                    IDLE.lock(|idle| idle.set((req.value >> 8) as u8));
+                }
+                OutResponse::Accepted
+            }
+            HID_REQ_SET_PROTOCOL => {
+                BOOT_PROTOCOL.lock(|boot| boot.set(req.value == 0));
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        };
+        Some(response)
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.recipient, req.index) != (Recipient::Interface, self.if_num.0 as u16) {
+            return None;
+        }
+
+        match req.request_type {
+            RequestType::Standard if req.request == Request::GET_DESCRIPTOR => match (req.value >> 8) as u8 {
+                HID_DESC_DESCTYPE_HID_REPORT => Some(InResponse::Accepted(self.report_descriptor)),
+                HID_DESC_DESCTYPE_HID => Some(InResponse::Accepted(&self.hid_descriptor)),
+                _ => Some(InResponse::Rejected),
+            },
+            RequestType::Class => {
+                let len = match req.request {
+                    HID_REQ_GET_REPORT if buf.len() >= BOOT_REPORT_SIZE => {
+                        buf[..BOOT_REPORT_SIZE].copy_from_slice(&LAST_REPORT.lock(|last| last.get()));
+                        BOOT_REPORT_SIZE
+                    }
+                    HID_REQ_GET_IDLE => {
+                        buf[0] = IDLE.lock(|idle| idle.get());
+                        1
+                    }
+                    HID_REQ_GET_PROTOCOL => {
+                        // 0 is the boot protocol, 1 the report protocol.
+                        buf[0] = if boot_protocol() { 0 } else { 1 };
+                        1
+                    }
+                    _ => return Some(InResponse::Rejected),
+                };
+                Some(InResponse::Accepted(&buf[..len]))
+            }
+            _ => None,
+        }
+    }
+}
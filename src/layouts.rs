@@ -1,5 +1,9 @@
-use crate::hid::KeyType;
+use crate::hid::{Key, KeyType};
+use oskar_input::layers::{LayerAction, Layered, Layout};
 
+pub use oskar_input::layers::NUM_LAYERS;
+
+#[derive(Clone, Copy)]
 pub struct KeyLayout {
     pub encoder_left: KeyType,
     pub encoder_right: KeyType,
@@ -7,4 +11,80 @@ pub struct KeyLayout {
     pub key1: KeyType,
     pub key2: KeyType,
     pub key3: KeyType,
-}
\ No newline at end of file
+    /// Encoder turned while `encoder_button` is held. Transparent falls
+    /// through to lower layers, and to `encoder_left`/`encoder_right` if no
+    /// layer binds it.
+    pub encoder_pressed_left: KeyType,
+    pub encoder_pressed_right: KeyType,
+}
+
+impl KeyLayout {
+    /// A layer where every key falls through to the layers below.
+    pub const TRANSPARENT: KeyLayout = KeyLayout {
+        encoder_left: KeyType::Transparent,
+        encoder_right: KeyType::Transparent,
+        encoder_button: KeyType::Transparent,
+        key1: KeyType::Transparent,
+        key2: KeyType::Transparent,
+        key3: KeyType::Transparent,
+        encoder_pressed_left: KeyType::Transparent,
+        encoder_pressed_right: KeyType::Transparent,
+    };
+
+    pub fn get(&self, key: Key) -> KeyType {
+        match key {
+            Key::EncoderLeft => self.encoder_left,
+            Key::EncoderRight => self.encoder_right,
+            Key::EncoderButton => self.encoder_button,
+            Key::Key1 => self.key1,
+            Key::Key2 => self.key2,
+            Key::Key3 => self.key3,
+            Key::EncoderPressedLeft => self.encoder_pressed_left,
+            Key::EncoderPressedRight => self.encoder_pressed_right,
+        }
+    }
+
+    pub fn set(&mut self, key: Key, key_type: KeyType) {
+        match key {
+            Key::EncoderLeft => self.encoder_left = key_type,
+            Key::EncoderRight => self.encoder_right = key_type,
+            Key::EncoderButton => self.encoder_button = key_type,
+            Key::Key1 => self.key1 = key_type,
+            Key::Key2 => self.key2 = key_type,
+            Key::Key3 => self.key3 = key_type,
+            Key::EncoderPressedLeft => self.encoder_pressed_left = key_type,
+            Key::EncoderPressedRight => self.encoder_pressed_right = key_type,
+        }
+    }
+}
+
+pub type Keymap = [KeyLayout; NUM_LAYERS];
+
+/// Layer stack of the firmware, see `oskar_input::layers::LayerState`.
+pub type LayerState = oskar_input::layers::LayerState<KeyType>;
+
+impl Layout for KeyLayout {
+    type Action = KeyType;
+
+    fn get(&self, key: Key) -> KeyType {
+        KeyLayout::get(self, key)
+    }
+}
+
+impl Layered for KeyType {
+    fn layer_action(self) -> LayerAction<KeyType> {
+        match self {
+            KeyType::Transparent => LayerAction::Transparent,
+            KeyType::MomentaryLayer(layer) => LayerAction::MomentaryLayer(layer),
+            KeyType::ToggleLayer(layer) => LayerAction::ToggleLayer(layer),
+            KeyType::OneShotLayer(layer) => LayerAction::OneShotLayer(layer),
+            KeyType::DefaultLayer(layer) => LayerAction::DefaultLayer(layer),
+            KeyType::IfLock { lock, on, off } => LayerAction::IfLock {
+                lock,
+                on: on.into(),
+                off: off.into(),
+            },
+            code => LayerAction::Other(code),
+        }
+    }
+}
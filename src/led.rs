@@ -1,8 +1,12 @@
+use crate::keyboard::{self, LOCK_CAPS, LOCK_NUM, LOCK_SCROLL};
+use crate::usb;
 use crate::{DeviceMode, LedResources};
 use embassy_rp::bind_interrupts;
 use embassy_rp::peripherals::PIO1;
 use embassy_rp::pio::{InterruptHandler, Pio};
 use embassy_rp::pio_programs::ws2812::{PioWs2812, PioWs2812Program};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
 use embassy_time::{Duration, Ticker};
 use smart_leds::RGB8;
 
@@ -10,6 +14,33 @@ bind_interrupts!(struct Irqs {
     PIO1_IRQ_0 => InterruptHandler<PIO1>;
 });
 
+/// LEDs that show a host lock in the given color while it is on, instead of
+/// the color wheel.
+const LOCK_INDICATORS: [(usize, u8, RGB8); 3] = [
+    (0, LOCK_NUM, RGB8 { r: 10, g: 10, b: 10 }),
+    (1, LOCK_CAPS, RGB8 { r: 10, g: 10, b: 10 }),
+    (2, LOCK_SCROLL, RGB8 { r: 10, g: 10, b: 10 }),
+];
+
+/// LED showing the color of the encoder mode instead of the color wheel,
+/// the scroll lock indicator takes precedence.
+const ENCODER_MODE_LED: usize = 2;
+
+/// State shown on the LEDs, sent by the other tasks.
+pub enum LedEvent {
+    /// Color of the current encoder mode, see `encoder_mode`.
+    EncoderMode(RGB8),
+    /// Shows this color on all LEDs instead of their state, `None` ends it.
+    Override(Option<RGB8>),
+}
+
+pub static LED_EVENTS: Channel<CriticalSectionRawMutex, LedEvent, 4> = Channel::new();
+
+/// What the LEDs show while the host suspended the bus: off with `None`,
+/// `Some(shift)` keeps the animation running with the brightness divided by
+/// `1 << shift`. Off by default, the bus only supplies 2.5 mA while suspended.
+pub const SUSPEND_LEDS_DIM: Option<u8> = None;
+
 #[embassy_executor::task]
 pub async fn led_task(r: LedResources, mode: DeviceMode) -> ! {
     let Pio {
@@ -27,6 +58,9 @@ pub async fn led_task(r: LedResources, mode: DeviceMode) -> ! {
     let program = PioWs2812Program::new(&mut common);
     let mut ws2812 = PioWs2812::new(&mut common, sm0, r.led_dma, r.led_gpio, &program);
 
+    let mut encoder_mode = None;
+    let mut color_override = None;
+
     let mut ticker = Ticker::every(Duration::from_millis(10));
     loop {
         for j in 0..(256 * 5) {
@@ -35,7 +69,37 @@ pub async fn led_task(r: LedResources, mode: DeviceMode) -> ! {
                 data[i] =
                     wheel((((i * 256) as u16 / (NUM_LEDS - 1) as u16 + j as u16) & 255) as u8);
             }
-            ws2812.write(&data).await;
+
+            while let Ok(event) = LED_EVENTS.try_receive() {
+                match event {
+                    LedEvent::EncoderMode(color) => encoder_mode = Some(color),
+                    LedEvent::Override(color) => color_override = color,
+                }
+            }
+            if let Some(color) = encoder_mode {
+                data[ENCODER_MODE_LED] = color;
+            }
+
+            let locks = keyboard::lock_state();
+            for (led, lock, color) in LOCK_INDICATORS {
+                if locks & lock != 0 {
+                    data[led] = color;
+                }
+            }
+
+            if usb::suspended() {
+                let mut dimmed = [RGB8::default(); NUM_LEDS];
+                if let Some(shift) = SUSPEND_LEDS_DIM {
+                    for (dimmed, color) in dimmed.iter_mut().zip(data) {
+                        *dimmed = (color.r >> shift, color.g >> shift, color.b >> shift).into();
+                    }
+                }
+                ws2812.write(&dimmed).await;
+            } else if let Some(color) = color_override {
+                ws2812.write(&[color; NUM_LEDS]).await;
+            } else {
+                ws2812.write(&data).await;
+            }
 
             ticker.next().await;
         }
@@ -0,0 +1,183 @@
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use heapless::Deque;
+use oskar_protocol::macros::{encode_macro, MacroStep, MacroSteps, MACRO_BUFFER_SIZE};
+use usbd_hid::descriptor::KeyboardUsage;
+
+/// Compiled in macros, played by `KeyType::Macro(index)`. Used when no valid
+/// macros are stored in flash.
+pub const DEFAULT_MACROS: &[&[MacroStep<'static>]] = &[];
+
+/// Delay after every key press and release unless a macro sets its own.
+const DEFAULT_KEY_DELAY: Duration = Duration::from_millis(10);
+
+/// The encoded macros in use, loaded from flash at boot.
+pub static MACROS: Mutex<CriticalSectionRawMutex, RefCell<[u8; MACRO_BUFFER_SIZE]>> = Mutex::new(RefCell::new([0; MACRO_BUFFER_SIZE]));
+
+/// Encodes `DEFAULT_MACROS`, unused space holds empty macros.
+pub fn default_macros() -> [u8; MACRO_BUFFER_SIZE] {
+    let mut buf = [0u8; MACRO_BUFFER_SIZE];
+    let mut offset = 0;
+    for steps in DEFAULT_MACROS {
+        match encode_macro(steps, &mut buf, offset) {
+            Some(end) => offset = end,
+            None => {
+                defmt::warn!("default macros don't fit into the macro buffer");
+                break;
+            }
+        }
+    }
+    buf
+}
+
+#[derive(Clone, Copy)]
+pub enum MacroEvent {
+    Press(u8),
+    Release(u8),
+    Wait(Duration),
+}
+
+/// Expands the steps of a macro into individual key presses, releases and delays.
+pub struct MacroPlayer<'a> {
+    steps: MacroSteps<'a>,
+    text: core::str::Chars<'a>,
+    pending: Deque<MacroEvent, 48>,
+    key_delay: Duration,
+}
+
+impl<'a> MacroPlayer<'a> {
+    pub fn new(steps: &'a [u8]) -> Self {
+        MacroPlayer {
+            steps: MacroSteps::new(steps),
+            text: "".chars(),
+            pending: Deque::new(),
+            key_delay: DEFAULT_KEY_DELAY,
+        }
+    }
+
+    fn press(&mut self, usage: u8) {
+        let _ = self.pending.push_back(MacroEvent::Press(usage));
+        let _ = self.pending.push_back(MacroEvent::Wait(self.key_delay));
+    }
+
+    fn release(&mut self, usage: u8) {
+        let _ = self.pending.push_back(MacroEvent::Release(usage));
+        let _ = self.pending.push_back(MacroEvent::Wait(self.key_delay));
+    }
+
+    fn tap(&mut self, usage: u8) {
+        self.press(usage);
+        self.release(usage);
+    }
+
+    fn type_char(&mut self, c: char) {
+        if let Some((shift, usage)) = ascii_usage(c) {
+            if shift {
+                self.press(KeyboardUsage::KeyboardLeftShift as u8);
+            }
+            self.tap(usage);
+            if shift {
+                self.release(KeyboardUsage::KeyboardLeftShift as u8);
+            }
+            return;
+        }
+
+        // Everything else goes through the Linux (IBus/GTK) unicode input:
+        // Ctrl+Shift+U, the code point in hex, then space.
+        self.press(KeyboardUsage::KeyboardLeftControl as u8);
+        self.press(KeyboardUsage::KeyboardLeftShift as u8);
+        self.tap(KeyboardUsage::KeyboardUu as u8);
+        self.release(KeyboardUsage::KeyboardLeftShift as u8);
+        self.release(KeyboardUsage::KeyboardLeftControl as u8);
+
+        let code_point = c as u32;
+        let digits = (32 - code_point.leading_zeros()).div_ceil(4).max(1);
+        for digit in (0..digits).rev() {
+            let nibble = ((code_point >> (digit * 4)) & 0xF) as u8;
+            let usage = match nibble {
+                0 => KeyboardUsage::Keyboard0CloseParens as u8,
+                1..=9 => KeyboardUsage::Keyboard1Exclamation as u8 + nibble - 1,
+                _ => KeyboardUsage::KeyboardAa as u8 + nibble - 10,
+            };
+            self.tap(usage);
+        }
+        self.tap(KeyboardUsage::KeyboardSpacebar as u8);
+    }
+}
+
+impl Iterator for MacroPlayer<'_> {
+    type Item = MacroEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+
+            if let Some(c) = self.text.next() {
+                self.type_char(c);
+                continue;
+            }
+
+            match self.steps.next()? {
+                MacroStep::Tap(usage) => self.tap(usage),
+                MacroStep::Press(usage) => self.press(usage),
+                MacroStep::Release(usage) => self.release(usage),
+                MacroStep::Delay(ms) => {
+                    let _ = self.pending.push_back(MacroEvent::Wait(Duration::from_millis(ms as u64)));
+                }
+                MacroStep::KeyDelay(ms) => self.key_delay = Duration::from_millis(ms as u64),
+                MacroStep::Text(text) => self.text = text.chars(),
+            }
+        }
+    }
+}
+
+/// Maps a character to its keycode on a US layout, and whether shift is needed.
+fn ascii_usage(c: char) -> Option<(bool, u8)> {
+    let (shift, usage) = match c {
+        'a'..='z' => return Some((false, KeyboardUsage::KeyboardAa as u8 + (c as u8 - b'a'))),
+        'A'..='Z' => return Some((true, KeyboardUsage::KeyboardAa as u8 + (c as u8 - b'A'))),
+        '1'..='9' => return Some((false, KeyboardUsage::Keyboard1Exclamation as u8 + (c as u8 - b'1'))),
+        '0' => (false, KeyboardUsage::Keyboard0CloseParens),
+        '\n' => (false, KeyboardUsage::KeyboardEnter),
+        '\t' => (false, KeyboardUsage::KeyboardTab),
+        ' ' => (false, KeyboardUsage::KeyboardSpacebar),
+        '!' => (true, KeyboardUsage::Keyboard1Exclamation),
+        '@' => (true, KeyboardUsage::Keyboard2At),
+        '#' => (true, KeyboardUsage::Keyboard3Hash),
+        '$' => (true, KeyboardUsage::Keyboard4Dollar),
+        '%' => (true, KeyboardUsage::Keyboard5Percent),
+        '^' => (true, KeyboardUsage::Keyboard6Caret),
+        '&' => (true, KeyboardUsage::Keyboard7Ampersand),
+        '*' => (true, KeyboardUsage::Keyboard8Asterisk),
+        '(' => (true, KeyboardUsage::Keyboard9OpenParens),
+        ')' => (true, KeyboardUsage::Keyboard0CloseParens),
+        '-' => (false, KeyboardUsage::KeyboardDashUnderscore),
+        '_' => (true, KeyboardUsage::KeyboardDashUnderscore),
+        '=' => (false, KeyboardUsage::KeyboardEqualPlus),
+        '+' => (true, KeyboardUsage::KeyboardEqualPlus),
+        '[' => (false, KeyboardUsage::KeyboardOpenBracketBrace),
+        '{' => (true, KeyboardUsage::KeyboardOpenBracketBrace),
+        ']' => (false, KeyboardUsage::KeyboardCloseBracketBrace),
+        '}' => (true, KeyboardUsage::KeyboardCloseBracketBrace),
+        '\\' => (false, KeyboardUsage::KeyboardBackslashBar),
+        '|' => (true, KeyboardUsage::KeyboardBackslashBar),
+        ';' => (false, KeyboardUsage::KeyboardSemiColon),
+        ':' => (true, KeyboardUsage::KeyboardSemiColon),
+        '\'' => (false, KeyboardUsage::KeyboardSingleDoubleQuote),
+        '"' => (true, KeyboardUsage::KeyboardSingleDoubleQuote),
+        '`' => (false, KeyboardUsage::KeyboardBacktickTilde),
+        '~' => (true, KeyboardUsage::KeyboardBacktickTilde),
+        ',' => (false, KeyboardUsage::KeyboardCommaLess),
+        '<' => (true, KeyboardUsage::KeyboardCommaLess),
+        '.' => (false, KeyboardUsage::KeyboardPeriodGreater),
+        '>' => (true, KeyboardUsage::KeyboardPeriodGreater),
+        '/' => (false, KeyboardUsage::KeyboardSlashQuestion),
+        '?' => (true, KeyboardUsage::KeyboardSlashQuestion),
+        _ => return None,
+    };
+    Some((shift, usage as u8))
+}
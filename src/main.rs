@@ -6,12 +6,14 @@
 #![feature(type_alias_impl_trait)]
 
 use assign_resources::assign_resources;
+use core::cell::Cell;
 use core::panic::PanicInfo;
 use cortex_m::peripheral::SCB;
 use defmt_rtt as _;
 use embassy_executor::Spawner;
-use embassy_futures::select::select_array;
+use embassy_futures::select::{select, select_array, Either};
 use embassy_rp::bind_interrupts;
+use embassy_rp::clocks::clk_peri_freq;
 use embassy_rp::flash::{Async, Flash};
 use embassy_rp::gpio::{Input, Level, Output, Pull};
 use embassy_rp::peripherals::{self, PIO0, SPI0, USB};
@@ -19,19 +21,36 @@ use embassy_rp::pio::InterruptHandler as PIOInterruptHandler;
 use embassy_rp::spi::{Config as SpiConfig, Spi};
 use embassy_rp::usb::{Driver, InterruptHandler as USBInterruptHandler};
 use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
 use embassy_usb::class::cdc_acm::{CdcAcmClass, State as CdcAcmState};
 use embassy_usb::class::hid::{HidReaderWriter, State as Hid_State};
-use usbd_hid::descriptor::{KeyboardReport, MediaKeyboardReport, SerializedDescriptor};
 
 use embassy_usb::{Config as UsbConfig, UsbDevice};
 use heapless::String;
 use static_cell::StaticCell;
 use ufmt::uwrite;
 
+mod cdc_acm;
+mod config;
+mod console;
+mod descriptor;
+mod encoder;
+mod encoder_mode;
 mod hid;
+mod input;
+mod keyboard;
 mod layouts;
 mod led;
+mod macros;
+mod modem;
+mod mouse;
+mod report;
+mod shell;
+mod storage;
 mod uart;
+mod usb;
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => USBInterruptHandler<USB>;
     PIO0_IRQ_0 => PIOInterruptHandler<PIO0>;
@@ -43,6 +62,12 @@ assign_resources! {
         tx: PIN_0,
         rx: PIN_1,
     }
+    modem: ModemResources{
+        reset: PIN_6,
+        boot: PIN_7,
+        cts: PIN_8,
+        dsr: PIN_9,
+    }
     spi: SpiResources{
         peripheral: SPI0,
         clk: PIN_2,
@@ -93,6 +118,7 @@ const FLASH_SIZE: usize = 2 * 1024 * 1024;
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p: embassy_rp::Peripherals = embassy_rp::init(Default::default());
+    console::init();
     let r: AssignedResources = split_resources!(p);
     let driver = Driver::new(p.USB, Irqs);
 
@@ -121,6 +147,8 @@ async fn main(spawner: Spawner) {
         uwrite!(uid_str, "{:02X}", *byte).unwrap_or_default();
     }
 
+    storage::init(flash);
+
     let config = {
         let mut config = UsbConfig::new(0x1ced, 0xc0fe);
         config.manufacturer = Some("9elements");
@@ -128,6 +156,7 @@ async fn main(spawner: Spawner) {
         config.serial_number = Some(uid_str.as_str());
         config.max_power = 100;
         config.max_packet_size_0 = 64;
+        config.supports_remote_wakeup = true;
 
         // Required for windows compatibility.
         // https://developer.nordicsemi.com/nRF_Connect_SDK/doc/1.9.1/kconfig/CONFIG_CDC_ACM_IAD.html#help
@@ -139,7 +168,7 @@ async fn main(spawner: Spawner) {
     };
 
     let mut builder: embassy_usb::Builder<'_, Driver<'_, USB>> = {
-        static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static CONFIG_DESCRIPTOR: StaticCell<[u8; 512]> = StaticCell::new();
         static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
         static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
         static MSOS_DESCRIPTOR: StaticCell <[u8; 256]> = StaticCell::new();
@@ -147,7 +176,7 @@ async fn main(spawner: Spawner) {
         let builder = embassy_usb::Builder::new(
             driver,
             config,
-            CONFIG_DESCRIPTOR.init([0; 256]),
+            CONFIG_DESCRIPTOR.init([0; 512]),
             BOS_DESCRIPTOR.init([0; 256]),
             MSOS_DESCRIPTOR.init([0; 256]), // no msos descriptors
             CONTROL_BUF.init([0; 64]),
@@ -155,13 +184,24 @@ async fn main(spawner: Spawner) {
         builder
     };
 
+    static USB_STATE_HANDLER: StaticCell<usb::UsbStateHandler> = StaticCell::new();
+    builder.handler(USB_STATE_HANDLER.init(usb::UsbStateHandler));
+
     spawner.spawn(led::led_task(r.led, mode)).unwrap();
 
+    // Added first in every mode, so it is always the first serial port.
+    let console_class = {
+        static STATE: StaticCell<CdcAcmState> = StaticCell::new();
+        let state = STATE.init(CdcAcmState::new());
+        CdcAcmClass::new(&mut builder, state, 64)
+    };
+    spawner.spawn(console::console_task(console_class, mode)).unwrap();
+
     if !(matches!(mode, DeviceMode::Keyboard)) {
         let uart_class = {
-            static STATE: StaticCell<CdcAcmState> = StaticCell::new();
-            let state = STATE.init(CdcAcmState::new());
-            CdcAcmClass::new(&mut builder, state, 64)
+            static STATE: StaticCell<cdc_acm::State> = StaticCell::new();
+            let state = STATE.init(cdc_acm::State::new());
+            cdc_acm::CdcAcmClass::new(&mut builder, state, 64)
         };
 
         let serprog_class = {
@@ -170,41 +210,50 @@ async fn main(spawner: Spawner) {
             CdcAcmClass::new(&mut builder, state, 64)
         };
 
-        spawner.spawn(uart::uart_task(uart_class, r.uart)).unwrap();
+        spawner.spawn(uart::uart_task(uart_class, r.uart, r.modem)).unwrap();
         spawner.spawn(serprog_task(serprog_class, r.spi)).unwrap();
     }
 
     if !(matches!(mode, DeviceMode::Picoprog)) {
-        let keyboard_class: HidReaderWriter<'_, Driver<'_, USB>, 1, 8> = {
+        let (keyboard_reader, keyboard_writer) = keyboard::new(&mut builder, 10);
+
+        let multimedia_class: HidReaderWriter<'_, Driver<'_, USB>, 1, 8> = {
             static STATE: StaticCell<Hid_State> = StaticCell::new();
             let state = STATE.init(Hid_State::new());
 
+            static REQUEST_HANDLER: StaticCell<mouse::MouseRequestHandler> = StaticCell::new();
+            let request_handler = REQUEST_HANDLER.init(mouse::MouseRequestHandler);
+
             let config = embassy_usb::class::hid::Config {
-                report_descriptor: KeyboardReport::desc(),
-                request_handler: None,
-                poll_ms: 60,
+                report_descriptor: descriptor::MEDIA_REPORT_DESCRIPTOR,
+                request_handler: Some(request_handler),
+                poll_ms: 10,
                 max_packet_size: 64,
             };
 
             HidReaderWriter::new(&mut builder, state, config)
         };
 
-        let multimedia_class: HidReaderWriter<'_, Driver<'_, USB>, 1, 8> = {
-            static STATE: StaticCell<Hid_State> = StaticCell::new();
-            let state = STATE.init(Hid_State::new());
+        spawner.spawn(keyboard::keyboard_reader_task(keyboard_reader)).unwrap();
+        spawner.spawn(console::key_log_task()).unwrap();
+        spawner.spawn(hid::hid_task(spawner, keyboard_writer, multimedia_class, r.hid, r.encoder)).unwrap();
+    }
 
-            let config = embassy_usb::class::hid::Config {
-                report_descriptor: MediaKeyboardReport::desc(),
-                request_handler: None,
-                poll_ms: 60,
-                max_packet_size: 64,
-            };
+    // The keymap can be configured in every mode, like the console.
+    let config_class: HidReaderWriter<'_, Driver<'_, USB>, { config::REPORT_SIZE }, { config::REPORT_SIZE }> = {
+        static STATE: StaticCell<Hid_State> = StaticCell::new();
+        let state = STATE.init(Hid_State::new());
 
-            HidReaderWriter::new(&mut builder, state, config)
+        let config = embassy_usb::class::hid::Config {
+            report_descriptor: config::CONFIG_REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: 64,
         };
 
-        spawner.spawn(hid::hid_task(spawner, keyboard_class, multimedia_class, r.hid, r.encoder)).unwrap();
-    }
+        HidReaderWriter::new(&mut builder, state, config)
+    };
+    spawner.spawn(config::config_task(config_class, mode)).unwrap();
 
     let usb = builder.build();
     // We can't really recover here so just unwrap
@@ -226,8 +275,15 @@ type CustomUsbDriver = Driver<'static, USB>;
 type CustomUsbDevice = UsbDevice<'static, CustomUsbDriver>;
 
 #[embassy_executor::task]
-async fn usb_task(mut usb: CustomUsbDevice) -> ! {
-    usb.run().await
+async fn usb_task(mut device: CustomUsbDevice) -> ! {
+    loop {
+        device.run_until_suspend().await;
+        if let Either::Second(_) = select(device.wait_resume(), usb::wait_remote_wakeup()).await {
+            if let Err(e) = device.remote_wakeup().await {
+                log::error!("Failed to wake up the host: {:?}", e);
+            }
+        }
+    }
 }
 
 #[embassy_executor::task]
@@ -245,10 +301,82 @@ async fn selector_watchdog_task(
     watchdog.trigger_reset();
 }
 
+const SPI_DEFAULT_FREQUENCY: u32 = 12_000_000; // 12 MHz
+
+static SPI_FREQUENCY: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(SPI_DEFAULT_FREQUENCY));
+
+/// SPI clock last requested by serprog or the shell.
+fn spi_frequency() -> u32 {
+    SPI_FREQUENCY.lock(|frequency| frequency.get())
+}
+
+/// Clock set on the debug console, waiting for `serprog_task` to apply it.
+static SPI_FREQUENCY_REQUEST: Signal<CriticalSectionRawMutex, u32> = Signal::new();
+
+/// Changes the SPI clock of serprog, until a serprog client sets its own.
+/// Returns `false` if the frequency is out of range.
+fn set_spi_frequency(frequency: u32) -> bool {
+    // Same limits as `Spi::set_frequency`, which panics outside of them: the
+    // clock is clk_peri / presc / (scr + 1) with an even presc up to 254.
+    let Some(double) = frequency.checked_mul(2).filter(|&double| double > 0) else {
+        return false;
+    };
+    if clk_peri_freq().div_ceil(double) > 127 * 256 {
+        return false;
+    }
+
+    SPI_FREQUENCY.lock(|current| current.set(frequency));
+    SPI_FREQUENCY_REQUEST.signal(frequency);
+    true
+}
+
+/// SPI bus handed to serprog. Takes a clock set on the debug console at the
+/// start of the next SPI op, serprog ops start by writing the flash command.
+struct SerprogSpi<'d> {
+    spi: Spi<'d, SPI0, embassy_rp::spi::Async>,
+}
+
+impl SerprogSpi<'_> {
+    fn apply_frequency(&mut self) {
+        if let Some(frequency) = SPI_FREQUENCY_REQUEST.try_take() {
+            self.spi.set_frequency(frequency);
+        }
+    }
+}
+
+impl embedded_hal_async::spi::ErrorType for SerprogSpi<'_> {
+    type Error = embassy_rp::spi::Error;
+}
+
+impl embedded_hal_async::spi::SpiBus<u8> for SerprogSpi<'_> {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.spi.read(words).await
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.apply_frequency();
+        self.spi.write(words).await
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        self.apply_frequency();
+        self.spi.transfer(read, write).await
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.apply_frequency();
+        self.spi.transfer_in_place(words).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
 #[embassy_executor::task]
 async fn serprog_task(class: CdcAcmClass<'static, CustomUsbDriver>, r: SpiResources) -> ! {
     let mut config = SpiConfig::default();
-    config.frequency = 12_000_000; // 12 MHz
+    config.frequency = SPI_DEFAULT_FREQUENCY;
 
     let spi = Spi::new(
         r.peripheral,
@@ -259,11 +387,15 @@ async fn serprog_task(class: CdcAcmClass<'static, CustomUsbDriver>, r: SpiResour
         r.miso_dma,
         config,
     );
+    let spi = SerprogSpi { spi };
     let cs = Output::new(r.cs, Level::High);
     let led = Output::new(r.led, Level::Low);
 
-    let set_freq_cb = move |spi: &mut Spi<'_, SPI0, embassy_rp::spi::Async>, freq| {
-        spi.set_frequency(freq);
+    let set_freq_cb = move |spi: &mut SerprogSpi<'_>, freq| {
+        // The client's clock wins over one set on the console before.
+        SPI_FREQUENCY_REQUEST.reset();
+        spi.spi.set_frequency(freq);
+        SPI_FREQUENCY.lock(|frequency| frequency.set(freq));
     };
 
     let serprog = serprog::Serprog::new(spi, cs, led, class, Some(set_freq_cb));
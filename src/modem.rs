@@ -0,0 +1,108 @@
+//! Modem control lines of the UART port.
+//!
+//! DTR and RTS from the host drive the reset and boot pins of a target, so
+//! tools like esptool and stm32flash can start its bootloader. How depends on
+//! the `LineMapping`. CTS and DSR are read from two inputs, both active low,
+//! and sent to the host as serial state.
+
+use crate::cdc_acm::{ControlLines, LineState, Notifier, SERIAL_STATE_DCD, SERIAL_STATE_DSR};
+use core::cell::Cell;
+use embassy_futures::select::select;
+use embassy_rp::gpio::{Flex, Input, Output};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use oskar_protocol::shell::LineMapping;
+
+/// Mapping after power-up, `uart lines` on the debug console changes it.
+/// Terminals set DTR and RTS when opening the port, which only leaves the
+/// target running with `Esptool`.
+const DEFAULT_MAPPING: LineMapping = LineMapping::Esptool;
+
+static MAPPING: Mutex<CriticalSectionRawMutex, Cell<LineMapping>> = Mutex::new(Cell::new(DEFAULT_MAPPING));
+static MAPPING_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn mapping() -> LineMapping {
+    MAPPING.lock(|mapping| mapping.get())
+}
+
+pub fn set_mapping(mapping: LineMapping) {
+    MAPPING.lock(|current| current.set(mapping));
+    MAPPING_CHANGED.signal(());
+}
+
+/// Levels of the reset and boot pins, `true` is high.
+fn outputs(mapping: LineMapping, lines: ControlLines) -> (bool, bool) {
+    let ControlLines { dtr, rts } = lines;
+    match mapping {
+        LineMapping::Direct => (!dtr, !rts),
+        // Setting both lines, which terminals do when opening the port,
+        // leaves the target alone.
+        LineMapping::Esptool => (!(rts && !dtr), !(dtr && !rts)),
+        LineMapping::Stm32 => (!dtr, rts),
+    }
+}
+
+/// Follows the control lines with the reset and boot pins. Reset is open
+/// drain, as reset pins usually have a pull-up and may be driven by the
+/// target itself.
+pub async fn drive_outputs(line_state: LineState<'_>, mut reset: Flex<'_>, mut boot: Output<'_>) -> ! {
+    reset.set_low();
+    loop {
+        let (reset_high, boot_high) = outputs(mapping(), line_state.control_lines());
+        if reset_high {
+            reset.set_as_input();
+        } else {
+            reset.set_as_output();
+        }
+        boot.set_level(boot_high.into());
+        select(line_state.control_lines_changed(), MAPPING_CHANGED.wait()).await;
+    }
+}
+
+/// Sends CTS and DSR to the host whenever they change.
+pub async fn report_inputs(notifier: &mut Notifier<'_, Driver<'_, USB>>, mut cts: Input<'_>, mut dsr: Input<'_>) -> ! {
+    loop {
+        notifier.wait_connection().await;
+        let mut reported = None;
+        loop {
+            let (cts_set, dsr_set) = (cts.is_low(), dsr.is_low());
+            let mut state = 0;
+            if cts_set {
+                state |= SERIAL_STATE_DCD;
+            }
+            if dsr_set {
+                state |= SERIAL_STATE_DSR;
+            }
+
+            if reported != Some(state) {
+                if notifier.serial_state(state).await.is_err() {
+                    break;
+                }
+                reported = Some(state);
+                // The inputs may have changed while the host didn't poll.
+                continue;
+            }
+
+            // Waiting for the other level instead of an edge doesn't miss
+            // changes since the inputs were read.
+            let cts_changed = async {
+                if cts_set {
+                    cts.wait_for_high().await
+                } else {
+                    cts.wait_for_low().await
+                }
+            };
+            let dsr_changed = async {
+                if dsr_set {
+                    dsr.wait_for_high().await
+                } else {
+                    dsr.wait_for_low().await
+                }
+            };
+            select(cts_changed, dsr_changed).await;
+        }
+    }
+}
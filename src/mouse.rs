@@ -0,0 +1,122 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use embassy_usb::class::hid::{ReportId, RequestHandler};
+use embassy_usb::control::OutResponse;
+
+pub const MOUSE_REPORT_ID: u8 = 0x02;
+
+/// Wheel units per notch while the host enabled the Resolution Multiplier,
+/// the physical maximum of the multiplier in the report descriptor.
+const HIGH_RESOLUTION: i32 = 120;
+
+/// Interval at which held mouse keys move the cursor.
+const MOUSE_KEY_INTERVAL: Duration = Duration::from_millis(16);
+/// Mouse keys ramp up to `MOUSE_KEY_MAX_SPEED` times their movement over this time.
+const MOUSE_KEY_RAMP: Duration = Duration::from_millis(1000);
+const MOUSE_KEY_MAX_SPEED: u8 = 4;
+
+/// Feature report written by the host: bits 0-1 enable the high resolution
+/// wheel, bits 2-3 the high resolution pan.
+static RESOLUTION_MULTIPLIER: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i16,
+    pub pan: i16,
+}
+
+impl MouseReport {
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let [wheel_low, wheel_high] = self.wheel.to_le_bytes();
+        let [pan_low, pan_high] = self.pan.to_le_bytes();
+        [MOUSE_REPORT_ID, self.buttons, self.x as u8, self.y as u8, wheel_low, wheel_high, pan_low, pan_high]
+    }
+}
+
+/// Converts wheel notches to the units the host currently expects.
+pub fn wheel_units(notches: i8) -> i32 {
+    let high_resolution = RESOLUTION_MULTIPLIER.lock(|multiplier| multiplier.get() & 0b0011 != 0);
+    notches as i32 * if high_resolution { HIGH_RESOLUTION } else { 1 }
+}
+
+/// Converts pan notches to the units the host currently expects.
+pub fn pan_units(notches: i8) -> i32 {
+    let high_resolution = RESOLUTION_MULTIPLIER.lock(|multiplier| multiplier.get() & 0b1100 != 0);
+    notches as i32 * if high_resolution { HIGH_RESOLUTION } else { 1 }
+}
+
+/// Answers the host's requests for the Resolution Multiplier feature report.
+pub struct MouseRequestHandler;
+
+impl RequestHandler for MouseRequestHandler {
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        match id {
+            ReportId::Feature(MOUSE_REPORT_ID) if buf.len() >= 2 => {
+                buf[0] = MOUSE_REPORT_ID;
+                buf[1] = RESOLUTION_MULTIPLIER.lock(|multiplier| multiplier.get());
+                Some(2)
+            }
+            _ => None,
+        }
+    }
+
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match (id, data) {
+            // The data starts with the report ID.
+            (ReportId::Feature(MOUSE_REPORT_ID), [_, value, ..]) => {
+                RESOLUTION_MULTIPLIER.lock(|multiplier| multiplier.set(*value));
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+}
+
+/// Timing of mouse keys, which move the cursor repeatedly while held.
+pub struct MouseKeys {
+    started: Option<Instant>,
+    next_tick: Instant,
+}
+
+impl MouseKeys {
+    pub const fn new() -> Self {
+        MouseKeys {
+            started: None,
+            next_tick: Instant::from_ticks(0),
+        }
+    }
+
+    /// Returns the speed factor if the cursor is due to move at `now`, 0 otherwise.
+    pub fn update(&mut self, moving: bool, now: Instant) -> u8 {
+        if !moving {
+            self.started = None;
+            return 0;
+        }
+
+        let started = match self.started {
+            Some(started) => started,
+            None => {
+                self.started = Some(now);
+                self.next_tick = now;
+                now
+            }
+        };
+
+        if now < self.next_tick {
+            return 0;
+        }
+        self.next_tick = now + MOUSE_KEY_INTERVAL;
+
+        let ramp = (now - started).as_millis() * (MOUSE_KEY_MAX_SPEED as u64 - 1) / MOUSE_KEY_RAMP.as_millis();
+        1 + ramp.min(MOUSE_KEY_MAX_SPEED as u64 - 1) as u8
+    }
+
+    /// Time of the next movement while mouse keys are held.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.started.map(|_| self.next_tick)
+    }
+}
@@ -0,0 +1,254 @@
+use crate::hid::{Key, KeyType, NUM_KEYS};
+use crate::mouse::MouseReport;
+use heapless::Vec;
+use usbd_hid::descriptor::{KeyboardReport, KeyboardUsage};
+
+/// Keycode sent in every slot when more keys are held than fit into a report.
+const ERROR_ROLL_OVER: u8 = KeyboardUsage::KeyboardErrorRollOver as u8;
+
+/// Smallest part of a scroll movement sent in one report. Larger movements
+/// are spread over a few reports, which looks smooth with a high resolution
+/// wheel.
+const MIN_SCROLL_SLICE: i32 = 30;
+
+/// Central state of all held keys, merged into the reports sent to the host.
+///
+/// Every physical key contributes its action while it is held, so releasing
+/// one key leaves the others untouched.
+pub struct ReportState {
+    /// Held keys and their resolved actions, oldest first.
+    held: Vec<(Key, KeyType), NUM_KEYS>,
+    /// Keycodes currently pressed by a macro.
+    macro_keys: Vec<u8, 6>,
+    sent_keyboard: (u8, [u8; 6]),
+    sent_consumer: u16,
+    sent_system: u8,
+    /// Scroll movement not sent yet, in the units the host expects.
+    wheel: i32,
+    pan: i32,
+    /// Speed of the cursor movement in the next mouse report, 0 to not move it.
+    cursor_speed: u8,
+    sent_mouse_buttons: u8,
+}
+
+impl ReportState {
+    pub const fn new() -> Self {
+        ReportState {
+            held: Vec::new(),
+            macro_keys: Vec::new(),
+            sent_keyboard: (0, [0; 6]),
+            sent_consumer: 0,
+            sent_system: 0,
+            wheel: 0,
+            pan: 0,
+            cursor_speed: 0,
+            sent_mouse_buttons: 0,
+        }
+    }
+
+    pub fn press(&mut self, key: Key, code: KeyType) {
+        self.release(key);
+        // Can't fail, there is at most one entry per key.
+        let _ = self.held.push((key, code));
+    }
+
+    pub fn release(&mut self, key: Key) {
+        self.held.retain(|(held_key, _)| *held_key != key);
+    }
+
+    pub fn macro_press(&mut self, usage: u8) {
+        if !self.macro_keys.contains(&usage) {
+            // Macros holding more than six keys at once lose the extra ones.
+            let _ = self.macro_keys.push(usage);
+        }
+    }
+
+    pub fn macro_release(&mut self, usage: u8) {
+        self.macro_keys.retain(|key| *key != usage);
+    }
+
+    pub fn macro_release_all(&mut self) {
+        self.macro_keys.clear();
+    }
+
+    pub fn scroll(&mut self, wheel: i32, pan: i32) {
+        self.wheel = self.wheel.saturating_add(wheel);
+        self.pan = self.pan.saturating_add(pan);
+    }
+
+    /// Whether scroll movement is left to be sent.
+    pub fn scroll_pending(&self) -> bool {
+        self.wheel != 0 || self.pan != 0
+    }
+
+    /// Whether a held mouse key moves the cursor.
+    pub fn cursor_moving(&self) -> bool {
+        self.held.iter().any(|(_, code)| matches!(code, KeyType::MouseMove { .. }))
+    }
+
+    /// Moves the cursor with the given speed factor in the next mouse report.
+    pub fn move_cursor(&mut self, speed: u8) {
+        self.cursor_speed = speed;
+    }
+
+    /// Returns the keyboard report if it differs from the last one returned.
+    pub fn take_keyboard_report(&mut self) -> Option<KeyboardReport> {
+        let mut modifier = 0;
+        let mut keycodes = [0u8; 6];
+        let mut count = 0;
+
+        let macro_keys = self.macro_keys.iter().map(|usage| KeyType::Chord {
+            modifiers: 0,
+            keycodes: [*usage, 0, 0, 0, 0, 0],
+        });
+        for code in self.held.iter().map(|(_, code)| *code).chain(macro_keys) {
+            let (key_modifier, key_keycodes) = keyboard_keys(code);
+            modifier |= key_modifier;
+
+            for usage in key_keycodes.into_iter().filter(|usage| *usage != 0) {
+                if keycodes[..count.min(6)].contains(&usage) {
+                    continue;
+                }
+                if count < 6 {
+                    keycodes[count] = usage;
+                }
+                count += 1;
+            }
+        }
+
+        if count > 6 {
+            keycodes = [ERROR_ROLL_OVER; 6];
+        }
+
+        if (modifier, keycodes) == self.sent_keyboard {
+            return None;
+        }
+        self.sent_keyboard = (modifier, keycodes);
+
+        Some(KeyboardReport {
+            modifier,
+            reserved: 0,
+            leds: 0,
+            keycodes,
+        })
+    }
+
+    /// Returns the consumer usage if it differs from the last one returned.
+    /// The report only holds one usage, the most recently pressed key wins.
+    pub fn take_consumer_usage(&mut self) -> Option<u16> {
+        let usage = self
+            .held
+            .iter()
+            .rev()
+            .find_map(|(_, code)| match code {
+                KeyType::Media(media_key) => Some(*media_key as u16),
+                KeyType::Consumer(usage) => Some(*usage),
+                _ => None,
+            })
+            .unwrap_or(0);
+
+        if usage == self.sent_consumer {
+            return None;
+        }
+        self.sent_consumer = usage;
+        Some(usage)
+    }
+
+    /// Returns the system control usage if it differs from the last one
+    /// returned, the most recently pressed key wins.
+    pub fn take_system_usage(&mut self) -> Option<u8> {
+        let usage = self
+            .held
+            .iter()
+            .rev()
+            .find_map(|(_, code)| match code {
+                KeyType::System(usage) => Some(*usage),
+                _ => None,
+            })
+            .unwrap_or(0);
+
+        if usage == self.sent_system {
+            return None;
+        }
+        self.sent_system = usage;
+        Some(usage)
+    }
+
+    /// Returns the next mouse report, if buttons changed or there is movement to send.
+    pub fn take_mouse_report(&mut self) -> Option<MouseReport> {
+        let mut buttons = 0;
+        let (mut x, mut y) = (0i32, 0i32);
+        for (_, code) in self.held.iter() {
+            match *code {
+                KeyType::MouseButton(mask) => buttons |= mask,
+                KeyType::MouseMove { x: key_x, y: key_y } => {
+                    x += key_x as i32;
+                    y += key_y as i32;
+                }
+                _ => {}
+            }
+        }
+
+        let speed = core::mem::take(&mut self.cursor_speed) as i32;
+        let x = (x * speed).clamp(-127, 127) as i8;
+        let y = (y * speed).clamp(-127, 127) as i8;
+        let wheel = take_scroll_slice(&mut self.wheel);
+        let pan = take_scroll_slice(&mut self.pan);
+
+        if buttons == self.sent_mouse_buttons && x == 0 && y == 0 && wheel == 0 && pan == 0 {
+            return None;
+        }
+        self.sent_mouse_buttons = buttons;
+
+        Some(MouseReport {
+            buttons,
+            x,
+            y,
+            wheel,
+            pan,
+        })
+    }
+}
+
+/// Takes the part of a pending scroll movement to send in the next report.
+fn take_scroll_slice(pending: &mut i32) -> i16 {
+    let slice = (pending.abs() / 2).max(MIN_SCROLL_SLICE).min(pending.abs()).min(i16::MAX as i32);
+    let slice = slice * pending.signum();
+    *pending -= slice;
+    slice as i16
+}
+
+/// Splits a keyboard action into the modifier byte and the keycode array of a
+/// `KeyboardReport`. Modifier usages are moved into the modifier byte, the
+/// report descriptor doesn't allow them in the keycode array.
+fn keyboard_keys(code: KeyType) -> (u8, [u8; 6]) {
+    match code {
+        KeyType::Keycode(keyboard_usage) => {
+            let usage = keyboard_usage as u8;
+            if is_modifier(usage) {
+                (1 << (usage - KeyboardUsage::KeyboardLeftControl as u8), [0; 6])
+            } else {
+                (0, [usage, 0, 0, 0, 0, 0])
+            }
+        }
+        KeyType::Chord { modifiers, keycodes } => {
+            let mut modifier = modifiers;
+            let mut report_keycodes = [0u8; 6];
+            let mut count = 0;
+            for usage in keycodes {
+                if is_modifier(usage) {
+                    modifier |= 1 << (usage - KeyboardUsage::KeyboardLeftControl as u8);
+                } else if usage != 0 {
+                    report_keycodes[count] = usage;
+                    count += 1;
+                }
+            }
+            (modifier, report_keycodes)
+        }
+        _ => (0, [0; 6]),
+    }
+}
+
+fn is_modifier(usage: u8) -> bool {
+    (KeyboardUsage::KeyboardLeftControl as u8..=KeyboardUsage::KeyboardRightGUI as u8).contains(&usage)
+}
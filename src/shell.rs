@@ -0,0 +1,151 @@
+//! Runs the commands of the shell on the debug console, see
+//! `oskar_protocol::shell` for the parser.
+
+use crate::console::{self, Writer};
+use crate::hid::{Key, KEYMAP};
+use crate::layouts::NUM_LAYERS;
+use crate::led::{LedEvent, LED_EVENTS};
+use crate::{encoder_mode, keyboard, modem, storage, uart, usb, DeviceMode};
+use core::fmt::{self, Write};
+use cortex_m::peripheral::SCB;
+use embassy_time::{Duration, Instant, Timer};
+use log::LevelFilter;
+use oskar_protocol::shell::{Command, DisplayAction, LogLevel, HELP};
+use oskar_protocol::{Action, KEY_NAMES};
+use smart_leds::RGB8;
+
+/// Runs a command and writes its output to the console.
+pub async fn execute(command: Command, mode: DeviceMode) {
+    let _ = run(&mut Writer, command, mode);
+
+    if let Command::Reboot | Command::Bootsel = command {
+        // Give the console a chance to send the reply before we drop off the bus.
+        Timer::after(Duration::from_millis(50)).await;
+        match command {
+            Command::Bootsel => embassy_rp::rom_data::reset_to_usb_boot(0, 0),
+            _ => SCB::sys_reset(),
+        }
+    }
+}
+
+fn run(out: &mut Writer, command: Command, mode: DeviceMode) -> fmt::Result {
+    let has_keyboard = !matches!(mode, DeviceMode::Picoprog);
+    let has_picoprog = !matches!(mode, DeviceMode::Keyboard);
+
+    match command {
+        Command::Help => out.write_str(HELP),
+        Command::Status => {
+            let uptime = Instant::now().as_millis();
+            let state = match (usb::configured(), usb::suspended()) {
+                (_, true) => "suspended",
+                (true, false) => "configured",
+                (false, false) => "not configured",
+            };
+            write!(out, "mode: {:?}\r\n", mode)?;
+            write!(out, "uptime: {}.{:03} s\r\n", uptime / 1000, uptime % 1000)?;
+            write!(out, "usb: {}, remote wakeup {}\r\n", state, if usb::remote_wakeup_enabled() { "allowed" } else { "not allowed" })?;
+            if has_keyboard {
+                let protocol = if keyboard::boot_protocol() { "boot" } else { "report" };
+                write!(out, "keyboard: {} protocol, encoder mode {}\r\n", protocol, encoder_mode::current())?;
+            }
+            if has_picoprog {
+                write!(out, "uart: {}, lines {}\r\n", uart::config(), modem::mapping().name())?;
+                write!(out, "spi: {} Hz\r\n", crate::spi_frequency())?;
+            }
+            write!(out, "log level: {}\r\n", log_level(console::level()).name())
+        }
+        Command::UartBaud(_) | Command::UartLines(_) | Command::SpiFreq(_) if !has_picoprog => out.write_str("not available in keyboard mode\r\n"),
+        Command::UartBaud(baud) => {
+            if baud.is_some_and(|baud| !uart::set_baud(baud)) {
+                return out.write_str("baud rate out of range\r\n");
+            }
+            write!(out, "uart: {}\r\n", uart::config())
+        }
+        Command::UartLines(mapping) => {
+            if let Some(mapping) = mapping {
+                modem::set_mapping(mapping);
+            }
+            write!(out, "uart lines: {}\r\n", modem::mapping().name())
+        }
+        Command::SpiFreq(frequency) => {
+            if frequency.is_some_and(|frequency| !crate::set_spi_frequency(frequency)) {
+                return out.write_str("frequency out of range\r\n");
+            }
+            write!(out, "spi: {} Hz\r\n", crate::spi_frequency())
+        }
+        Command::KeymapShow(Some(layer)) | Command::KeymapSet { layer, .. } if layer as usize >= NUM_LAYERS => {
+            write!(out, "there are {} layers\r\n", NUM_LAYERS)
+        }
+        Command::KeymapShow(layer) => {
+            let keymap = KEYMAP.lock(|keymap| *keymap.borrow());
+            for (index, layout) in keymap.iter().enumerate() {
+                if layer.is_some_and(|layer| layer as usize != index) {
+                    continue;
+                }
+                write!(out, "layer {}\r\n", index)?;
+                for (key, name) in Key::ALL.iter().zip(KEY_NAMES) {
+                    write!(out, "  {:<22} {}\r\n", name, DisplayAction(Action::from(layout.get(*key))))?;
+                }
+            }
+            Ok(())
+        }
+        Command::KeymapSet { layer, key, action } => {
+            KEYMAP.lock(|keymap| keymap.borrow_mut()[layer as usize].set(Key::ALL[key], action.into()));
+            out.write_str("done, keymap save writes it to flash\r\n")
+        }
+        Command::KeymapSave => match storage::save() {
+            Ok(()) => out.write_str("saved\r\n"),
+            Err(e) => {
+                log::error!("Failed to save config: {:?}", e);
+                out.write_str("saving failed\r\n")
+            }
+        },
+        Command::Led(color) => {
+            let color = color.map(|[r, g, b]| RGB8 { r, g, b });
+            match LED_EVENTS.try_send(LedEvent::Override(color)) {
+                Ok(()) => Ok(()),
+                Err(_) => out.write_str("LED queue is full, try again\r\n"),
+            }
+        }
+        Command::Log(level) => {
+            if let Some(level) = level {
+                console::set_level(level_filter(level));
+            }
+            write!(out, "log level: {}\r\n", log_level(console::level()).name())
+        }
+        Command::FlashId => match storage::flash_id() {
+            Ok((jedec_id, unique_id)) => {
+                write!(out, "jedec id: {:06X}\r\nunique id: ", jedec_id)?;
+                for byte in unique_id {
+                    write!(out, "{:02X}", byte)?;
+                }
+                out.write_str("\r\n")
+            }
+            Err(e) => write!(out, "reading the flash failed: {:?}\r\n", e),
+        },
+        Command::Reboot => out.write_str("rebooting\r\n"),
+        Command::Bootsel => out.write_str("rebooting into the USB bootloader\r\n"),
+    }
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+fn log_level(filter: LevelFilter) -> LogLevel {
+    match filter {
+        LevelFilter::Off => LogLevel::Off,
+        LevelFilter::Error => LogLevel::Error,
+        LevelFilter::Warn => LogLevel::Warn,
+        LevelFilter::Info => LogLevel::Info,
+        LevelFilter::Debug => LogLevel::Debug,
+        LevelFilter::Trace => LogLevel::Trace,
+    }
+}
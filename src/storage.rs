@@ -0,0 +1,229 @@
+use crate::encoder_mode;
+use crate::hid::{Key, DEFAULT_KEYMAP, KEYMAP, NUM_KEYS};
+use crate::layouts::NUM_LAYERS;
+use crate::macros::{default_macros, MACROS};
+use crate::FLASH_SIZE;
+use core::cell::RefCell;
+use embassy_rp::flash::{Async, Error as FlashError, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use oskar_protocol::macros::MACRO_BUFFER_SIZE;
+use oskar_protocol::Action;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+/// The last flash sector is reserved for the configuration, see `memory.x`.
+const CONFIG_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+
+const CONFIG_MAGIC: u32 = 0x524B_534F; // "OSKR"
+const CONFIG_VERSION: u16 = 3;
+
+const KEYMAP_SIZE: usize = NUM_LAYERS * NUM_KEYS * Action::SIZE;
+const CONFIG_SIZE: usize = KEYMAP_SIZE + MACRO_BUFFER_SIZE;
+const HEADER_SIZE: usize = core::mem::size_of::<ConfigHeader>();
+
+/// The encoder mode changes much more often than the configuration, so the
+/// second half of the sector is a log of it instead: each change programs the
+/// next erased byte, the last programmed one is the current mode. The sector
+/// is only erased once the log is full.
+const ENCODER_MODE_OFFSET: u32 = CONFIG_OFFSET + ENCODER_MODE_SLOTS as u32;
+const ENCODER_MODE_SLOTS: usize = ERASE_SIZE / 2;
+const _: () = assert!(HEADER_SIZE + CONFIG_SIZE <= ENCODER_MODE_SLOTS);
+
+/// Value of erased flash, never a valid encoder mode.
+const ERASED: u8 = 0xFF;
+
+pub type ConfigFlash = Flash<'static, FLASH, Async, FLASH_SIZE>;
+
+// Flash operations disable interrupts anyway, so a blocking mutex is fine here.
+static CONFIG_FLASH: Mutex<CriticalSectionRawMutex, RefCell<Option<ConfigFlash>>> = Mutex::new(RefCell::new(None));
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotInitialized,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for StorageError {
+    fn from(val: FlashError) -> Self {
+        StorageError::Flash(val)
+    }
+}
+
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[repr(C)]
+struct ConfigHeader {
+    magic: u32,
+    version: u16,
+    length: u16,
+    crc: u32,
+}
+
+/// Takes ownership of the flash and loads the stored configuration into
+/// `hid::KEYMAP` and `macros::MACROS`, falling back to the compiled defaults
+/// if it is missing or corrupt. The stored encoder mode is restored, too.
+pub fn init(mut flash: ConfigFlash) {
+    let mut buf = [0u8; HEADER_SIZE + CONFIG_SIZE];
+    let loaded = match flash.blocking_read(CONFIG_OFFSET, &mut buf) {
+        Ok(()) => decode_config(&buf),
+        Err(_) => {
+            defmt::warn!("failed to read config from flash");
+            false
+        }
+    };
+
+    if loaded {
+        defmt::info!("loaded config from flash");
+    } else {
+        defmt::info!("no valid config in flash, using defaults");
+        reset_defaults();
+    }
+
+    match find_encoder_mode(&mut flash) {
+        Ok((Some(mode), _)) => encoder_mode::restore(mode),
+        Ok((None, _)) => {}
+        Err(_) => defmt::warn!("failed to read encoder mode from flash"),
+    }
+
+    CONFIG_FLASH.lock(|f| f.replace(Some(flash)));
+}
+
+/// Restores the compiled default configuration, flash is not touched.
+pub fn reset_defaults() {
+    KEYMAP.lock(|k| k.replace(DEFAULT_KEYMAP));
+    MACROS.lock(|m| m.replace(default_macros()));
+}
+
+/// Erases the config sector and writes the current configuration to it.
+pub fn save() -> Result<(), StorageError> {
+    let mut buf = [0xFFu8; HEADER_SIZE + CONFIG_SIZE];
+    let (header, payload) = buf.split_at_mut(HEADER_SIZE);
+    let (keymap_bytes, macro_bytes) = payload.split_at_mut(KEYMAP_SIZE);
+
+    let keymap = KEYMAP.lock(|k| *k.borrow());
+    for (layer, layout) in keymap.iter().enumerate() {
+        for (index, key) in Key::ALL.iter().enumerate() {
+            let offset = (layer * NUM_KEYS + index) * Action::SIZE;
+            keymap_bytes[offset..offset + Action::SIZE].copy_from_slice(&Action::from(layout.get(*key)).encode());
+        }
+    }
+
+    MACROS.lock(|m| macro_bytes.copy_from_slice(&*m.borrow()));
+
+    let config_header = ConfigHeader {
+        magic: CONFIG_MAGIC,
+        version: CONFIG_VERSION,
+        length: CONFIG_SIZE as u16,
+        crc: crc32(payload),
+    };
+    header.copy_from_slice(config_header.as_bytes());
+
+    CONFIG_FLASH.lock(|f| {
+        let mut f = f.borrow_mut();
+        let flash = f.as_mut().ok_or(StorageError::NotInitialized)?;
+        flash.blocking_erase(CONFIG_OFFSET, CONFIG_OFFSET + ERASE_SIZE as u32)?;
+        flash.blocking_write(CONFIG_OFFSET, &buf)?;
+        // The erase cleared the encoder mode log.
+        flash.blocking_write(ENCODER_MODE_OFFSET, &[encoder_mode::current()])?;
+        Ok(())
+    })
+}
+
+/// Appends `mode` to the encoder mode log, erasing the sector and writing
+/// the stored configuration back once the log is full.
+pub fn save_encoder_mode(mode: u8) -> Result<(), StorageError> {
+    CONFIG_FLASH.lock(|f| {
+        let mut f = f.borrow_mut();
+        let flash = f.as_mut().ok_or(StorageError::NotInitialized)?;
+
+        let slot = match find_encoder_mode(flash)? {
+            (Some(last), _) if last == mode => return Ok(()),
+            (_, Some(slot)) => slot,
+            (_, None) => {
+                let mut buf = [0u8; HEADER_SIZE + CONFIG_SIZE];
+                flash.blocking_read(CONFIG_OFFSET, &mut buf)?;
+                flash.blocking_erase(CONFIG_OFFSET, CONFIG_OFFSET + ERASE_SIZE as u32)?;
+                flash.blocking_write(CONFIG_OFFSET, &buf)?;
+                0
+            }
+        };
+        flash.blocking_write(ENCODER_MODE_OFFSET + slot as u32, &[mode])?;
+        Ok(())
+    })
+}
+
+/// Returns the last encoder mode in the log and the first free slot, `None`
+/// if the log is empty or full.
+fn find_encoder_mode(flash: &mut ConfigFlash) -> Result<(Option<u8>, Option<usize>), StorageError> {
+    let mut last = None;
+    let mut chunk = [0u8; 256];
+    for start in (0..ENCODER_MODE_SLOTS).step_by(chunk.len()) {
+        flash.blocking_read(ENCODER_MODE_OFFSET + start as u32, &mut chunk)?;
+        // The log is written in order, the first erased byte ends it.
+        for (index, &byte) in chunk.iter().enumerate() {
+            if byte == ERASED {
+                return Ok((last, Some(start + index)));
+            }
+            last = Some(byte);
+        }
+    }
+    Ok((last, None))
+}
+
+/// JEDEC ID and unique ID of the on-board flash.
+pub fn flash_id() -> Result<(u32, [u8; 8]), StorageError> {
+    CONFIG_FLASH.lock(|f| {
+        let mut f = f.borrow_mut();
+        let flash = f.as_mut().ok_or(StorageError::NotInitialized)?;
+        let jedec_id = flash.blocking_jedec_id()?;
+        let mut unique_id = [0u8; 8];
+        flash.blocking_unique_id(&mut unique_id)?;
+        Ok((jedec_id, unique_id))
+    })
+}
+
+/// Applies the stored configuration, returns `false` without touching
+/// anything if it is invalid.
+fn decode_config(buf: &[u8]) -> bool {
+    let Ok((header, payload)) = ConfigHeader::read_from_prefix(buf) else {
+        return false;
+    };
+
+    if header.magic != CONFIG_MAGIC || header.version != CONFIG_VERSION || header.length as usize != CONFIG_SIZE {
+        return false;
+    }
+
+    let payload = &payload[..CONFIG_SIZE];
+    if crc32(payload) != header.crc {
+        return false;
+    }
+    let (keymap_bytes, macro_bytes) = payload.split_at(KEYMAP_SIZE);
+
+    let mut keymap = DEFAULT_KEYMAP;
+    for (layer, layout) in keymap.iter_mut().enumerate() {
+        for (index, key) in Key::ALL.iter().enumerate() {
+            let offset = (layer * NUM_KEYS + index) * Action::SIZE;
+            match Action::decode(&keymap_bytes[offset..]) {
+                Ok(action) => layout.set(*key, action.into()),
+                Err(_) => return false,
+            }
+        }
+    }
+
+    KEYMAP.lock(|k| k.replace(keymap));
+    MACROS.lock(|m| m.borrow_mut().copy_from_slice(macro_bytes));
+    true
+}
+
+/// CRC-32 (IEEE 802.3), bitwise to keep the table out of flash.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -1,15 +1,332 @@
-use embassy_futures::join::join;
-use embassy_rp::peripherals::USB;
-use embassy_rp::pio::{Instance as PioInstance, Pio};
-use embassy_rp::pio_programs::uart::{PioUartRx, PioUartRxProgram, PioUartTx, PioUartTxProgram};
+//! USB to UART bridge on the picoprog CDC ACM interface.
+//!
+//! The UART runs on two PIO state machines. TX frames are built in software,
+//! start bit, data bits, parity and stop bits, and the state machine shifts
+//! them out, so all line codings share one program. RX samples the data and
+//! parity bits and checks the stop bit. Both are reconfigured in place when
+//! the host changes the line coding or the shell changes the baud rate.
+
+use core::cell::Cell;
+use core::fmt;
+use embassy_futures::join::{join, join3};
+use embassy_futures::select::{select, Either};
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::gpio::{Flex, Input, Level, Output, Pull};
+use embassy_rp::peripherals::{PIN_0, PIN_1, PIO0, USB};
+use embassy_rp::pio::{
+    Common, Config as PioConfig, Direction, FifoJoin, Pin, Pio, ShiftConfig, ShiftDirection, StateMachine,
+};
 use embassy_rp::usb::Driver;
 use embassy_rp::usb::Instance as UsbInstance;
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::pipe::{Pipe, Reader, Writer};
-use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_sync::signal::Signal;
+use embassy_usb::class::cdc_acm::{ParityType, StopBits};
 use embassy_usb::driver::EndpointError;
+use fixed::types::extra::U8;
+use fixed::FixedU32;
+use pio::{InstructionOperands, OutDestination};
+
+use crate::cdc_acm::{CdcAcmClass, LineCoding, LineState, Receiver, Sender};
+use crate::{modem, ModemResources, UartResources};
+
+const DEFAULT_CONFIG: UartConfig = UartConfig {
+    baud: 115200,
+    data_bits: 8,
+    parity: Parity::None,
+    stop_bits: 1,
+};
+
+/// State machines of the UART PIO block, see `uart_task`.
+const TX_SM: usize = 0;
+const RX_SM: usize = 1;
+
+/// Both PIO programs take 8 cycles per bit.
+const CYCLES_PER_BIT: u64 = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Always 1.
+    Mark,
+    /// Always 0.
+    Space,
+}
+
+impl Parity {
+    /// The parity bit for `data`, if there is one.
+    fn bit(self, data: u32) -> Option<u32> {
+        match self {
+            Parity::None => None,
+            Parity::Odd => Some((data.count_ones() + 1) % 2),
+            Parity::Even => Some(data.count_ones() % 2),
+            Parity::Mark => Some(1),
+            Parity::Space => Some(0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UartConfig {
+    pub baud: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2.
+    pub stop_bits: u8,
+}
+
+impl UartConfig {
+    /// Converts the line coding set by the host. Returns `None` for data bits
+    /// the programs can't handle.
+    fn from_line_coding(coding: &LineCoding) -> Option<Self> {
+        let parity = match coding.parity_type() {
+            ParityType::None => Parity::None,
+            ParityType::Odd => Parity::Odd,
+            ParityType::Even => Parity::Even,
+            ParityType::Mark => Parity::Mark,
+            ParityType::Space => Parity::Space,
+        };
+        let stop_bits = match coding.stop_bits() {
+            StopBits::One => 1,
+            // 1.5 stop bits can't be sent in whole bits, 2 are close enough.
+            StopBits::OnePointFive | StopBits::Two => 2,
+        };
+        let config = UartConfig {
+            baud: coding.data_rate(),
+            data_bits: coding.data_bits(),
+            parity,
+            stop_bits,
+        };
+        (5..=8).contains(&config.data_bits).then_some(config)
+    }
+
+    /// 16.8 fixed point divider of the system clock, `None` if the baud rate
+    /// is out of its range.
+    fn clock_divider(&self) -> Option<FixedU32<U8>> {
+        match (clk_sys_freq() as u64 * 256).checked_div(CYCLES_PER_BIT * self.baud as u64) {
+            Some(divider) if (0x100..=0xFF_FFFF).contains(&divider) => Some(FixedU32::from_bits(divider as u32)),
+            _ => None,
+        }
+    }
+
+    /// Bits read by the RX program after the start bit, the stop bit excluded.
+    fn rx_bits(&self) -> u32 {
+        self.data_bits as u32 + self.parity.bit(0).map_or(0, |_| 1)
+    }
+
+    /// Word for the TX program: the number of bits minus one in the low
+    /// nibble, followed by the bits of the frame, least significant first.
+    fn tx_frame(&self, byte: u8) -> u32 {
+        let data = byte as u32 & ((1 << self.data_bits) - 1);
+        // Start bit 0.
+        let mut frame = data << 1;
+        let mut len = 1 + self.data_bits as u32;
+        if let Some(bit) = self.parity.bit(data) {
+            frame |= bit << len;
+            len += 1;
+        }
+        frame |= ((1 << self.stop_bits) - 1) << len;
+        len += self.stop_bits as u32;
+        (frame << 4) | (len - 1)
+    }
+
+    /// Extracts the data from a word pushed by the RX program. The error
+    /// carries the data of a frame with a wrong parity bit.
+    fn rx_data(&self, word: u32) -> Result<u8, u8> {
+        let bits = word >> (32 - self.rx_bits());
+        let data = bits & ((1 << self.data_bits) - 1);
+        match self.parity.bit(data) {
+            Some(parity) if parity != (bits >> self.data_bits) & 1 => Err(data as u8),
+            _ => Ok(data as u8),
+        }
+    }
+}
+
+/// Shows the common notation, e.g. `115200 baud, 8N1`.
+impl fmt::Display for UartConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        };
+        write!(f, "{} baud, {}{}{}", self.baud, self.data_bits, parity, self.stop_bits)
+    }
+}
+
+static CONFIG: Mutex<CriticalSectionRawMutex, Cell<UartConfig>> = Mutex::new(Cell::new(DEFAULT_CONFIG));
+
+/// New configurations for the state machines.
+static TX_CONFIG: Signal<CriticalSectionRawMutex, UartConfig> = Signal::new();
+static RX_CONFIG: Signal<CriticalSectionRawMutex, UartConfig> = Signal::new();
 
-use crate::UartResources;
+pub fn config() -> UartConfig {
+    CONFIG.lock(|config| config.get())
+}
+
+/// Reconfigures the running UART. Returns `false` if the clock divider can't
+/// reach the baud rate.
+pub fn set_config(config: UartConfig) -> bool {
+    if config.clock_divider().is_none() {
+        return false;
+    }
+    CONFIG.lock(|current| current.set(config));
+    TX_CONFIG.signal(config);
+    RX_CONFIG.signal(config);
+    true
+}
+
+/// Changes the baud rate of the running UART, keeping the rest of the line
+/// coding. Returns `false` if the clock divider can't reach it.
+pub fn set_baud(baud: u32) -> bool {
+    set_config(UartConfig { baud, ..config() })
+}
+
+struct UartTx<'d> {
+    sm: StateMachine<'d, PIO0, TX_SM>,
+    cfg: PioConfig<'d, PIO0>,
+    pin: Pin<'d, PIO0>,
+    config: UartConfig,
+}
+
+impl<'d> UartTx<'d> {
+    fn new(common: &mut Common<'d, PIO0>, mut sm: StateMachine<'d, PIO0, TX_SM>, pin: PIN_0) -> Self {
+        let prg = pio_proc::pio_asm!(
+            "pull block",
+            "out x, 4", // Number of bits minus one.
+            "bitloop:",
+            "out pins, 1 [6]",
+            "jmp x-- bitloop",
+        );
+        let pin = common.make_pio_pin(pin);
+        let mut cfg = PioConfig::default();
+        cfg.use_program(&common.load_program(&prg.program), &[]);
+        cfg.set_out_pins(&[&pin]);
+        cfg.shift_out = ShiftConfig {
+            auto_fill: false,
+            threshold: 32,
+            direction: ShiftDirection::Right,
+        };
+        cfg.fifo_join = FifoJoin::TxOnly;
+        sm.set_pins(Level::High, &[&pin]);
+        sm.set_pin_dirs(Direction::Out, &[&pin]);
+
+        let mut tx = UartTx {
+            sm,
+            cfg,
+            pin,
+            config: DEFAULT_CONFIG,
+        };
+        tx.configure(config());
+        tx
+    }
+
+    fn configure(&mut self, config: UartConfig) {
+        let Some(divider) = config.clock_divider() else {
+            return;
+        };
+        self.sm.set_enable(false);
+        self.cfg.clock_divider = divider;
+        self.sm.set_config(&self.cfg);
+        self.sm.clear_fifos();
+        self.sm.restart();
+        // A frame cut off in the middle must not leave the line low.
+        self.sm.set_pins(Level::High, &[&self.pin]);
+        self.sm.set_enable(true);
+        self.config = config;
+    }
+
+    async fn write(&mut self, byte: u8) {
+        self.sm.tx().wait_push(self.config.tx_frame(byte)).await;
+    }
+}
+
+struct UartRx<'d> {
+    sm: StateMachine<'d, PIO0, RX_SM>,
+    cfg: PioConfig<'d, PIO0>,
+    config: UartConfig,
+}
+
+impl<'d> UartRx<'d> {
+    fn new(common: &mut Common<'d, PIO0>, mut sm: StateMachine<'d, PIO0, RX_SM>, pin: PIN_1) -> Self {
+        // Y holds the number of bits after the start bit minus one. The first
+        // one is sampled 1.5 bits after the falling edge, the rest a bit apart.
+        let prg = pio_proc::pio_asm!(
+            "start:",
+            "wait 0 pin 0",
+            "mov x, y [10]",
+            "bitloop:",
+            "in pins, 1",
+            "jmp x-- bitloop [6]",
+            "jmp pin good_stop",
+            // Framing error, drop the bits and wait for the line to go idle.
+            "mov isr, null",
+            "wait 1 pin 0",
+            "jmp start",
+            "good_stop:",
+            "push",
+        );
+        let mut pin = common.make_pio_pin(pin);
+        pin.set_pull(Pull::Up);
+        let mut cfg = PioConfig::default();
+        cfg.use_program(&common.load_program(&prg.program), &[]);
+        cfg.set_in_pins(&[&pin]);
+        cfg.set_jmp_pin(&pin);
+        cfg.shift_in = ShiftConfig {
+            auto_fill: false,
+            threshold: 32,
+            direction: ShiftDirection::Right,
+        };
+        sm.set_pin_dirs(Direction::In, &[&pin]);
+
+        let mut rx = UartRx {
+            sm,
+            cfg,
+            config: DEFAULT_CONFIG,
+        };
+        rx.configure(config());
+        rx
+    }
+
+    fn configure(&mut self, config: UartConfig) {
+        let Some(divider) = config.clock_divider() else {
+            return;
+        };
+        self.sm.set_enable(false);
+        self.cfg.clock_divider = divider;
+        self.sm.set_config(&self.cfg);
+        self.sm.clear_fifos();
+        self.sm.restart();
+        // Load Y through the otherwise unused TX FIFO.
+        self.sm.tx().push(config.rx_bits() - 1);
+        unsafe {
+            self.sm.exec_instr(InstructionOperands::PULL { if_empty: false, block: false }.encode());
+            self.sm.exec_instr(
+                InstructionOperands::OUT {
+                    destination: OutDestination::Y,
+                    bit_count: 32,
+                }
+                .encode(),
+            );
+        }
+        self.sm.set_enable(true);
+        self.config = config;
+    }
+
+    async fn read(&mut self) -> u8 {
+        let word = self.sm.rx().wait_pull().await;
+        self.config.rx_data(word).unwrap_or_else(|data| {
+            log::debug!("[UART]: Parity error in {:02X}", data);
+            data
+        })
+    }
+}
 
 pub struct Disconnected {}
 
@@ -23,7 +340,7 @@ impl From<EndpointError> for Disconnected {
 }
 
 #[embassy_executor::task]
-pub async fn uart_task(class: CdcAcmClass<'static, Driver<'static, USB>>, r: UartResources) {
+pub async fn uart_task(class: CdcAcmClass<'static, Driver<'static, USB>>, r: UartResources, m: ModemResources) {
     let Pio {
         mut common,
         sm0,
@@ -31,11 +348,8 @@ pub async fn uart_task(class: CdcAcmClass<'static, Driver<'static, USB>>, r: Uar
         ..
     } = Pio::new(r.peripheral, crate::Irqs);
 
-    let tx_prog = PioUartTxProgram::new(&mut common);
-    let mut uart_tx = PioUartTx::new(115200, &mut common, sm0, r.tx, &tx_prog);
-
-    let rx_prog = PioUartRxProgram::new(&mut common);
-    let mut uart_rx = PioUartRx::new(115200, &mut common, sm1, r.rx, &rx_prog);
+    let mut uart_tx = UartTx::new(&mut common, sm0, r.tx);
+    let mut uart_rx = UartRx::new(&mut common, sm1, r.rx);
 
     let mut usb_pipe: Pipe<NoopRawMutex, 64> = Pipe::new();
     let (mut usb_pipe_reader, mut usb_pipe_writer) = usb_pipe.split();
@@ -43,7 +357,8 @@ pub async fn uart_task(class: CdcAcmClass<'static, Driver<'static, USB>>, r: Uar
     let mut uart_pipe: Pipe<NoopRawMutex, 64> = Pipe::new();
     let (mut uart_pipe_reader, mut uart_pipe_writer) = uart_pipe.split();
 
-    let (mut usb_tx, mut usb_rx) = class.split();
+    let line_state = class.line_state();
+    let (mut usb_tx, mut usb_rx, mut notifier) = class.split();
 
     // Read + write from USB
     let usb_future = async {
@@ -51,9 +366,8 @@ pub async fn uart_task(class: CdcAcmClass<'static, Driver<'static, USB>>, r: Uar
             log::debug!("[UART]: Wait for USB connection");
             usb_rx.wait_connection().await;
             log::debug!("[UART]: USB Connected");
-            let _baud = usb_rx.line_coding().data_rate(); // TODO: Make use of this in the PIO program
             let _ = join(
-                usb_read(&mut usb_rx, &mut uart_pipe_writer),
+                usb_read(&mut usb_rx, line_state, &mut uart_pipe_writer),
                 usb_write(&mut usb_tx, &mut usb_pipe_reader),
             )
             .await;
@@ -67,17 +381,39 @@ pub async fn uart_task(class: CdcAcmClass<'static, Driver<'static, USB>>, r: Uar
         uart_write(&mut uart_tx, &mut uart_pipe_reader),
     );
 
-    join(usb_future, uart_future).await;
+    // Modem control lines
+    let modem_future = join(
+        modem::drive_outputs(line_state, Flex::new(m.reset), Output::new(m.boot, Level::High)),
+        modem::report_inputs(&mut notifier, Input::new(m.cts, Pull::Up), Input::new(m.dsr, Pull::Up)),
+    );
+
+    join3(usb_future, uart_future, modem_future).await;
 }
 
-/// Read from the USB and write it to the UART TX pipe
+/// Read from the USB and write it to the UART TX pipe. Applies the line
+/// coding of the host whenever it changes.
 async fn usb_read<'d, T: UsbInstance + 'd>(
-    usb_rx: &mut embassy_usb::class::cdc_acm::Receiver<'d, Driver<'d, T>>,
+    usb_rx: &mut Receiver<'d, Driver<'d, T>>,
+    line_state: LineState<'_>,
     uart_pipe_writer: &mut embassy_sync::pipe::Writer<'_, NoopRawMutex, 64>,
 ) -> Result<(), Disconnected> {
     let mut buf = [0; 64];
+    // The last line coding seen, the shell may have changed the baud rate since.
+    let mut line_coding = None;
     loop {
-        let n = usb_rx.read_packet(&mut buf).await?;
+        let config = UartConfig::from_line_coding(&line_state.line_coding());
+        if config != line_coding {
+            line_coding = config;
+            match config {
+                Some(config) if set_config(config) => log::info!("[UART]: {}", config),
+                _ => log::warn!("[UART]: Unsupported line coding, keeping the current one"),
+            }
+        }
+
+        let n = match select(usb_rx.read_packet(&mut buf), line_state.line_coding_changed()).await {
+            Either::First(n) => n?,
+            Either::Second(()) => continue,
+        };
         let data = &buf[..n];
         log::debug!("[UART]: USB IN: {:?}", data);
         (*uart_pipe_writer).write(data).await;
@@ -86,7 +422,7 @@ async fn usb_read<'d, T: UsbInstance + 'd>(
 
 /// Read from the USB TX pipe and write it to the USB
 async fn usb_write<'d, T: UsbInstance + 'd>(
-    usb_tx: &mut embassy_usb::class::cdc_acm::Sender<'d, Driver<'d, T>>,
+    usb_tx: &mut Sender<'d, Driver<'d, T>>,
     usb_pipe_reader: &mut Reader<'_, NoopRawMutex, 64>,
 ) -> Result<(), Disconnected> {
     let mut buf = [0; 64];
@@ -99,12 +435,15 @@ async fn usb_write<'d, T: UsbInstance + 'd>(
 }
 
 /// Read from the UART and write it to the USB TX pipe
-async fn uart_read<PIO: PioInstance, const SM: usize>(
-    uart_rx: &mut PioUartRx<'_, PIO, SM>,
-    usb_pipe_writer: &mut Writer<'_, NoopRawMutex, 64>,
-) -> ! {
+async fn uart_read(uart_rx: &mut UartRx<'_>, usb_pipe_writer: &mut Writer<'_, NoopRawMutex, 64>) -> ! {
     loop {
-        let byte = uart_rx.read_u8().await;
+        let byte = match select(uart_rx.read(), RX_CONFIG.wait()).await {
+            Either::First(byte) => byte,
+            Either::Second(config) => {
+                uart_rx.configure(config);
+                continue;
+            }
+        };
         let data = &[byte];
         log::debug!("[UART]: UART IN: {:?}", data);
         (*usb_pipe_writer).write(data).await;
@@ -112,17 +451,20 @@ async fn uart_read<PIO: PioInstance, const SM: usize>(
 }
 
 /// Read from the UART TX pipe and write it to the UART
-async fn uart_write<PIO: PioInstance, const SM: usize>(
-    uart_tx: &mut PioUartTx<'_, PIO, SM>,
-    uart_pipe_reader: &mut Reader<'_, NoopRawMutex, 64>,
-) -> ! {
+async fn uart_write(uart_tx: &mut UartTx<'_>, uart_pipe_reader: &mut Reader<'_, NoopRawMutex, 64>) -> ! {
     let mut buf = [0; 64];
     loop {
-        let n = (*uart_pipe_reader).read(&mut buf).await;
+        let n = match select((*uart_pipe_reader).read(&mut buf), TX_CONFIG.wait()).await {
+            Either::First(n) => n,
+            Either::Second(config) => {
+                uart_tx.configure(config);
+                continue;
+            }
+        };
         let data = &buf[..n];
         log::debug!("[UART]: UART OUT: {:?}", data);
         for &byte in data {
-            uart_tx.write_u8(byte).await;
+            uart_tx.write(byte).await;
         }
     }
 }
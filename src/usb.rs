@@ -0,0 +1,76 @@
+//! Bus state shared by the USB device and the tasks using it.
+
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::Handler;
+
+static CONFIGURED: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+static SUSPENDED: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+static REMOTE_WAKEUP_ENABLED: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+static RESUMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static REMOTE_WAKEUP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether the host selected the configuration, i.e. the interfaces are in use.
+pub fn configured() -> bool {
+    CONFIGURED.lock(|configured| configured.get())
+}
+
+/// Whether the host allowed remote wakeup.
+pub fn remote_wakeup_enabled() -> bool {
+    REMOTE_WAKEUP_ENABLED.lock(|enabled| enabled.get())
+}
+
+/// Whether the host suspended the bus. Writes to endpoints don't complete
+/// until it resumes.
+pub fn suspended() -> bool {
+    SUSPENDED.lock(|suspended| suspended.get())
+}
+
+/// Waits until the bus isn't suspended.
+pub async fn wait_resumed() {
+    while suspended() {
+        RESUMED.wait().await;
+    }
+}
+
+/// Asks the host to resume the bus, if it allowed remote wakeup.
+pub fn remote_wakeup() {
+    if remote_wakeup_enabled() {
+        REMOTE_WAKEUP.signal(());
+    }
+}
+
+/// Waits for a remote wakeup request, see `remote_wakeup`.
+pub async fn wait_remote_wakeup() {
+    REMOTE_WAKEUP.wait().await
+}
+
+/// Tracks the device state reported by embassy-usb.
+pub struct UsbStateHandler;
+
+impl Handler for UsbStateHandler {
+    fn reset(&mut self) {
+        self.suspended(false);
+        self.configured(false);
+    }
+
+    fn configured(&mut self, configured: bool) {
+        CONFIGURED.lock(|state| state.set(configured));
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        defmt::info!("USB suspended: {}", suspended);
+        SUSPENDED.lock(|state| state.set(suspended));
+        if !suspended {
+            REMOTE_WAKEUP.reset();
+            RESUMED.signal(());
+        }
+    }
+
+    fn remote_wakeup_enabled(&mut self, enabled: bool) {
+        REMOTE_WAKEUP_ENABLED.lock(|state| state.set(enabled));
+    }
+}